@@ -0,0 +1,118 @@
+//! Benchmarks of the parts of the interpreter that run on every step.
+//!
+//! ```text
+//! cargo bench
+//! ```
+
+use std::time::Instant;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use chip8_interpreter::clock::VirtualClock;
+use chip8_interpreter::config::MachineConfig;
+use chip8_interpreter::cpu::CPU;
+use chip8_interpreter::instruction::Instruction;
+use chip8_interpreter::screen::{Position, Screen, SpriteEdge};
+use chip8_interpreter::views::Inputs;
+
+/// A tight loop of arithmetic and memory writes.
+const ARITHMETIC_ROM: [u8; 12] = [
+    0x60, 0x00, // 0x200: V0 = 0
+    0x70, 0x01, // 0x202: V0 += 1
+    0x81, 0x04, // 0x204: V1 += V0
+    0xA3, 0x00, // 0x206: I = 0x300
+    0xF0, 0x33, // 0x208: Store V0 as decimal digits at I
+    0x12, 0x02, // 0x20A: Jump to 0x202
+];
+
+/// Repeatedly fills the screen with font characters, like a game redrawing its playfield.
+const SPRITE_ROM: [u8; 28] = [
+    0x00, 0xE0, // 0x200: Clear display
+    0x60, 0x00, // 0x202: V0 = 0
+    0x61, 0x00, // 0x204: V1 = 0
+    0x62, 0x05, // 0x206: V2 = 5
+    0xF2, 0x29, // 0x208: I = font character V2
+    0xD0, 0x15, // 0x20A: Draw 5 rows at (V0, V1)
+    0x70, 0x08, // 0x20C: V0 += 8
+    0x30, 0x40, // 0x20E: Skip if V0 == 64
+    0x12, 0x0A, // 0x210: Jump to 0x20A
+    0x60, 0x00, // 0x212: V0 = 0
+    0x71, 0x06, // 0x214: V1 += 6
+    0x31, 0x1E, // 0x216: Skip if V1 == 30
+    0x12, 0x0A, // 0x218: Jump to 0x20A
+    0x12, 0x00, // 0x21A: Jump to 0x200
+];
+
+const STEPS_PER_ITERATION: u64 = 1000;
+
+fn instruction_from_u16(c: &mut Criterion) {
+    let words: Vec<u16> = ARITHMETIC_ROM
+        .chunks_exact(2)
+        .chain(SPRITE_ROM.chunks_exact(2))
+        .map(|word| ((word[0] as u16) << 8) | word[1] as u16)
+        .collect();
+
+    c.bench_function("Instruction::from_u16", |b| {
+        b.iter(|| {
+            for word in words.iter() {
+                black_box(Instruction::from_u16(black_box(*word)).unwrap());
+            }
+        })
+    });
+}
+
+fn screen_draw_sprite(c: &mut Criterion) {
+    let sprite = [
+        0xF0, 0x90, 0xF0, 0x90, 0xF0, 0x3C, 0x42, 0x81, 0x81, 0x42, 0x3C, 0xFF, 0x00, 0xFF, 0x00,
+    ];
+
+    let mut screen = Screen::default();
+    c.bench_function("Screen::draw_sprite", |b| {
+        b.iter(|| screen.draw_sprite(black_box(&Position::new(10, 5)), &sprite, SpriteEdge::Clip))
+    });
+
+    let mut screen = Screen::default();
+    c.bench_function("Screen::draw_sprite at edge", |b| {
+        b.iter(|| screen.draw_sprite(black_box(&Position::new(60, 28)), &sprite, SpriteEdge::Clip))
+    });
+}
+
+/// Runs each ROM with and without the decoded instruction cache, to show how much it speeds up
+/// stepping.
+fn cpu_step(c: &mut Criterion) {
+    let roms: [(&str, &[u8]); 2] = [("arithmetic", &ARITHMETIC_ROM), ("sprites", &SPRITE_ROM)];
+
+    for ((name, rom), use_instruction_cache) in
+        roms.iter().flat_map(|rom| [(rom, true), (rom, false)])
+    {
+        let mut cpu = CPU::new(MachineConfig::default(), 0);
+        cpu.set_instruction_cache_enabled(use_instruction_cache);
+        cpu.load_default_font().unwrap();
+        cpu.load_rom(rom).unwrap();
+        cpu.initialize_program_counter();
+
+        let clock = VirtualClock::new(Instant::now(), 1000);
+        let inputs = Inputs::default();
+        let mut cycle = 0;
+
+        let cache = if use_instruction_cache {
+            "cached"
+        } else {
+            "uncached"
+        };
+        c.bench_function(
+            &format!("CPU::step {} {} x{}", name, cache, STEPS_PER_ITERATION),
+            |b| {
+                b.iter(|| {
+                    for _ in 0..STEPS_PER_ITERATION {
+                        cpu.step(&clock.time_at(cycle), &inputs).unwrap();
+                        cycle += 1;
+                    }
+                })
+            },
+        );
+    }
+}
+
+criterion_group!(benches, instruction_from_u16, screen_draw_sprite, cpu_step);
+criterion_main!(benches);
@@ -0,0 +1,207 @@
+//! Tracking of how a running program accesses each address in memory, to find self-modifying code
+//! and bytes that are used as both code and data.
+//!
+//! Only accesses made by instructions are tracked. Loading the ROM and font, and changes made by a
+//! debugger, are left out.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::ram::{Address, MEMORY_SIZE};
+use crate::symbols::SymbolMap;
+
+const READ: u8 = 0b0001;
+const WRITE: u8 = 0b0010;
+const EXECUTE: u8 = 0b0100;
+const SPRITE: u8 = 0b1000;
+
+/// Number of addresses shown on each row of the report.
+const REPORT_ROW_SIZE: usize = 16;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccessMap {
+    accesses: Vec<u8>,
+    /// The instruction that last wrote to each address.
+    last_writes: BTreeMap<Address, Address>,
+    /// Addresses that were run after being written to, along with the instruction that wrote them.
+    self_modified: BTreeMap<Address, Address>,
+    /// Addresses that were both run and read as sprite data.
+    sprite_code_overlaps: BTreeSet<Address>,
+}
+
+impl Default for AccessMap {
+    fn default() -> Self {
+        AccessMap {
+            accesses: vec![0; MEMORY_SIZE],
+            last_writes: BTreeMap::new(),
+            self_modified: BTreeMap::new(),
+            sprite_code_overlaps: BTreeSet::new(),
+        }
+    }
+}
+
+impl AccessMap {
+    pub fn record_read(&mut self, address: Address) {
+        self.add_flags(address, READ);
+    }
+
+    /// Records a read of sprite data by DXYN.
+    pub fn record_sprite_read(&mut self, address: Address) {
+        if self.add_flags(address, READ | SPRITE) & EXECUTE != 0 {
+            self.sprite_code_overlaps.insert(address);
+        }
+    }
+
+    /// Records a write by the instruction at the given program counter.
+    pub fn record_write(&mut self, program_counter: Address, address: Address) {
+        self.add_flags(address, WRITE);
+        self.last_writes.insert(address, program_counter);
+    }
+
+    /// Records that the byte at the given address was fetched as part of an instruction.
+    pub fn record_execute(&mut self, address: Address) {
+        let flags = self.add_flags(address, EXECUTE);
+
+        if let Some(writer) = self.last_writes.get(&address) {
+            self.self_modified.entry(address).or_insert(*writer);
+        }
+        if flags & SPRITE != 0 {
+            self.sprite_code_overlaps.insert(address);
+        }
+    }
+
+    /// Returns the addresses that were run after the program wrote to them, along with the
+    /// address of the instruction that did the write.
+    pub fn get_self_modified(&self) -> &BTreeMap<Address, Address> {
+        &self.self_modified
+    }
+
+    /// Returns the addresses that were both run as code and drawn as sprite data.
+    pub fn get_sprite_code_overlaps(&self) -> &BTreeSet<Address> {
+        &self.sprite_code_overlaps
+    }
+
+    /// Returns how the given address was accessed in the `RWX` style, with `-` for kinds of
+    /// access that didn't happen.
+    pub fn describe(&self, address: Address) -> String {
+        let flags = self.accesses.get(address as usize).copied().unwrap_or(0);
+        let flag = |mask: u8, c: char| if flags & mask != 0 { c } else { '-' };
+
+        [flag(READ, 'R'), flag(WRITE, 'W'), flag(EXECUTE, 'X')]
+            .iter()
+            .collect()
+    }
+
+    /// Returns a report of the self-modifying code and code/data overlaps that were found, followed
+    /// by a map of how each accessed address was used. Rows of memory that were never accessed
+    /// are left out of the map.
+    pub fn report(&self, symbols: &SymbolMap) -> String {
+        let mut report = String::new();
+
+        writeln!(report, "Self-modifying code: {}", self.self_modified.len()).unwrap();
+        for (address, writer) in self.self_modified.iter() {
+            writeln!(
+                report,
+                "  {} was run after being written by {}",
+                symbols.format_address(*address),
+                symbols.format_address(*writer)
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            report,
+            "Code read as sprite data: {}",
+            self.sprite_code_overlaps.len()
+        )
+        .unwrap();
+        for address in self.sprite_code_overlaps.iter() {
+            writeln!(report, "  {}", symbols.format_address(*address)).unwrap();
+        }
+
+        writeln!(report, "Access map (R = read, W = written, X = run):").unwrap();
+        for (row, row_accesses) in self.accesses.chunks(REPORT_ROW_SIZE).enumerate() {
+            if row_accesses.iter().all(|flags| *flags == 0) {
+                continue;
+            }
+
+            let start = row * REPORT_ROW_SIZE;
+            let cells: Vec<String> = (start..start + row_accesses.len())
+                .map(|address| self.describe(address as Address))
+                .collect();
+            writeln!(report, "  0x{:04x}: {}", start, cells.join(" ")).unwrap();
+        }
+
+        report
+    }
+
+    /// Adds the given flags to the address, returning the flags it had before.
+    fn add_flags(&mut self, address: Address, flags: u8) -> u8 {
+        match self.accesses.get_mut(address as usize) {
+            Some(accesses) => {
+                let previous = *accesses;
+                *accesses |= flags;
+                previous
+            }
+            None => 0,
+        }
+    }
+}
+
+#[test]
+fn access_map_self_modifying_code() {
+    let mut access_map = AccessMap::default();
+    access_map.record_execute(0x0300);
+    access_map.record_write(0x0200, 0x0300);
+    access_map.record_write(0x0202, 0x0302);
+    assert!(access_map.get_self_modified().is_empty());
+
+    access_map.record_execute(0x0302);
+    access_map.record_execute(0x0302);
+    assert_eq!(
+        vec![(0x0302, 0x0202)],
+        access_map
+            .get_self_modified()
+            .iter()
+            .map(|(address, writer)| (*address, *writer))
+            .collect::<Vec<(Address, Address)>>()
+    );
+    assert_eq!("-WX", access_map.describe(0x0302));
+    assert_eq!("---", access_map.describe(0x0304));
+}
+
+#[test]
+fn access_map_sprite_code_overlaps() {
+    let mut access_map = AccessMap::default();
+    access_map.record_execute(0x0200);
+    access_map.record_sprite_read(0x0200);
+    access_map.record_sprite_read(0x0300);
+    access_map.record_read(0x0302);
+    access_map.record_execute(0x0302);
+    access_map.record_execute(0x0300);
+
+    assert_eq!(
+        vec![0x0200, 0x0300],
+        access_map
+            .get_sprite_code_overlaps()
+            .iter()
+            .copied()
+            .collect::<Vec<Address>>()
+    );
+}
+
+#[test]
+fn access_map_report() {
+    let mut access_map = AccessMap::default();
+    access_map.record_execute(0x0200);
+    access_map.record_execute(0x0201);
+    access_map.record_write(0x0200, 0x0212);
+
+    let expected = "Self-modifying code: 0
+Code read as sprite data: 0
+Access map (R = read, W = written, X = run):
+  0x0200: --X --X --- --- --- --- --- --- --- --- --- --- --- --- --- ---
+  0x0210: --- --- -W- --- --- --- --- --- --- --- --- --- --- --- --- ---
+";
+    assert_eq!(expected, access_map.report(&SymbolMap::default()));
+}
@@ -0,0 +1,98 @@
+//! The arithmetic and logic operations of the 8XYN instructions, along with what they do to VF.
+//!
+//! Every operation reads VX and VY before writing anything, then writes its result to VX, and
+//! then writes its flag (if any) to VF. Writing VF last means that when X is F the flag is what
+//! ends up in VF, which is how all of the original interpreters behave.
+
+use crate::quirks::Quirks;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AluOperation {
+    Copy,             // 0x8XY0
+    Or,               // 0x8XY1
+    And,              // 0x8XY2
+    Xor,              // 0x8XY3
+    Add,              // 0x8XY4
+    Subtract,         // 0x8XY5
+    ShiftRight,       // 0x8XY6
+    SubtractReversed, // 0x8XY7
+    ShiftLeft,        // 0x8XYE
+}
+
+/// What an operation does to VF after writing its result.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FlagEffect {
+    Unchanged,
+    Set(u8),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AluResult {
+    pub value: u8,
+    pub flag: FlagEffect,
+}
+
+/// Applies the operation to the values of VX and VY.
+pub fn apply(operation: AluOperation, x: u8, y: u8, quirks: &Quirks) -> AluResult {
+    use AluOperation::*;
+
+    // The logic quirk resets VF for the bitwise operations
+    let logic_flag = match quirks.logic {
+        true => FlagEffect::Set(0),
+        false => FlagEffect::Unchanged,
+    };
+    // The shift quirk shifts VX in place instead of shifting VY
+    let shift_source = match quirks.shift {
+        true => x,
+        false => y,
+    };
+
+    let (value, flag) = match operation {
+        Copy => (y, FlagEffect::Unchanged),
+        Or => (x | y, logic_flag),
+        And => (x & y, logic_flag),
+        Xor => (x ^ y, logic_flag),
+        Add => {
+            let (value, overflowed) = x.overflowing_add(y);
+            (value, FlagEffect::Set(overflowed as u8))
+        }
+        Subtract => {
+            let (value, borrowed) = x.overflowing_sub(y);
+            (value, FlagEffect::Set(!borrowed as u8))
+        }
+        SubtractReversed => {
+            let (value, borrowed) = y.overflowing_sub(x);
+            (value, FlagEffect::Set(!borrowed as u8))
+        }
+        ShiftRight => (shift_source >> 1, FlagEffect::Set(shift_source & 0x01)),
+        ShiftLeft => (shift_source << 1, FlagEffect::Set(shift_source >> 7)),
+    };
+
+    AluResult { value, flag }
+}
+
+#[test]
+fn alu_apply() {
+    let quirks = Quirks::default();
+
+    let expected = AluResult {
+        value: 0x01,
+        flag: FlagEffect::Set(1),
+    };
+    assert_eq!(expected, apply(AluOperation::Add, 0xFF, 0x02, &quirks));
+
+    let expected = AluResult {
+        value: 0xFE,
+        flag: FlagEffect::Set(0),
+    };
+    assert_eq!(
+        expected,
+        apply(AluOperation::SubtractReversed, 0x05, 0x03, &quirks)
+    );
+
+    let expected = AluResult {
+        value: 0xFF,
+        flag: FlagEffect::Unchanged,
+    };
+    assert_eq!(expected, apply(AluOperation::Or, 0x0F, 0xF0, &quirks));
+}
@@ -0,0 +1,401 @@
+//! Octo cartridges, which are GIF images with an Octo program and its options hidden in them.
+//!
+//! The payload is split into 2 bit pieces, which are stored in the lowest 2 bits of the color
+//! index of each pixel of the image, most significant bits first. It starts with its size as a
+//! 32 bit big endian number, followed by that many bytes of UTF-8 JSON of the form
+//! `{"program": "...", "options": {...}}`.
+//!
+//! Cartridges hold the Octo source code of the program rather than an assembled ROM, so the
+//! program has to be assembled with `octo::assemble` before it can be run.
+
+use serde::Deserialize;
+
+use crate::quirks::QuirkOverrides;
+
+const GIF_HEADERS: [&[u8]; 2] = [b"GIF87a", b"GIF89a"];
+const GIF_EXTENSION: u8 = 0x21;
+const GIF_IMAGE: u8 = 0x2C;
+const GIF_TRAILER: u8 = 0x3B;
+/// GIF LZW codes are at most 12 bits.
+const MAX_LZW_CODES: usize = 4096;
+
+/// Size in bytes of the payload's size.
+const PAYLOAD_SIZE_BYTES: usize = 4;
+/// Most pixels that will be decoded, enough for a payload of 4 MiB. Cartridges are far smaller than
+/// this, so larger images are rejected rather than risking running out of memory.
+const MAX_PIXELS: usize = 16 * 1024 * 1024;
+
+/// The options that Octo saves with a program. Options that don't affect how this interpreter
+/// runs programs are left out.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OctoOptions {
+    /// Instructions run per frame.
+    pub tickrate: Option<u32>,
+    pub fill_color: Option<String>,
+    pub background_color: Option<String>,
+    pub font_style: Option<String>,
+    pub shift_quirks: Option<bool>,
+    pub load_store_quirks: Option<bool>,
+    pub clip_quirks: Option<bool>,
+    pub jump_quirks: Option<bool>,
+    pub v_blank_quirks: Option<bool>,
+    pub logic_quirks: Option<bool>,
+}
+
+impl OctoOptions {
+    /// Returns the quirks that the options turn on or off, translated from Octo's names for them.
+    pub fn get_quirk_overrides(&self) -> QuirkOverrides {
+        QuirkOverrides {
+            shift: self.shift_quirks,
+            // Octo's load/store quirk leaves I unchanged, instead of incrementing it at all
+            memory_increment_by_x: self.load_store_quirks.map(|_| false),
+            memory_leave_i_unchanged: self.load_store_quirks,
+            // Octo clips sprites when the quirk is on, and wraps them otherwise
+            wrap: self.clip_quirks.map(|clip| !clip),
+            jump: self.jump_quirks,
+            vblank: self.v_blank_quirks,
+            logic: self.logic_quirks,
+            index_overflow: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct OctoCartridge {
+    /// The Octo source code of the program.
+    pub program: String,
+    #[serde(default)]
+    pub options: OctoOptions,
+}
+
+impl OctoCartridge {
+    pub fn parse(gif: &[u8]) -> Result<OctoCartridge, String> {
+        let pixels = decode_gif(gif)?;
+        let mut payload = pixels.chunks(4).map(|pieces| {
+            pieces
+                .iter()
+                .fold(0, |byte, piece| (byte << 2) | (piece & 0b11))
+        });
+
+        let size = payload
+            .by_ref()
+            .take(PAYLOAD_SIZE_BYTES)
+            .fold(0, |size, byte| (size << 8) | byte as usize);
+        let json: Vec<u8> = payload.take(size).collect();
+        if json.len() != size {
+            return Err("Octo cartridge is missing part of its payload".to_string());
+        }
+
+        serde_json::from_slice(&json)
+            .map_err(|e| format!("Failed to parse Octo cartridge payload: {}", e))
+    }
+}
+
+pub fn is_gif(bytes: &[u8]) -> bool {
+    GIF_HEADERS.iter().any(|header| bytes.starts_with(header))
+}
+
+/// Returns the color indices of the pixels of every image in the GIF, one image after another.
+fn decode_gif(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    if !is_gif(bytes) {
+        return Err("Missing GIF header".to_string());
+    }
+
+    let mut reader = GifReader { bytes, position: 6 };
+    reader.read_bytes(4)?; // Screen size
+    let flags = reader.read_byte()?;
+    reader.read_bytes(2)?; // Background color and aspect ratio
+    reader.skip_color_table(flags)?;
+
+    let mut pixels = vec![];
+    loop {
+        match reader.read_byte()? {
+            GIF_EXTENSION => {
+                reader.read_byte()?;
+                reader.read_sub_blocks()?;
+            }
+            GIF_IMAGE => {
+                reader.read_bytes(4)?; // Position
+                let width = reader.read_u16()? as usize;
+                let height = reader.read_u16()? as usize;
+                let flags = reader.read_byte()?;
+                reader.skip_color_table(flags)?;
+
+                let image_size = width * height;
+                if image_size > MAX_PIXELS - pixels.len() {
+                    return Err(format!("GIF is too large: more than {} pixels", MAX_PIXELS));
+                }
+
+                let min_code_size = reader.read_byte()?;
+                let mut image = decode_lzw(&reader.read_sub_blocks()?, min_code_size, image_size)?;
+                image.resize(image_size, 0);
+                if flags & 0x40 != 0 {
+                    image = deinterlace(&image, width, height);
+                }
+                pixels.extend_from_slice(&image);
+            }
+            GIF_TRAILER => return Ok(pixels),
+            block => return Err(format!("Unknown GIF block: 0x{:02x}", block)),
+        }
+    }
+}
+
+/// Puts the rows of an interlaced image back in order. Interlaced images store every 8th row
+/// starting from 0, then every 8th from 4, every 4th from 2, and every 2nd from 1.
+fn deinterlace(image: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let rows = (0..height)
+        .step_by(8)
+        .chain((4..height).step_by(8))
+        .chain((2..height).step_by(4))
+        .chain((1..height).step_by(2));
+
+    let mut deinterlaced = vec![0; image.len()];
+    for (source_row, row) in rows.enumerate() {
+        deinterlaced[row * width..(row + 1) * width]
+            .copy_from_slice(&image[source_row * width..(source_row + 1) * width]);
+    }
+
+    deinterlaced
+}
+
+/// Decompresses GIF image data, failing if it has more than the given number of pixels.
+fn decode_lzw(data: &[u8], min_code_size: u8, max_size: usize) -> Result<Vec<u8>, String> {
+    if !(1..=11).contains(&min_code_size) {
+        return Err(format!("Invalid GIF code size: {}", min_code_size));
+    }
+    let clear_code = 1 << min_code_size;
+    let end_code = clear_code + 1;
+
+    let initial_table = || -> Vec<Vec<u8>> {
+        let mut table: Vec<Vec<u8>> = (0..clear_code).map(|i| vec![i as u8]).collect();
+        // Entries for the clear and end codes
+        table.push(vec![]);
+        table.push(vec![]);
+        table
+    };
+
+    let mut table = initial_table();
+    let mut code_size = min_code_size + 1;
+    let mut previous: Option<Vec<u8>> = None;
+    let mut output = vec![];
+
+    let mut bit_position = 0;
+    while bit_position + code_size as usize <= data.len() * 8 {
+        let mut code = 0;
+        for i in 0..code_size as usize {
+            let bit = (data[(bit_position + i) / 8] >> ((bit_position + i) % 8)) & 1;
+            code |= (bit as usize) << i;
+        }
+        bit_position += code_size as usize;
+
+        if code == clear_code {
+            table = initial_table();
+            code_size = min_code_size + 1;
+            previous = None;
+            continue;
+        }
+        if code == end_code {
+            break;
+        }
+
+        let entry = match (table.get(code), previous.as_ref()) {
+            (Some(entry), _) => entry.clone(),
+            // A code for the entry that is about to be added, which starts with the previous one
+            (None, Some(previous)) if code == table.len() => {
+                let mut entry = previous.clone();
+                entry.push(previous[0]);
+                entry
+            }
+            _ => return Err(format!("Invalid GIF code: {}", code)),
+        };
+        if entry.len() > max_size - output.len() {
+            return Err(format!("GIF image has more than its {} pixels", max_size));
+        }
+        output.extend_from_slice(&entry);
+
+        if let Some(mut previous) = previous {
+            if table.len() < MAX_LZW_CODES {
+                previous.push(entry[0]);
+                table.push(previous);
+                if table.len() == 1 << code_size && code_size < 12 {
+                    code_size += 1;
+                }
+            }
+        }
+        previous = Some(entry);
+    }
+
+    Ok(output)
+}
+
+struct GifReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> GifReader<'a> {
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + length)
+            .ok_or("GIF ended unexpectedly")?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, String> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Skips the color table that the given flags say follows them, if any.
+    fn skip_color_table(&mut self, flags: u8) -> Result<(), String> {
+        if flags & 0x80 != 0 {
+            self.read_bytes(3 << ((flags & 0x07) + 1))?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a series of length prefixed blocks, ending with an empty block.
+    fn read_sub_blocks(&mut self) -> Result<Vec<u8>, String> {
+        let mut data = vec![];
+        loop {
+            let length = self.read_byte()? as usize;
+            if length == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(self.read_bytes(length)?);
+        }
+    }
+}
+
+/// Creates a GIF with 4 colors that has the given pixels. Codes are kept 3 bits long by clearing
+/// the code table before it grows.
+#[cfg(test)]
+fn create_test_gif(width: u16, pixels: &[u8]) -> Vec<u8> {
+    let height = (pixels.len() as u16).div_ceil(width);
+    let mut codes = vec![];
+    for pair in pixels.chunks(2) {
+        codes.push(4);
+        codes.extend_from_slice(pair);
+    }
+    codes.push(5);
+
+    let mut data = vec![0u8; (codes.len() * 3).div_ceil(8)];
+    for (i, code) in codes.iter().enumerate() {
+        for bit in 0..3 {
+            data[(i * 3 + bit) / 8] |= ((code >> bit) & 1) << ((i * 3 + bit) % 8);
+        }
+    }
+
+    let mut gif = b"GIF89a".to_vec();
+    gif.extend_from_slice(&width.to_le_bytes());
+    gif.extend_from_slice(&height.to_le_bytes());
+    gif.extend_from_slice(&[0x81, 0, 0]);
+    gif.extend_from_slice(&[0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0xFF]);
+    gif.extend_from_slice(&[GIF_EXTENSION, 0xF9, 4, 0, 0, 0, 0, 0]);
+    gif.extend_from_slice(&[GIF_IMAGE, 0, 0, 0, 0]);
+    gif.extend_from_slice(&width.to_le_bytes());
+    gif.extend_from_slice(&height.to_le_bytes());
+    gif.push(0);
+    gif.push(2);
+    for block in data.chunks(255) {
+        gif.push(block.len() as u8);
+        gif.extend_from_slice(block);
+    }
+    gif.push(0);
+    gif.push(GIF_TRAILER);
+    gif
+}
+
+#[test]
+fn cartridge_decode_gif() {
+    let pixels = [0, 1, 2, 3, 3, 2, 1, 0, 1, 1];
+    let gif = create_test_gif(4, &pixels);
+    assert!(is_gif(&gif));
+
+    let mut expected = pixels.to_vec();
+    expected.resize(12, 0);
+    assert_eq!(Ok(expected), decode_gif(&gif));
+
+    assert_eq!(
+        Err("GIF ended unexpectedly".to_string()),
+        decode_gif(&gif[..gif.len() - 8])
+    );
+
+    // An image whose size says it has billions of pixels
+    let mut huge = gif.clone();
+    let image = huge
+        .windows(5)
+        .position(|block| block == [GIF_IMAGE, 0, 0, 0, 0])
+        .unwrap();
+    huge[image + 5..image + 9].copy_from_slice(&[0xFF; 4]);
+    assert_eq!(
+        Err("GIF is too large: more than 16777216 pixels".to_string()),
+        decode_gif(&huge)
+    );
+}
+
+#[test]
+fn cartridge_decode_lzw() {
+    // "0 1 01 010" using codes that refer to entries added along the way, with a 2 bit minimum
+    // code size: clear, 0, 1, 6 (01), 8 (010), end. Codes grow to 4 bits once entry 7 is added.
+    let codes: [(u16, usize); 6] = [(4, 3), (0, 3), (1, 3), (6, 3), (8, 4), (5, 4)];
+    let mut data = vec![0u8; 3];
+    let mut position = 0;
+    for (code, size) in codes.iter() {
+        for bit in 0..*size {
+            data[position / 8] |= (((code >> bit) & 1) as u8) << (position % 8);
+            position += 1;
+        }
+    }
+
+    assert_eq!(Ok(vec![0, 1, 0, 1, 0, 1, 0]), decode_lzw(&data, 2, 7));
+    assert_eq!(
+        Err("GIF image has more than its 6 pixels".to_string()),
+        decode_lzw(&data, 2, 6)
+    );
+}
+
+#[test]
+fn cartridge_parse() {
+    let json = r##"{"program":": main\n  jump main\n","options":{"tickrate":20,"shiftQuirks":true,"clipQuirks":true,"fillColor":"#FFCC00","enableXO":false}}"##;
+    let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+    payload.extend_from_slice(json.as_bytes());
+    let pixels: Vec<u8> = payload
+        .iter()
+        .flat_map(|byte| vec![byte >> 6, (byte >> 4) & 3, (byte >> 2) & 3, byte & 3])
+        .collect();
+
+    let cartridge = OctoCartridge::parse(&create_test_gif(32, &pixels)).unwrap();
+    assert_eq!(": main\n  jump main\n", cartridge.program);
+    assert_eq!(
+        OctoOptions {
+            tickrate: Some(20),
+            fill_color: Some("#FFCC00".to_string()),
+            shift_quirks: Some(true),
+            clip_quirks: Some(true),
+            ..OctoOptions::default()
+        },
+        cartridge.options
+    );
+    assert_eq!(
+        QuirkOverrides {
+            shift: Some(true),
+            wrap: Some(false),
+            ..QuirkOverrides::default()
+        },
+        cartridge.options.get_quirk_overrides()
+    );
+
+    assert_eq!(
+        Err("Octo cartridge is missing part of its payload".to_string()),
+        OctoCartridge::parse(&create_test_gif(16, &pixels[..16]))
+    );
+}
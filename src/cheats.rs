@@ -0,0 +1,556 @@
+//! Cheats, for finding the values a program keeps in memory (such as a lives counter) and freezing
+//! them.
+//!
+//! A memory search starts with a snapshot of RAM, and is then narrowed down by filtering the
+//! addresses whose values changed in a certain way since the last snapshot. Found values can then
+//! be frozen, which sets them back to a fixed value after every step.
+//!
+//! Cheat lists are saved as plain text files named after the SHA-1 hash of the ROM, so that the
+//! cheats for each ROM are picked up again the next time it is run:
+//!
+//! ```text
+//! # Infinite lives
+//! memory 0x03a0 9
+//! register v5 0
+//! ```
+
+use std::collections::BTreeSet;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+
+use crate::cpu::{DebugRegister, ScreenChanged, CPU};
+use crate::debugger::{Debugger, StepDecision};
+use crate::ram::{Address, MEMORY_SIZE};
+use crate::rom;
+
+/// Number of candidates listed by the console before the rest are left out.
+const MAX_LISTED_CANDIDATES: usize = 32;
+
+const CONSOLE_HELP: &str = "Commands:
+  search                      start a new search from a snapshot of memory
+  filter equal VALUE          keep addresses that hold the given value
+  filter changed|unchanged    keep addresses whose value changed or stayed the same
+  filter increased|decreased  keep addresses whose value went up or down
+  candidates                  list the addresses that are left in the search
+  freeze TARGET VALUE         keep an address (0x0300) or register (v3, i, dt, st) at a value
+  unfreeze TARGET             stop freezing an address or register
+  cheats                      list the frozen addresses and registers
+  save                        save the cheats for the ROM
+  pause, continue             pause or continue the program
+  quit                        stop the program
+";
+
+/// How the value at an address has to compare to the last snapshot to stay in a search.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SearchFilter {
+    /// The address holds the given value.
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl SearchFilter {
+    fn matches(&self, previous: u8, current: u8) -> bool {
+        match self {
+            SearchFilter::Equal(value) => current == *value,
+            SearchFilter::Changed => current != previous,
+            SearchFilter::Unchanged => current == previous,
+            SearchFilter::Increased => current > previous,
+            SearchFilter::Decreased => current < previous,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MemorySearch {
+    snapshot: Vec<u8>,
+    candidates: BTreeSet<Address>,
+}
+
+impl MemorySearch {
+    /// Starts a search with every address as a candidate.
+    pub fn new(cpu: &CPU) -> Result<MemorySearch, String> {
+        Ok(MemorySearch {
+            snapshot: cpu.read_memory(0, MEMORY_SIZE)?,
+            candidates: (0..MEMORY_SIZE).map(|address| address as Address).collect(),
+        })
+    }
+
+    /// Removes the candidates that don't match the filter, and takes a new snapshot to compare
+    /// the next filter against.
+    pub fn filter(&mut self, cpu: &CPU, filter: SearchFilter) -> Result<(), String> {
+        let memory = cpu.read_memory(0, MEMORY_SIZE)?;
+
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|address| {
+            let address = *address as usize;
+            filter.matches(snapshot[address], memory[address])
+        });
+        self.snapshot = memory;
+
+        Ok(())
+    }
+
+    pub fn get_candidates(&self) -> &BTreeSet<Address> {
+        &self.candidates
+    }
+}
+
+/// Something a cheat can freeze.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CheatTarget {
+    Memory(Address),
+    Register(DebugRegister),
+}
+
+impl CheatTarget {
+    /// Parses an address such as `0x0300` or a register name such as `v3`.
+    pub fn parse(text: &str) -> Result<CheatTarget, String> {
+        if text.starts_with("0x") {
+            return Ok(CheatTarget::Memory(parse_memory_address(text)?));
+        }
+
+        let register = DebugRegister::all()
+            .into_iter()
+            .find(|register| register.get_name().eq_ignore_ascii_case(text))
+            .ok_or_else(|| format!("Unknown address or register: {}", text))?;
+        match register {
+            // Freezing these would stop the program from running at all
+            DebugRegister::ProgramCounter | DebugRegister::StackPointer => {
+                Err(format!("Can't freeze the {} register", register.get_name()))
+            }
+            _ => Ok(CheatTarget::Register(register)),
+        }
+    }
+
+    fn get_max_value(&self) -> u16 {
+        match self {
+            CheatTarget::Register(register) if register.get_size() == 2 => u16::MAX,
+            _ => u8::MAX as u16,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cheat {
+    pub target: CheatTarget,
+    pub value: u16,
+}
+
+impl Cheat {
+    pub fn new(target: CheatTarget, value: u16) -> Result<Cheat, String> {
+        if value > target.get_max_value() {
+            return Err(format!("Value is too large to freeze: {}", value));
+        }
+
+        Ok(Cheat { target, value })
+    }
+
+    fn apply(&self, cpu: &mut CPU) -> Result<(), String> {
+        match self.target {
+            CheatTarget::Memory(address) => cpu.write_memory(address, &[self.value as u8]),
+            CheatTarget::Register(register) => cpu.set_debug_register(register, self.value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CheatList {
+    cheats: Vec<Cheat>,
+}
+
+impl CheatList {
+    pub fn parse(text: &str) -> Result<CheatList, String> {
+        let mut cheats = CheatList::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let cheat = parse_cheat(line)
+                .map_err(|e| format!("Invalid cheat file line {}: {}", i + 1, e))?;
+            cheats.freeze(cheat);
+        }
+
+        Ok(cheats)
+    }
+
+    /// Returns the path of the cheat file for the given ROM in the given directory.
+    pub fn get_path(directory: &Path, rom: &[u8]) -> PathBuf {
+        directory.join(format!("{}.cheats", rom::sha1_hex(rom)))
+    }
+
+    /// Loads the cheats for the given ROM from the given directory, or an empty list if none have
+    /// been saved for it.
+    pub fn load_for_rom(directory: &Path, rom: &[u8]) -> Result<CheatList, String> {
+        let filepath = CheatList::get_path(directory, rom);
+        if !filepath.exists() {
+            return Ok(CheatList::default());
+        }
+
+        let text = fs::read_to_string(&filepath)
+            .map_err(|e| format!("Failed to read {}: {}", filepath.display(), e))?;
+        CheatList::parse(&text)
+    }
+
+    pub fn save(&self, filepath: &Path) -> Result<(), String> {
+        fs::write(filepath, self.to_text())
+            .map_err(|e| format!("Failed to write {}: {}", filepath.display(), e))
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for cheat in self.cheats.iter() {
+            match cheat.target {
+                CheatTarget::Memory(address) => {
+                    writeln!(text, "memory 0x{:04x} {}", address, cheat.value).unwrap()
+                }
+                CheatTarget::Register(register) => {
+                    writeln!(text, "register {} {}", register.get_name(), cheat.value).unwrap()
+                }
+            }
+        }
+
+        text
+    }
+
+    pub fn get_cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    /// Adds the cheat, replacing any cheat that freezes the same target.
+    pub fn freeze(&mut self, cheat: Cheat) {
+        self.unfreeze(cheat.target);
+        self.cheats.push(cheat);
+    }
+
+    /// Removes the cheat for the given target, returning whether there was one.
+    pub fn unfreeze(&mut self, target: CheatTarget) -> bool {
+        let num_cheats = self.cheats.len();
+        self.cheats.retain(|cheat| cheat.target != target);
+
+        self.cheats.len() != num_cheats
+    }
+
+    /// Sets every frozen address and register back to its value.
+    pub fn apply(&self, cpu: &mut CPU) -> Result<(), String> {
+        for cheat in self.cheats.iter() {
+            cheat.apply(cpu)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_cheat(entry: &str) -> Result<Cheat, String> {
+    let parts: Vec<&str> = entry.split_whitespace().collect();
+    match parts.as_slice() {
+        ["memory", address, value] => Cheat::new(
+            CheatTarget::Memory(parse_memory_address(address)?),
+            parse_number(value)?,
+        ),
+        ["register", name, value] => Cheat::new(CheatTarget::parse(name)?, parse_number(value)?),
+        _ => Err(format!("Unrecognized entry: {}", entry)),
+    }
+}
+
+/// Parses a number, which is hex if it starts with `0x` and decimal otherwise.
+fn parse_number(text: &str) -> Result<u16, String> {
+    let result = match text.strip_prefix("0x") {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => text.parse::<u16>(),
+    };
+
+    result.map_err(|_| format!("Invalid number: {}", text))
+}
+
+/// Parses the address of a byte of memory, which has to be inside of memory for writing to it to
+/// work.
+fn parse_memory_address(text: &str) -> Result<Address, String> {
+    let address = parse_number(text)?;
+    if address as usize >= MEMORY_SIZE {
+        return Err(format!("Address is outside of memory: {}", text));
+    }
+
+    Ok(address)
+}
+
+/// A console for searching memory and freezing values while a program runs. Commands are read
+/// one line at a time from a channel, so that reading them (such as from standard input) never
+/// holds up the program.
+pub struct CheatConsole<W: Write> {
+    commands: Receiver<String>,
+    output: W,
+    cheats: CheatList,
+    search: Option<MemorySearch>,
+    /// Where `save` writes the cheat list to.
+    filepath: PathBuf,
+    paused: bool,
+    quit: bool,
+}
+
+impl<W: Write> CheatConsole<W> {
+    pub fn new(
+        commands: Receiver<String>,
+        output: W,
+        cheats: CheatList,
+        filepath: PathBuf,
+    ) -> CheatConsole<W> {
+        CheatConsole {
+            commands,
+            output,
+            cheats,
+            search: None,
+            filepath,
+            paused: false,
+            quit: false,
+        }
+    }
+
+    pub fn get_cheats(&self) -> &CheatList {
+        &self.cheats
+    }
+
+    fn handle_command(&mut self, cpu: &CPU, command: &str) -> Result<(), String> {
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        match parts.as_slice() {
+            [] => {}
+            ["help"] => write!(self.output, "{}", CONSOLE_HELP).map_err(|e| e.to_string())?,
+            ["search"] => {
+                self.search = Some(MemorySearch::new(cpu)?);
+                self.print_candidate_count()?;
+            }
+            ["filter", filter @ ..] => {
+                let filter = match filter {
+                    ["equal", value] => SearchFilter::Equal(parse_byte(value)?),
+                    ["changed"] => SearchFilter::Changed,
+                    ["unchanged"] => SearchFilter::Unchanged,
+                    ["increased"] => SearchFilter::Increased,
+                    ["decreased"] => SearchFilter::Decreased,
+                    _ => return Err(format!("Unknown filter: {}", filter.join(" "))),
+                };
+                self.search
+                    .as_mut()
+                    .ok_or_else(|| "No search has been started".to_string())?
+                    .filter(cpu, filter)?;
+                self.print_candidate_count()?;
+            }
+            ["candidates"] => self.print_candidates(cpu)?,
+            ["freeze", target, value] => {
+                let cheat = Cheat::new(CheatTarget::parse(target)?, parse_number(value)?)?;
+                self.cheats.freeze(cheat);
+            }
+            ["unfreeze", target] => {
+                if !self.cheats.unfreeze(CheatTarget::parse(target)?) {
+                    return Err(format!("{} is not frozen", target));
+                }
+            }
+            ["cheats"] => {
+                let text = self.cheats.to_text();
+                write!(self.output, "{}", text).map_err(|e| e.to_string())?;
+            }
+            ["save"] => {
+                self.cheats.save(&self.filepath)?;
+                writeln!(self.output, "Saved cheats: {}", self.filepath.display())
+                    .map_err(|e| e.to_string())?;
+            }
+            ["pause"] => self.paused = true,
+            ["continue"] => self.paused = false,
+            ["quit"] => self.quit = true,
+            _ => return Err(format!("Unknown command: {} (try help)", command)),
+        }
+
+        Ok(())
+    }
+
+    fn print_candidate_count(&mut self) -> Result<(), String> {
+        let count = self.search.as_ref().map_or(0, |s| s.get_candidates().len());
+        writeln!(self.output, "Candidates left: {}", count).map_err(|e| e.to_string())
+    }
+
+    fn print_candidates(&mut self, cpu: &CPU) -> Result<(), String> {
+        let search = self
+            .search
+            .as_ref()
+            .ok_or_else(|| "No search has been started".to_string())?;
+
+        for address in search.get_candidates().iter().take(MAX_LISTED_CANDIDATES) {
+            let value = cpu.read_memory(*address, 1)?[0];
+            writeln!(self.output, "  0x{:04x}: {}", address, value).map_err(|e| e.to_string())?;
+        }
+        if search.get_candidates().len() > MAX_LISTED_CANDIDATES {
+            writeln!(
+                self.output,
+                "  ... and {} more",
+                search.get_candidates().len() - MAX_LISTED_CANDIDATES
+            )
+            .map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_byte(text: &str) -> Result<u8, String> {
+    let value = parse_number(text)?;
+    if value > u8::MAX as u16 {
+        return Err(format!("Value is too large for a byte: {}", text));
+    }
+
+    Ok(value as u8)
+}
+
+impl<W: Write> Debugger for CheatConsole<W> {
+    fn before_step(&mut self, cpu: &mut CPU) -> Result<StepDecision, String> {
+        // Mistyped commands shouldn't stop the program, so errors are only shown
+        while let Ok(command) = self.commands.try_recv() {
+            if let Err(message) = self.handle_command(cpu, &command) {
+                writeln!(self.output, "Error: {}", message).map_err(|e| e.to_string())?;
+            }
+        }
+        self.output.flush().map_err(|e| e.to_string())?;
+
+        Ok(match (self.quit, self.paused) {
+            (true, _) => StepDecision::Quit,
+            (false, true) => StepDecision::Pause,
+            (false, false) => StepDecision::Step,
+        })
+    }
+
+    fn after_step(
+        &mut self,
+        cpu: &mut CPU,
+        result: Result<ScreenChanged, String>,
+    ) -> Result<ScreenChanged, String> {
+        let screen_changed = result?;
+        self.cheats.apply(cpu)?;
+
+        Ok(screen_changed)
+    }
+}
+
+#[test]
+fn memory_search_filter() {
+    let mut cpu = CPU::default();
+    cpu.write_memory(0x0300, &[3, 5, 7]).unwrap();
+    let mut search = MemorySearch::new(&cpu).unwrap();
+    assert_eq!(MEMORY_SIZE, search.get_candidates().len());
+
+    cpu.write_memory(0x0300, &[2, 5, 8]).unwrap();
+    search.filter(&cpu, SearchFilter::Decreased).unwrap();
+    assert_eq!(
+        vec![0x0300],
+        search.get_candidates().iter().copied().collect::<Vec<_>>()
+    );
+
+    search.filter(&cpu, SearchFilter::Equal(1)).unwrap();
+    assert!(search.get_candidates().is_empty());
+}
+
+#[test]
+fn cheat_list_parse() {
+    let text = "# Infinite lives\nmemory 0x03a0 9\nregister v5 0x10\nregister i 0x0400\n";
+    let cheats = CheatList::parse(text).unwrap();
+
+    assert_eq!(
+        &[
+            Cheat {
+                target: CheatTarget::Memory(0x03A0),
+                value: 9
+            },
+            Cheat {
+                target: CheatTarget::Register(DebugRegister::V(crate::instruction::Register::V5)),
+                value: 0x10
+            },
+            Cheat {
+                target: CheatTarget::Register(DebugRegister::Index),
+                value: 0x0400
+            },
+        ],
+        cheats.get_cheats()
+    );
+    assert_eq!(
+        "memory 0x03a0 9\nregister v5 16\nregister i 1024\n",
+        cheats.to_text()
+    );
+
+    assert_eq!(
+        Err("Invalid cheat file line 1: Value is too large to freeze: 256".to_string()),
+        CheatList::parse("memory 0x03a0 256")
+    );
+    assert_eq!(
+        Err("Invalid cheat file line 1: Can't freeze the pc register".to_string()),
+        CheatList::parse("register pc 0x0200")
+    );
+    assert_eq!(
+        Err("Invalid cheat file line 1: Address is outside of memory: 0x1000".to_string()),
+        CheatList::parse("memory 0x1000 1")
+    );
+    assert_eq!(
+        Err("Address is outside of memory: 0xffff".to_string()),
+        CheatTarget::parse("0xffff")
+    );
+}
+
+#[test]
+fn cheat_console_freeze() {
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    use crate::views::Inputs;
+
+    let mut cpu = CPU::default();
+    // Count down V0 and store it at 0x0300, forever
+    let rom = [0x70, 0xFF, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x00];
+    cpu.load_rom(&rom).unwrap();
+    cpu.initialize_program_counter();
+
+    let (sender, receiver) = mpsc::channel();
+    let mut console = CheatConsole::new(
+        receiver,
+        vec![],
+        CheatList::default(),
+        PathBuf::from("test.cheats"),
+    );
+    let time = Instant::now();
+    let inputs = Inputs::default();
+    let run = |console: &mut CheatConsole<Vec<u8>>, cpu: &mut CPU, steps: usize| {
+        for _ in 0..steps {
+            if console.before_step(cpu).unwrap() == StepDecision::Step {
+                let result = cpu.step(&time, &inputs);
+                console.after_step(cpu, result).unwrap();
+            }
+        }
+    };
+
+    run(&mut console, &mut cpu, 4);
+    sender.send("search".to_string()).unwrap();
+    run(&mut console, &mut cpu, 4);
+    sender.send("filter decreased".to_string()).unwrap();
+    sender.send("freeze v0 9".to_string()).unwrap();
+    run(&mut console, &mut cpu, 4);
+    assert_eq!(vec![9], cpu.read_memory(0x0300, 1).unwrap());
+
+    sender.send("filter equal 9".to_string()).unwrap();
+    sender.send("candidates".to_string()).unwrap();
+    sender.send("freeze pc 0".to_string()).unwrap();
+    sender.send("pause".to_string()).unwrap();
+    run(&mut console, &mut cpu, 1);
+
+    let output = String::from_utf8(console.output.clone()).unwrap();
+    assert_eq!(
+        format!(
+            "Candidates left: {}\nCandidates left: 1\nCandidates left: 1\n  0x0300: 9\nError: Can't freeze the pc register\n",
+            MEMORY_SIZE
+        ),
+        output
+    );
+    assert_eq!(StepDecision::Pause, console.before_step(&mut cpu).unwrap());
+}
@@ -0,0 +1,59 @@
+//! Clocks used to produce the times passed to `CPU::step`.
+
+use std::time::{Duration, Instant};
+
+/// A clock whose time is derived from the number of steps that have been run rather than from
+/// the wall clock, so that timer behavior is the same every time a program is run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VirtualClock {
+    start: Instant,
+    steps_per_second: u64,
+}
+
+impl VirtualClock {
+    const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+
+    pub fn new(start: Instant, steps_per_second: u64) -> VirtualClock {
+        assert!(
+            steps_per_second > 0,
+            "VirtualClock needs at least 1 step per second"
+        );
+
+        VirtualClock {
+            start,
+            steps_per_second,
+        }
+    }
+
+    pub fn time_at(&self, cycle: u64) -> Instant {
+        // Worked out from the cycle rather than by adding up step durations, so that rounding
+        // doesn't build up over long runs
+        let elapsed_nanos =
+            cycle as u128 * VirtualClock::NANOSECONDS_PER_SECOND / self.steps_per_second as u128;
+
+        self.start + Duration::from_nanos(elapsed_nanos as u64)
+    }
+}
+
+#[test]
+fn virtual_clock_time_at() {
+    let start = Instant::now();
+    let clock = VirtualClock::new(start, 1000);
+
+    assert_eq!(start, clock.time_at(0));
+    assert_eq!(start + Duration::from_millis(1), clock.time_at(1));
+    assert_eq!(start + Duration::from_secs(60), clock.time_at(60000));
+}
+
+#[test]
+fn virtual_clock_uneven_rates() {
+    let start = Instant::now();
+
+    // 1/700th of a second isn't a whole number of microseconds
+    let clock = VirtualClock::new(start, 700);
+    assert_eq!(start + Duration::from_nanos(1_428_571), clock.time_at(1));
+    assert_eq!(start + Duration::from_secs(3600), clock.time_at(700 * 3600));
+
+    let clock = VirtualClock::new(start, 2_000_000);
+    assert_eq!(start + Duration::from_nanos(500), clock.time_at(1));
+}
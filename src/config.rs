@@ -0,0 +1,227 @@
+//! Configuration of the machine that the CPU emulates.
+
+use crate::font::{LARGE_FONT_SIZE, SMALL_FONT_SIZE};
+use crate::quirks::Quirks;
+use crate::ram::{Address, MEMORY_SIZE};
+
+const STACK_ENTRY_SIZE: usize = 2;
+
+pub const DEFAULT_FONT_ADDRESS: Address = 0x0050;
+pub const DEFAULT_LARGE_FONT_ADDRESS: Address = DEFAULT_FONT_ADDRESS + SMALL_FONT_SIZE as u16;
+pub const DEFAULT_LOAD_ADDRESS: Address = 0x0200;
+
+/// Where the ETI-660 loads programs into memory.
+pub const ETI_660_LOAD_ADDRESS: Address = 0x0600;
+
+pub const DEFAULT_STACK_DEPTH: usize = 16;
+
+/// The COSMAC VIP keeps its stack in RAM starting at 0xEA0, with room for 12 return addresses.
+pub const VIP_STACK_ADDRESS: Address = 0x0EA0;
+pub const VIP_STACK_DEPTH: usize = 12;
+
+/// Where return addresses pushed by 2NNN are stored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StackLocation {
+    /// Stored internally by the interpreter, out of reach of programs.
+    Internal,
+    /// Stored in RAM in the region starting at the given address, as on the COSMAC VIP. The stack
+    /// grows downward from the end of the region, with each address stored big endian.
+    Ram(Address),
+}
+
+/// How long instructions take to run and how the timers are ticked.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Timing {
+    /// Every instruction takes the same amount of time, and the timers tick based on the times
+    /// passed to `CPU::step`.
+    Fixed,
+    /// Instructions take as many machine cycles as they do on the COSMAC VIP, and the timers tick
+    /// on its 60 Hz display interrupt. See the `timing` module for details.
+    CosmacVip,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MachineConfig {
+    /// Address that ROMs are loaded into memory at.
+    pub load_address: Address,
+    /// Address that the font is loaded into memory at.
+    pub font_address: Address,
+    /// Address that the large font used by FX30 is loaded into memory at.
+    pub large_font_address: Address,
+    /// Address of the first instruction to run.
+    pub initial_program_counter: Address,
+    /// Maximum number of return addresses the stack can hold.
+    pub stack_depth: usize,
+    pub stack_location: StackLocation,
+    pub timing: Timing,
+    pub quirks: Quirks,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            load_address: DEFAULT_LOAD_ADDRESS,
+            font_address: DEFAULT_FONT_ADDRESS,
+            large_font_address: DEFAULT_LARGE_FONT_ADDRESS,
+            initial_program_counter: DEFAULT_LOAD_ADDRESS,
+            stack_depth: DEFAULT_STACK_DEPTH,
+            stack_location: StackLocation::Internal,
+            timing: Timing::Fixed,
+            quirks: Quirks::default(),
+        }
+    }
+}
+
+impl MachineConfig {
+    /// Returns a config with the memory layout of the ETI-660, which loads programs at 0x600.
+    pub fn eti_660() -> MachineConfig {
+        MachineConfig {
+            load_address: ETI_660_LOAD_ADDRESS,
+            initial_program_counter: ETI_660_LOAD_ADDRESS,
+            ..MachineConfig::default()
+        }
+    }
+
+    /// Returns a config that keeps the stack in RAM with the location and depth used by the
+    /// COSMAC VIP.
+    pub fn with_vip_stack(&self) -> MachineConfig {
+        MachineConfig {
+            stack_depth: VIP_STACK_DEPTH,
+            stack_location: StackLocation::Ram(VIP_STACK_ADDRESS),
+            ..*self
+        }
+    }
+
+    /// Returns a config with the font loaded at the given address, followed by the large font.
+    pub fn with_font_address(&self, font_address: Address) -> MachineConfig {
+        MachineConfig {
+            font_address,
+            large_font_address: font_address.saturating_add(SMALL_FONT_SIZE as u16),
+            ..*self
+        }
+    }
+
+    /// Returns the address that the stack entry at the given depth is stored at, if the stack is
+    /// kept in RAM.
+    pub fn get_stack_entry_address(&self, depth: usize) -> Option<Address> {
+        match self.stack_location {
+            StackLocation::Internal => None,
+            StackLocation::Ram(address) => {
+                let end_address = address as usize + self.stack_depth * STACK_ENTRY_SIZE;
+                Some((end_address - (depth + 1) * STACK_ENTRY_SIZE) as Address)
+            }
+        }
+    }
+
+    /// Returns the size of the largest ROM that fits in memory after the load address.
+    pub fn get_max_rom_size(&self) -> usize {
+        MEMORY_SIZE.saturating_sub(self.load_address as usize)
+    }
+
+    /// Returns the regions of memory used by the interpreter rather than the program, along with
+    /// how many bytes they take up.
+    pub fn get_reserved_regions(&self) -> Vec<(&'static str, Address, usize)> {
+        let mut regions = vec![
+            ("Font", self.font_address, SMALL_FONT_SIZE),
+            ("Large font", self.large_font_address, LARGE_FONT_SIZE),
+        ];
+        if let StackLocation::Ram(address) = self.stack_location {
+            regions.push(("Stack", address, self.stack_depth * STACK_ENTRY_SIZE));
+        }
+
+        regions
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        // Regions of memory that need to fit, along with how many bytes they take up
+        let mut regions = self.get_reserved_regions();
+        regions.push(("Program", self.load_address, 1));
+
+        let addresses = regions
+            .iter()
+            .map(|(name, address, size)| (*name, *address, *size))
+            .chain(std::iter::once((
+                "Initial program counter",
+                self.initial_program_counter,
+                1,
+            )));
+        for (name, address, size) in addresses {
+            if address as usize + size > MEMORY_SIZE {
+                return Err(format!(
+                    "{} address is outside of memory: 0x{:x} (memory size 0x{:x})",
+                    name, address, MEMORY_SIZE
+                ));
+            }
+        }
+
+        for (i, (name, address, size)) in regions.iter().enumerate() {
+            for (other_name, other_address, other_size) in regions.iter().skip(i + 1) {
+                let end = *address as usize + size;
+                let other_end = *other_address as usize + other_size;
+                if (*address as usize) < other_end && (*other_address as usize) < end {
+                    return Err(format!(
+                        "{} at 0x{:x}-0x{:x} overlaps {} at 0x{:x}-0x{:x}",
+                        name,
+                        address,
+                        end - 1,
+                        other_name.to_lowercase(),
+                        other_address,
+                        other_end - 1
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn machine_config_get_max_rom_size() {
+    assert_eq!(3584, MachineConfig::default().get_max_rom_size());
+    assert_eq!(2560, MachineConfig::eti_660().get_max_rom_size());
+}
+
+#[test]
+fn machine_config_get_stack_entry_address() {
+    assert_eq!(None, MachineConfig::default().get_stack_entry_address(0));
+
+    let config = MachineConfig::default().with_vip_stack();
+    assert_eq!(Some(0x0EB6), config.get_stack_entry_address(0));
+    assert_eq!(Some(0x0EA0), config.get_stack_entry_address(11));
+}
+
+#[test]
+fn machine_config_validate() {
+    assert_eq!(Ok(()), MachineConfig::default().validate());
+
+    let config = MachineConfig {
+        font_address: 0x1000,
+        ..MachineConfig::default()
+    };
+    let expected =
+        Err("Font address is outside of memory: 0x1000 (memory size 0x1000)".to_string());
+    assert_eq!(expected, config.validate());
+
+    let config = MachineConfig::default().with_font_address(0x0100);
+    assert_eq!(0x0150, config.large_font_address);
+    assert_eq!(Ok(()), config.validate());
+
+    let config = MachineConfig {
+        large_font_address: 0x0060,
+        ..MachineConfig::default()
+    };
+    let expected = Err("Font at 0x50-0x9f overlaps large font at 0x60-0xff".to_string());
+    assert_eq!(expected, config.validate());
+
+    let config = MachineConfig::default().with_font_address(0x01F0);
+    let expected = Err("Font at 0x1f0-0x23f overlaps program at 0x200-0x200".to_string());
+    assert_eq!(expected, config.validate());
+
+    let config = MachineConfig {
+        stack_location: StackLocation::Ram(0x0090),
+        ..MachineConfig::default()
+    };
+    let expected = Err("Font at 0x50-0x9f overlaps stack at 0x90-0xaf".to_string());
+    assert_eq!(expected, config.validate());
+}
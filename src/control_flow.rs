@@ -0,0 +1,387 @@
+//! Static analysis of the control flow of ROMs.
+//!
+//! Starting from the first instruction, the analysis follows jumps, calls, returns, and skips to
+//! find every instruction that can be reached, which separates code from data much more reliably
+//! than decoding every word of the ROM. Jumps with an offset (BNNN) can't be followed without
+//! running the program, so their targets are left out and reported as warnings.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::instruction::{Instruction, INSTRUCTION_SIZE_BYTES};
+use crate::ram::Address;
+use crate::rom;
+use crate::symbols::SymbolMap;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EdgeKind {
+    /// Execution continues on to the next instruction.
+    Fallthrough,
+    Jump,
+    /// A skip instruction skipped the next instruction.
+    Skip,
+    /// A subroutine was called. Execution continues after the call once it returns.
+    Call,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Edge {
+    pub target: Address,
+    pub kind: EdgeKind,
+}
+
+/// A run of instructions that is only ever entered at its first instruction and only ever left
+/// after its last one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BasicBlock {
+    pub start: Address,
+    pub instructions: Vec<(Address, Instruction)>,
+    pub successors: Vec<Edge>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ControlFlowGraph {
+    load_address: Address,
+    rom_size: usize,
+    entry: Address,
+    blocks: BTreeMap<Address, BasicBlock>,
+    subroutines: BTreeSet<Address>,
+    /// Problems found while following the program, such as jumps out of the ROM.
+    warnings: Vec<(Address, String)>,
+}
+
+impl ControlFlowGraph {
+    /// Finds the instructions in the given ROM that can be reached from the given entry address.
+    pub fn analyze(rom: &[u8], load_address: Address, entry: Address) -> ControlFlowGraph {
+        let read_instruction = |address: Address| -> Option<Result<Instruction, String>> {
+            let offset = address.checked_sub(load_address)? as usize;
+            if offset + 1 >= rom.len() {
+                return None;
+            }
+
+            let bytes = ((rom[offset] as u16) << 8) | rom[offset + 1] as u16;
+            Some(Instruction::from_u16(bytes))
+        };
+
+        let mut instructions = BTreeMap::new();
+        let mut leaders = BTreeSet::new();
+        let mut subroutines = BTreeSet::new();
+        let mut warnings = vec![];
+
+        leaders.insert(entry);
+        let mut to_visit = vec![entry];
+        while let Some(address) = to_visit.pop() {
+            if instructions.contains_key(&address) {
+                continue;
+            }
+
+            let instruction = match read_instruction(address) {
+                Some(Ok(instruction)) => instruction,
+                Some(Err(message)) => {
+                    warnings.push((address, message));
+                    continue;
+                }
+                None => {
+                    warnings.push((address, "Execution reaches outside of the ROM".to_string()));
+                    continue;
+                }
+            };
+
+            let successors = get_successors(address, &instruction);
+            if let Instruction::JumpWithOffset(base) = instruction {
+                warnings.push((
+                    address,
+                    format!("Jump with offset from 0x{:04x} can't be followed", base),
+                ));
+            }
+
+            for edge in successors.iter() {
+                if edge.kind != EdgeKind::Fallthrough || is_terminator(address, &successors) {
+                    leaders.insert(edge.target);
+                }
+                if edge.kind == EdgeKind::Call {
+                    subroutines.insert(edge.target);
+                }
+                to_visit.push(edge.target);
+            }
+
+            instructions.insert(address, instruction);
+        }
+
+        let blocks = leaders
+            .iter()
+            .filter(|leader| instructions.contains_key(leader))
+            .map(|leader| {
+                let block = build_block(*leader, &instructions, &leaders);
+                (*leader, block)
+            })
+            .collect();
+
+        warnings.sort();
+        ControlFlowGraph {
+            load_address,
+            rom_size: rom.len(),
+            entry,
+            blocks,
+            subroutines,
+            warnings,
+        }
+    }
+
+    pub fn get_blocks(&self) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks.values()
+    }
+
+    /// Returns the addresses of the subroutines that the program calls.
+    pub fn get_subroutines(&self) -> impl Iterator<Item = &Address> {
+        self.subroutines.iter()
+    }
+
+    pub fn get_warnings(&self) -> &[(Address, String)] {
+        &self.warnings
+    }
+
+    /// Returns whether the byte at the given address is part of a reachable instruction.
+    pub fn is_code(&self, address: Address) -> bool {
+        let instruction_start = address.saturating_sub(INSTRUCTION_SIZE_BYTES - 1);
+        self.blocks
+            .values()
+            .flat_map(|block| block.instructions.iter())
+            .any(|(start, _)| *start >= instruction_start && *start <= address)
+    }
+
+    /// Returns the ranges of bytes in the ROM that are not part of any reachable instruction,
+    /// each given as its first address and its length.
+    pub fn get_data_ranges(&self) -> Vec<(Address, usize)> {
+        let mut ranges: Vec<(Address, usize)> = vec![];
+
+        for offset in 0..self.rom_size {
+            let address = match rom::get_rom_address(self.load_address, offset) {
+                Some(address) => address,
+                None => break,
+            };
+            if self.is_code(address) {
+                continue;
+            }
+
+            match ranges.last_mut() {
+                Some((start, length)) if *start as usize + *length == address as usize => {
+                    *length += 1
+                }
+                _ => ranges.push((address, 1)),
+            }
+        }
+
+        ranges
+    }
+
+    /// Returns the graph in the Graphviz DOT format, with addresses shown using the given symbols.
+    /// Subroutines are drawn with a double border, and calls with dashed edges.
+    pub fn to_dot(&self, symbols: &SymbolMap) -> String {
+        let mut dot = String::new();
+        let node_name = |address: Address| format!("\"0x{:04x}\"", address);
+
+        writeln!(dot, "digraph control_flow {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        for block in self.blocks.values() {
+            let label: String = block
+                .instructions
+                .iter()
+                .map(|(address, instruction)| {
+                    format!("{}: {:?}\\l", symbols.format_address(*address), instruction)
+                })
+                .collect();
+
+            let is_subroutine =
+                self.subroutines.contains(&block.start) || block.start == self.entry;
+            let border = if is_subroutine { ", peripheries=2" } else { "" };
+
+            writeln!(
+                dot,
+                "    {} [label=\"{}\"{}];",
+                node_name(block.start),
+                label,
+                border
+            )
+            .unwrap();
+        }
+
+        for block in self.blocks.values() {
+            for edge in block.successors.iter() {
+                if !self.blocks.contains_key(&edge.target) {
+                    continue;
+                }
+
+                let style = match edge.kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [label=\"jump\"]",
+                    EdgeKind::Skip => " [label=\"skip\"]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                };
+                writeln!(
+                    dot,
+                    "    {} -> {}{};",
+                    node_name(block.start),
+                    node_name(edge.target),
+                    style
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+/// Returns where execution can go after the given instruction.
+fn get_successors(address: Address, instruction: &Instruction) -> Vec<Edge> {
+    use Instruction::*;
+
+    let next = address.wrapping_add(INSTRUCTION_SIZE_BYTES);
+    let edge = |target: Address, kind: EdgeKind| Edge { target, kind };
+
+    match instruction {
+        Jump(target) => vec![edge(*target, EdgeKind::Jump)],
+        Call(target) => vec![
+            edge(*target, EdgeKind::Call),
+            edge(next, EdgeKind::Fallthrough),
+        ],
+        Return() | JumpWithOffset(_) => vec![],
+        JumpIfEqValue(_, _)
+        | JumpIfNotEqValue(_, _)
+        | JumpIfRegistersEq(_, _)
+        | JumpIfRegistersNotEq(_, _)
+        | SkipIfNotPressed(_) => vec![
+            edge(next, EdgeKind::Fallthrough),
+            edge(next.wrapping_add(INSTRUCTION_SIZE_BYTES), EdgeKind::Skip),
+        ],
+        _ => vec![edge(next, EdgeKind::Fallthrough)],
+    }
+}
+
+/// Returns whether the instruction with the given successors ends a basic block.
+fn is_terminator(address: Address, successors: &[Edge]) -> bool {
+    let next = address.wrapping_add(INSTRUCTION_SIZE_BYTES);
+    successors
+        != [Edge {
+            target: next,
+            kind: EdgeKind::Fallthrough,
+        }]
+}
+
+fn build_block(
+    start: Address,
+    instructions: &BTreeMap<Address, Instruction>,
+    leaders: &BTreeSet<Address>,
+) -> BasicBlock {
+    let mut block_instructions = vec![];
+    let mut address = start;
+
+    loop {
+        let instruction = &instructions[&address];
+        block_instructions.push((address, instruction.clone()));
+
+        let successors = get_successors(address, instruction);
+        let next = address.wrapping_add(INSTRUCTION_SIZE_BYTES);
+        let ends_block = is_terminator(address, &successors)
+            || leaders.contains(&next)
+            || !instructions.contains_key(&next);
+
+        if ends_block {
+            return BasicBlock {
+                start,
+                instructions: block_instructions,
+                successors,
+            };
+        }
+
+        address = next;
+    }
+}
+
+#[cfg(test)]
+const TEST_ROM: [u8; 16] = [
+    0x22, 0x08, // 0x200: Call 0x208
+    0x30, 0x00, // 0x202: Skip if V0 == 0
+    0x12, 0x00, // 0x204: Jump to 0x200
+    0x12, 0x06, // 0x206: Jump to 0x206
+    0x60, 0x01, // 0x208: V0 = 1
+    0x00, 0xEE, // 0x20A: Return
+    0xFF, 0xFF, // 0x20C: Sprite data
+    0x81, 0x81, // 0x20E: Sprite data
+];
+
+#[test]
+fn control_flow_graph_analyze() {
+    let graph = ControlFlowGraph::analyze(&TEST_ROM, 0x0200, 0x0200);
+
+    let block_starts: Vec<Address> = graph.get_blocks().map(|block| block.start).collect();
+    assert_eq!(vec![0x0200, 0x0202, 0x0204, 0x0206, 0x0208], block_starts);
+    assert_eq!(
+        vec![0x0208],
+        graph.get_subroutines().copied().collect::<Vec<Address>>()
+    );
+
+    let skip_block = graph.get_blocks().nth(1).unwrap();
+    assert_eq!(
+        vec![
+            Edge {
+                target: 0x0204,
+                kind: EdgeKind::Fallthrough
+            },
+            Edge {
+                target: 0x0206,
+                kind: EdgeKind::Skip
+            },
+        ],
+        skip_block.successors
+    );
+
+    let subroutine = graph.get_blocks().last().unwrap();
+    assert_eq!(2, subroutine.instructions.len());
+
+    assert!(graph.is_code(0x020B));
+    assert!(!graph.is_code(0x020C));
+    assert_eq!(vec![(0x020C, 4)], graph.get_data_ranges());
+    assert!(graph.get_warnings().is_empty());
+}
+
+#[test]
+fn control_flow_graph_warnings() {
+    let rom = [
+        0xB2, 0x00, // 0x200: Jump to 0x200 + V0
+        0x00, 0x00, // 0x202: Not reachable
+    ];
+    let graph = ControlFlowGraph::analyze(&rom, 0x0200, 0x0200);
+    assert_eq!(
+        vec![(
+            0x0200,
+            "Jump with offset from 0x0200 can't be followed".to_string()
+        )],
+        graph.get_warnings()
+    );
+
+    let rom = [0x60, 0x01]; // 0x200: V0 = 1, and then off the end of the ROM
+    let graph = ControlFlowGraph::analyze(&rom, 0x0200, 0x0200);
+    assert_eq!(
+        vec![(0x0202, "Execution reaches outside of the ROM".to_string())],
+        graph.get_warnings()
+    );
+}
+
+#[test]
+fn control_flow_graph_to_dot() {
+    let graph = ControlFlowGraph::analyze(&TEST_ROM, 0x0200, 0x0200);
+    let symbols = SymbolMap::parse("label 0x0208 reset_v0").unwrap();
+    let dot = graph.to_dot(&symbols);
+
+    assert!(dot.starts_with("digraph control_flow {\n"));
+    assert!(dot.contains(
+        "    \"0x0208\" [label=\"reset_v0: SetRegister(V0, 1)\\lreset_v0+0x2: Return\\l\", peripheries=2];\n"
+    ));
+    assert!(dot.contains("    \"0x0200\" -> \"0x0208\" [label=\"call\", style=dashed];\n"));
+    assert!(dot.contains("    \"0x0202\" -> \"0x0206\" [label=\"skip\"];\n"));
+    assert!(dot.ends_with("}\n"));
+}
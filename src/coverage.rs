@@ -0,0 +1,295 @@
+//! Code coverage of the instructions run by a program, along with which way each skip went.
+//!
+//! Coverage can be written out as an annotated listing of the ROM, or as an lcov tracefile that
+//! maps it back to the source through a symbol file, so that CI tools can show which lines of a
+//! CHIP-8 program its tests run.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::control_flow::ControlFlowGraph;
+use crate::instruction::{Instruction, INSTRUCTION_SIZE_BYTES};
+use crate::ram::Address;
+use crate::rom;
+use crate::symbols::SymbolMap;
+
+/// How many times a skip instruction did and did not skip the next instruction.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BranchCoverage {
+    pub skipped: u64,
+    pub not_skipped: u64,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Coverage {
+    /// Number of times the instruction at each address was run.
+    hits: BTreeMap<Address, u64>,
+    branches: BTreeMap<Address, BranchCoverage>,
+}
+
+impl Coverage {
+    /// Records that the instruction at the given address ran, leaving the program counter at the
+    /// given address.
+    pub fn record(&mut self, address: Address, instruction: &Instruction, next_address: Address) {
+        *self.hits.entry(address).or_default() += 1;
+
+        if is_skip(instruction) {
+            let branch = self.branches.entry(address).or_default();
+            match next_address == address.wrapping_add(2 * INSTRUCTION_SIZE_BYTES) {
+                true => branch.skipped += 1,
+                false => branch.not_skipped += 1,
+            }
+        }
+    }
+
+    pub fn get_hits(&self, address: Address) -> u64 {
+        self.hits.get(&address).copied().unwrap_or(0)
+    }
+
+    pub fn get_branch(&self, address: Address) -> Option<&BranchCoverage> {
+        self.branches.get(&address)
+    }
+
+    /// Returns a listing of every instruction in the ROM along with the number of times it was
+    /// run, in the style of gcov.
+    ///
+    /// Instructions that can be reached but were never run are marked with `#####`, while words
+    /// that the control flow graph shows to be data are marked with `-`.
+    pub fn to_listing(
+        &self,
+        rom: &[u8],
+        load_address: Address,
+        graph: &ControlFlowGraph,
+        symbols: &SymbolMap,
+    ) -> String {
+        let mut listing = String::new();
+
+        for (i, word) in rom.chunks(INSTRUCTION_SIZE_BYTES as usize).enumerate() {
+            let address = match rom::get_rom_address(load_address, i * word.len()) {
+                Some(address) => address,
+                None => break,
+            };
+
+            if let Some(label) = symbols.get_label(address) {
+                writeln!(listing, "{}:", label).unwrap();
+            }
+
+            let bytes: String = word.iter().map(|b| format!("{:02x}", b)).collect();
+            let hits = self.get_hits(address);
+            let (count, description) = match word {
+                [first, second] if hits > 0 || graph.is_code(address) => {
+                    let count = match hits {
+                        0 => "#####".to_string(),
+                        n => n.to_string(),
+                    };
+                    let instruction =
+                        Instruction::from_u16(((*first as u16) << 8) | *second as u16);
+                    let description = match instruction {
+                        Ok(instruction) => format!("{:?}", instruction),
+                        Err(message) => message,
+                    };
+                    (count, description)
+                }
+                _ => ("-".to_string(), "data".to_string()),
+            };
+
+            write!(
+                listing,
+                "{:>9}: 0x{:04x}: {}  {}",
+                count, address, bytes, description
+            )
+            .unwrap();
+            if let Some(branch) = self.get_branch(address) {
+                write!(
+                    listing,
+                    "  [skipped {} of {}]",
+                    branch.skipped,
+                    branch.skipped + branch.not_skipped
+                )
+                .unwrap();
+            }
+            writeln!(listing).unwrap();
+        }
+
+        listing
+    }
+
+    /// Returns the coverage of the source lines in the given symbols as an lcov tracefile.
+    ///
+    /// Lines are counted as run as many times as the most run instruction assembled from them.
+    /// Each skip instruction in the ROM is reported as a branch with two outcomes, not skipping and
+    /// skipping, which are marked with `-` if the skip never ran.
+    pub fn to_lcov(&self, rom: &[u8], load_address: Address, symbols: &SymbolMap) -> String {
+        // Instructions in each source file, by line
+        let mut files: BTreeMap<&str, BTreeMap<u32, Vec<Address>>> = BTreeMap::new();
+        for (address, location) in symbols.get_source_locations() {
+            files
+                .entry(&location.file)
+                .or_default()
+                .entry(location.line)
+                .or_default()
+                .push(*address);
+        }
+
+        let mut lcov = String::new();
+        for (file, lines) in files.iter() {
+            writeln!(lcov, "SF:{}", file).unwrap();
+
+            let mut lines_hit = 0;
+            let mut branches_found = 0;
+            let mut branches_hit = 0;
+            for (line, addresses) in lines.iter() {
+                for (block, address) in addresses.iter().enumerate() {
+                    let counts = match self.branches.get(address) {
+                        Some(branch) => [Some(branch.not_skipped), Some(branch.skipped)],
+                        None => match decode_rom_instruction(rom, load_address, *address) {
+                            Some(instruction) if is_skip(&instruction) => [None, None],
+                            _ => continue,
+                        },
+                    };
+
+                    for (outcome, count) in counts.iter().enumerate() {
+                        let taken = match count {
+                            Some(count) => count.to_string(),
+                            None => "-".to_string(),
+                        };
+                        writeln!(lcov, "BRDA:{},{},{},{}", line, block, outcome, taken).unwrap();
+                        branches_found += 1;
+                        if count.unwrap_or(0) > 0 {
+                            branches_hit += 1;
+                        }
+                    }
+                }
+
+                let hits = addresses
+                    .iter()
+                    .map(|address| self.get_hits(*address))
+                    .max()
+                    .unwrap_or(0);
+                writeln!(lcov, "DA:{},{}", line, hits).unwrap();
+                if hits > 0 {
+                    lines_hit += 1;
+                }
+            }
+
+            writeln!(lcov, "BRF:{}", branches_found).unwrap();
+            writeln!(lcov, "BRH:{}", branches_hit).unwrap();
+            writeln!(lcov, "LF:{}", lines.len()).unwrap();
+            writeln!(lcov, "LH:{}", lines_hit).unwrap();
+            writeln!(lcov, "end_of_record").unwrap();
+        }
+
+        lcov
+    }
+}
+
+/// Decodes the instruction that the ROM has at the given address, if there is one.
+fn decode_rom_instruction(
+    rom: &[u8],
+    load_address: Address,
+    address: Address,
+) -> Option<Instruction> {
+    let offset = address.checked_sub(load_address)? as usize;
+    match rom.get(offset..offset + INSTRUCTION_SIZE_BYTES as usize)? {
+        [first, second] => Instruction::from_u16(((*first as u16) << 8) | *second as u16).ok(),
+        _ => None,
+    }
+}
+
+fn is_skip(instruction: &Instruction) -> bool {
+    use Instruction::*;
+
+    matches!(
+        instruction,
+        JumpIfEqValue(_, _)
+            | JumpIfNotEqValue(_, _)
+            | JumpIfRegistersEq(_, _)
+            | JumpIfRegistersNotEq(_, _)
+            | SkipIfNotPressed(_)
+    )
+}
+
+#[cfg(test)]
+fn create_test_coverage() -> Coverage {
+    use crate::instruction::Register;
+
+    let mut coverage = Coverage::default();
+    let skip = Instruction::JumpIfEqValue(Register::V0, 0);
+    coverage.record(0x0200, &skip, 0x0204);
+    coverage.record(0x0204, &Instruction::Jump(0x0200), 0x0200);
+    coverage.record(0x0200, &skip, 0x0204);
+    coverage.record(0x0204, &Instruction::Jump(0x0200), 0x0200);
+    coverage
+}
+
+#[test]
+fn coverage_record() {
+    let coverage = create_test_coverage();
+
+    assert_eq!(2, coverage.get_hits(0x0200));
+    assert_eq!(0, coverage.get_hits(0x0202));
+    assert_eq!(
+        Some(&BranchCoverage {
+            skipped: 2,
+            not_skipped: 0
+        }),
+        coverage.get_branch(0x0200)
+    );
+    assert_eq!(None, coverage.get_branch(0x0204));
+}
+
+#[test]
+fn coverage_to_listing() {
+    let rom = [
+        0x30, 0x00, // 0x200: Skip if V0 == 0
+        0x00, 0xE0, // 0x202: Clear display
+        0x12, 0x00, // 0x204: Jump to 0x200
+        0xFF, 0x81, // 0x206: Sprite data
+    ];
+    let graph = ControlFlowGraph::analyze(&rom, 0x0200, 0x0200);
+    let symbols = SymbolMap::parse("label 0x0200 main").unwrap();
+
+    let expected = "main:
+        2: 0x0200: 3000  JumpIfEqValue(V0, 0)  [skipped 2 of 2]
+    #####: 0x0202: 00e0  ClearDisplay
+        2: 0x0204: 1200  Jump(512)
+        -: 0x0206: ff81  data
+";
+    assert_eq!(
+        expected,
+        create_test_coverage().to_listing(&rom, 0x0200, &graph, &symbols)
+    );
+}
+
+#[test]
+fn coverage_to_lcov() {
+    let rom = [
+        0x30, 0x00, // 0x200: Skip if V0 == 0
+        0x00, 0xE0, // 0x202: Clear display
+        0x12, 0x00, // 0x204: Jump to 0x200
+        0x40, 0x01, // 0x206: Skip if V0 != 1, which never runs
+    ];
+    let symbols = SymbolMap::parse(
+        "line 0x0200 game.8o:3\nline 0x0202 game.8o:4\nline 0x0204 game.8o:6\nline 0x0206 game.8o:6",
+    )
+    .unwrap();
+
+    let expected = "SF:game.8o
+BRDA:3,0,0,0
+BRDA:3,0,1,2
+DA:3,2
+DA:4,0
+BRDA:6,1,0,-
+BRDA:6,1,1,-
+DA:6,2
+BRF:4
+BRH:1
+LF:3
+LH:2
+end_of_record
+";
+    assert_eq!(
+        expected,
+        create_test_coverage().to_lcov(&rom, 0x0200, &symbols)
+    );
+}
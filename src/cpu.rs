@@ -3,67 +3,373 @@ extern crate rand;
 use std::cmp;
 use std::time::{Duration, Instant};
 
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::access_map::AccessMap;
+use crate::alu;
+use crate::alu::{AluOperation, FlagEffect};
+use crate::config::{MachineConfig, Timing};
+use crate::coverage::Coverage;
+use crate::font::{Font, LARGE_GLYPH_SIZE, SMALL_GLYPH_SIZE};
+#[cfg(test)]
+use crate::font::{LARGE_FONT_SIZE, SMALL_FONT_SIZE};
 use crate::instruction::{Instruction, Register, INSTRUCTION_SIZE_BYTES};
+use crate::profiler::Profiler;
 use crate::ram;
-use crate::ram::Address;
-use crate::screen::{AnyPixelsUnset, Position, Screen};
+use crate::ram::{Address, MEMORY_SIZE};
+use crate::screen::{AnyPixelsUnset, Position, Screen, SpriteEdge};
+use crate::symbols::SymbolMap;
+use crate::timing;
 use crate::views::{InputState, Inputs};
 
-// From: https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#font
-const DEFAULT_FONT: [u8; 80] = [
-    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-    0x20, 0x60, 0x20, 0x20, 0x70, // 1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-];
-
-const FONT_ADDRESS: Address = 0x0050;
-const ROM_ADDRESS: Address = 0x0200;
-
 const ONE_SECOND_IN_MICROSECONDS: u64 = 1000000;
 const TIMER_TICK_DURATION: Duration = Duration::from_micros(ONE_SECOND_IN_MICROSECONDS / 60);
 
-#[derive(Debug, Default, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq)]
 pub struct CPU {
     registers: Registers,
     ram: ram::RAM,
     pub screen: Screen,
     last_timer_tick: Option<Instant>,
     inputs: Inputs,
+    config: MachineConfig,
+    rng_seed: u64,
+    rng: StdRng,
+    use_instruction_cache: bool,
+    cycle_count: u64,
+    next_interrupt_cycle: u64,
+    symbols: SymbolMap,
+    access_map: Option<AccessMap>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        CPU::new(MachineConfig::default(), rand::random())
+    }
 }
 
 impl CPU {
+    /// Creates a CPU for the machine with the given config, whose random number generator is
+    /// seeded with the given value so that runs given the same ROM, inputs, and step times are
+    /// reproducible.
+    pub fn new(config: MachineConfig, rng_seed: u64) -> CPU {
+        CPU {
+            registers: Registers::default(),
+            ram: ram::RAM::default(),
+            screen: Screen::default(),
+            last_timer_tick: None,
+            inputs: Inputs::default(),
+            config,
+            rng_seed,
+            rng: StdRng::seed_from_u64(rng_seed),
+            use_instruction_cache: true,
+            cycle_count: 0,
+            next_interrupt_cycle: timing::MACHINE_CYCLES_PER_FRAME,
+            symbols: SymbolMap::default(),
+            access_map: None,
+            profiler: None,
+            coverage: None,
+        }
+    }
+
+    pub fn get_rng_seed(&self) -> u64 {
+        self.rng_seed
+    }
+
+    /// Sets whether decoded instructions are cached between steps. Caching is on by default, and
+    /// turning it off is only useful for measuring how much it speeds things up.
+    pub fn set_instruction_cache_enabled(&mut self, enabled: bool) {
+        self.use_instruction_cache = enabled;
+    }
+
+    /// Sets whether the reads, writes, and instruction fetches made by the program are recorded
+    /// in an access map. Tracking is off by default, and turning it on clears the map.
+    pub fn set_access_tracking_enabled(&mut self, enabled: bool) {
+        self.access_map = match enabled {
+            true => Some(AccessMap::default()),
+            false => None,
+        };
+    }
+
+    pub fn get_access_map(&self) -> Option<&AccessMap> {
+        self.access_map.as_ref()
+    }
+
+    /// Sets whether the instructions run by the program and the cycles they take are recorded
+    /// by a profiler. Profiling is off by default, and turning it on clears the profile.
+    pub fn set_profiling_enabled(&mut self, enabled: bool) {
+        self.profiler = match enabled {
+            true => Some(Profiler::default()),
+            false => None,
+        };
+    }
+
+    pub fn get_profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Sets whether the instructions run by the program and the outcomes of its skips are
+    /// recorded for code coverage. Coverage is off by default, and turning it on clears it.
+    pub fn set_coverage_enabled(&mut self, enabled: bool) {
+        self.coverage = match enabled {
+            true => Some(Coverage::default()),
+            false => None,
+        };
+    }
+
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Sets the symbols of the loaded ROM, which are used to show addresses in error messages.
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.symbols = symbols;
+    }
+
+    pub fn get_symbols(&self) -> &SymbolMap {
+        &self.symbols
+    }
+
+    pub fn get_config(&self) -> &MachineConfig {
+        &self.config
+    }
+
+    pub fn get_program_counter(&self) -> Address {
+        self.registers.program_counter
+    }
+
+    pub fn get_debug_register(&self, register: DebugRegister) -> u16 {
+        match register {
+            DebugRegister::V(register) => self.registers.get_register(&register) as u16,
+            DebugRegister::Index => self.registers.index_register,
+            DebugRegister::ProgramCounter => self.registers.program_counter,
+            DebugRegister::StackPointer => self.registers.stack.len() as u16,
+            DebugRegister::DelayTimer => self.registers.delay_timer as u16,
+            DebugRegister::SoundTimer => self.registers.sound_timer as u16,
+        }
+    }
+
+    /// Changes the value of a register from a debugger. Values are truncated to the size of the
+    /// register. The stack pointer can only be lowered, which discards the most recent calls.
+    pub fn set_debug_register(
+        &mut self,
+        register: DebugRegister,
+        value: u16,
+    ) -> Result<(), String> {
+        match register {
+            DebugRegister::V(register) => self.registers.set_register(&register, value as u8),
+            DebugRegister::Index => self.registers.index_register = value,
+            DebugRegister::ProgramCounter => self.registers.program_counter = value,
+            DebugRegister::StackPointer => {
+                if value as usize > self.registers.stack.len() {
+                    return Err(format!(
+                        "Stack pointer can't be raised past the current stack depth ({})",
+                        self.registers.stack.len()
+                    ));
+                }
+                self.registers.stack.truncate(value as usize);
+            }
+            DebugRegister::DelayTimer => self.registers.delay_timer = value as u8,
+            DebugRegister::SoundTimer => self.registers.sound_timer = value as u8,
+        }
+
+        Ok(())
+    }
+
+    pub fn read_memory(&self, address: Address, length: usize) -> Result<Vec<u8>, String> {
+        (0..length)
+            .map(|i| self.ram.read_byte(address.wrapping_add(i as u16)))
+            .collect()
+    }
+
+    /// Writes to memory from outside of the running program, such as from a debugger.
+    pub fn write_memory(&mut self, address: Address, bytes: &[u8]) -> Result<(), String> {
+        self.ram.write_bytes(address, bytes)
+    }
+
+    /// Returns the frames of the call stack, with the most recent call first.
+    pub fn get_call_stack(&self) -> Result<Vec<StackFrame>, String> {
+        let mut frames = vec![];
+        for depth in (0..self.registers.stack.len()).rev() {
+            let return_address = self.get_stack_entry(depth)?;
+
+            frames.push(StackFrame {
+                call_address: return_address.wrapping_sub(INSTRUCTION_SIZE_BYTES),
+                return_address,
+            });
+        }
+
+        Ok(frames)
+    }
+
+    /// Returns the return address at the given depth of the stack, reading it from RAM if the
+    /// stack is kept there so that any changes made by the program are seen.
+    fn get_stack_entry(&self, depth: usize) -> Result<Address, String> {
+        match self.config.get_stack_entry_address(depth) {
+            Some(entry_address) => self.ram.read_u16(entry_address),
+            None => Ok(self.registers.stack[depth]),
+        }
+    }
+
     pub fn load_default_font(&mut self) -> Result<(), String> {
-        self.ram.write_bytes(FONT_ADDRESS, &DEFAULT_FONT)
+        self.load_font(&Font::default())
+    }
+
+    pub fn load_font(&mut self, font: &Font) -> Result<(), String> {
+        self.ram
+            .write_bytes(self.config.font_address, font.get_small())?;
+        self.ram
+            .write_bytes(self.config.large_font_address, font.get_large())
     }
 
     pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), String> {
-        self.ram.write_bytes(ROM_ADDRESS, rom)
+        let max_size = self.config.get_max_rom_size();
+        if rom.len() > max_size {
+            return Err(format!(
+                "ROM is too large to fit in memory: {} bytes (max {} bytes)",
+                rom.len(),
+                max_size
+            ));
+        }
+
+        let rom_start = self.config.load_address as usize;
+        let rom_end = rom_start + rom.len();
+        for (name, region_address, region_size) in self.config.get_reserved_regions() {
+            let region_start = region_address as usize;
+            let region_end = region_start + region_size;
+            if rom_start < region_end && region_start < rom_end {
+                return Err(format!(
+                    "ROM (0x{:x}-0x{:x}) would overlap the {} (0x{:x}-0x{:x})",
+                    rom_start,
+                    rom_end,
+                    name.to_lowercase(),
+                    region_start,
+                    region_end
+                ));
+            }
+        }
+
+        self.ram.write_bytes(self.config.load_address, rom)
     }
 
     pub fn initialize_program_counter(&mut self) {
-        self.registers.program_counter = ROM_ADDRESS;
+        self.registers.program_counter = self.config.initial_program_counter;
     }
 
+    /// Runs the next instruction.
+    ///
+    /// With `Timing::CosmacVip` the given time is ignored, since timers are ticked by the
+    /// simulated display interrupt instead.
     pub fn step(&mut self, time: &Instant, inputs: &Inputs) -> Result<ScreenChanged, String> {
         self.inputs = inputs.clone();
-        self.handle_timers(time);
+        if self.config.timing == Timing::Fixed {
+            self.handle_timers(time);
+        }
+
+        let program_counter = self.registers.program_counter;
+        self.record_access(|access_map| {
+            access_map.record_execute(program_counter);
+            access_map.record_execute(program_counter.wrapping_add(1));
+        });
+
+        let cycles_before_step = self.cycle_count;
+        let (instruction, screen_changed) = match self.fetch_and_execute() {
+            Ok(result) => result,
+            Err(message) => {
+                // Leave the program counter at the instruction that failed, so that debuggers and
+                // error reports point at it rather than the one after it
+                self.registers.program_counter = program_counter;
+                return Err(format!(
+                    "Error at {}: {}",
+                    self.symbols.format_address(program_counter),
+                    message
+                ));
+            }
+        };
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            let cycles = self.cycle_count - cycles_before_step;
+            profiler.record(program_counter, &instruction, cycles);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(
+                program_counter,
+                &instruction,
+                self.registers.program_counter,
+            );
+        }
+
+        Ok(screen_changed)
+    }
+
+    fn fetch_and_execute(&mut self) -> Result<(Instruction, ScreenChanged), String> {
+        let instruction = if self.use_instruction_cache {
+            self.ram.read_instruction(self.registers.program_counter)?
+        } else {
+            let instruction_bytes = self.fetch()?;
+            self.decode(instruction_bytes)?
+        };
+
+        let screen_changed = match self.config.timing {
+            Timing::Fixed => {
+                self.cycle_count += 1;
+                self.execute(&instruction)?
+            }
+            Timing::CosmacVip => self.execute_timed(&instruction)?,
+        };
+
+        Ok((instruction, screen_changed))
+    }
 
-        let instruction_bytes = self.fetch()?;
-        let instruction = self.decode(instruction_bytes)?;
-        self.execute(&instruction)
+    fn record_access<F>(&mut self, record: F)
+    where
+        F: FnOnce(&mut AccessMap),
+    {
+        if let Some(access_map) = self.access_map.as_mut() {
+            record(access_map);
+        }
+    }
+
+    /// Returns the number of cycles that have been run. With `Timing::CosmacVip` this counts
+    /// machine cycles, otherwise it counts instructions.
+    pub fn get_cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    /// Runs the instruction and advances the cycle counter by as long as it would take on the
+    /// COSMAC VIP.
+    fn execute_timed(&mut self, instruction: &Instruction) -> Result<ScreenChanged, String> {
+        // On the VIP, DXYN waits for the display interrupt so that sprites are drawn between
+        // frames
+        if let Instruction::DrawSprite(_, _, _) = instruction {
+            if self.config.quirks.vblank {
+                self.cycle_count = cmp::max(self.cycle_count, self.next_interrupt_cycle);
+                self.handle_interrupts();
+            }
+        }
+
+        let program_counter = self.registers.program_counter;
+        let result = self.execute(instruction)?;
+        let skipped = self.registers.program_counter
+            == program_counter.wrapping_add(2 * INSTRUCTION_SIZE_BYTES);
+
+        self.cycle_count += timing::get_instruction_cycles(instruction, skipped);
+        self.handle_interrupts();
+
+        Ok(result)
+    }
+
+    /// Runs the display interrupts for any frames that have started, each of which ticks the
+    /// timers and takes up some of the frame's cycles.
+    fn handle_interrupts(&mut self) {
+        while self.cycle_count >= self.next_interrupt_cycle {
+            self.cycle_count += timing::INTERRUPT_MACHINE_CYCLES;
+            self.next_interrupt_cycle += timing::MACHINE_CYCLES_PER_FRAME;
+            self.tick_timers(1);
+        }
     }
 
     fn handle_timers(&mut self, time: &Instant) {
@@ -79,23 +385,25 @@ impl CPU {
             // might as well handle the case where 2+ ticks might need to be applied. Though that
             // would likely only be in cases of extreme lag.
             let num_ticks_elapsed =
-                (time_since_last_tick.as_micros() / TIMER_TICK_DURATION.as_micros()) as u8;
+                time_since_last_tick.as_micros() / TIMER_TICK_DURATION.as_micros();
 
             // Adjust the timing value that we record. Making sure to exclude the portion of the
             // next tick that hasn't elapsed yet.
-            let time_increment = Duration::from_micros(
-                (num_ticks_elapsed as u128 * TIMER_TICK_DURATION.as_micros()) as u64,
-            );
+            let time_increment =
+                Duration::from_micros((num_ticks_elapsed * TIMER_TICK_DURATION.as_micros()) as u64);
             self.last_timer_tick = Some(self.last_timer_tick.unwrap() + time_increment);
 
-            // Decrement the timers
-            self.registers.delay_timer =
-                cmp::max(0, self.registers.delay_timer - num_ticks_elapsed);
-            self.registers.sound_timer =
-                cmp::max(0, self.registers.sound_timer - num_ticks_elapsed);
+            // Anything more than 255 ticks is enough to run out either timer
+            self.tick_timers(cmp::min(num_ticks_elapsed, u8::MAX as u128) as u8);
         }
     }
 
+    /// Decrements the timers by the given number of ticks, stopping at 0.
+    fn tick_timers(&mut self, num_ticks: u8) {
+        self.registers.delay_timer = self.registers.delay_timer.saturating_sub(num_ticks);
+        self.registers.sound_timer = self.registers.sound_timer.saturating_sub(num_ticks);
+    }
+
     fn fetch(&self) -> Result<u16, String> {
         self.ram.read_u16(self.registers.program_counter)
     }
@@ -109,7 +417,10 @@ impl CPU {
 
         // Increment the program counter to look at the next instruction. Jump instructions will
         // overwrite this change with their jump destination.
-        self.registers.program_counter += INSTRUCTION_SIZE_BYTES;
+        self.registers.program_counter = self
+            .registers
+            .program_counter
+            .wrapping_add(INSTRUCTION_SIZE_BYTES);
 
         match instruction {
             // 0x00E0
@@ -118,13 +429,16 @@ impl CPU {
                 Ok(ScreenChanged::Changed)
             }
             // 0x00EE
-            Return() => match self.registers.stack.pop() {
-                Some(address) => {
-                    self.registers.program_counter = address;
-                    Ok(ScreenChanged::NoChange)
-                }
-                None => Err("No address on the stack to return to.".to_string()),
-            },
+            Return() => {
+                let depth = match self.registers.stack.len() {
+                    0 => return Err("No address on the stack to return to.".to_string()),
+                    n => n - 1,
+                };
+
+                self.registers.program_counter = self.get_stack_entry(depth)?;
+                self.registers.stack.pop();
+                Ok(ScreenChanged::NoChange)
+            }
             // 0x1NNN
             Jump(address) => {
                 self.registers.program_counter = *address;
@@ -132,14 +446,36 @@ impl CPU {
             }
             // 0x2NNN
             Call(address) => {
-                self.registers.stack.push(self.registers.program_counter);
+                let depth = self.registers.stack.len();
+                if depth >= self.config.stack_depth {
+                    return Err(format!(
+                        "Stack overflow: call to {} with {} return addresses already on the stack (max {})",
+                        self.symbols.format_address(*address), depth, self.config.stack_depth
+                    ));
+                }
+
+                let return_address = self.registers.program_counter;
+                if let Some(entry_address) = self.config.get_stack_entry_address(depth) {
+                    self.ram.write_u16(entry_address, return_address)?;
+
+                    let call_address = return_address.wrapping_sub(INSTRUCTION_SIZE_BYTES);
+                    self.record_access(|access_map| {
+                        access_map.record_write(call_address, entry_address);
+                        access_map.record_write(call_address, entry_address.wrapping_add(1));
+                    });
+                }
+                self.registers.stack.push(return_address);
+
                 self.registers.program_counter = *address;
                 Ok(ScreenChanged::NoChange)
             }
             // 0x3XNN
             JumpIfEqValue(register, value) => {
                 if self.registers.get_register(register) == *value {
-                    self.registers.program_counter += INSTRUCTION_SIZE_BYTES;
+                    self.registers.program_counter = self
+                        .registers
+                        .program_counter
+                        .wrapping_add(INSTRUCTION_SIZE_BYTES);
                 }
 
                 Ok(ScreenChanged::NoChange)
@@ -147,7 +483,10 @@ impl CPU {
             // 0x4XNN
             JumpIfNotEqValue(register, value) => {
                 if self.registers.get_register(register) != *value {
-                    self.registers.program_counter += INSTRUCTION_SIZE_BYTES;
+                    self.registers.program_counter = self
+                        .registers
+                        .program_counter
+                        .wrapping_add(INSTRUCTION_SIZE_BYTES);
                 }
 
                 Ok(ScreenChanged::NoChange)
@@ -157,7 +496,10 @@ impl CPU {
                 let a = self.registers.get_register(first_register);
                 let b = self.registers.get_register(second_register);
                 if a == b {
-                    self.registers.program_counter += INSTRUCTION_SIZE_BYTES;
+                    self.registers.program_counter = self
+                        .registers
+                        .program_counter
+                        .wrapping_add(INSTRUCTION_SIZE_BYTES);
                 }
 
                 Ok(ScreenChanged::NoChange)
@@ -169,114 +511,59 @@ impl CPU {
             }
             // 0x7XNN
             IncrementRegister(register, increment) => {
+                // 7XNN never changes VF, even when it overflows
                 let prev_value = self.registers.get_register(register);
                 self.registers
-                    .set_register(register, prev_value + increment);
+                    .set_register(register, prev_value.wrapping_add(*increment));
                 Ok(ScreenChanged::NoChange)
             }
             // 0x8XY0
             CopyRegister(first_register, second_register) => {
-                let value = self.registers.get_register(second_register);
-                self.registers.set_register(first_register, value);
-                Ok(ScreenChanged::NoChange)
+                self.execute_alu(AluOperation::Copy, first_register, second_register)
             }
             // 0x8XY1
             BitwiseOr(first_register, second_register) => {
-                let a = self.registers.get_register(first_register);
-                let b = self.registers.get_register(second_register);
-
-                self.registers.set_register(first_register, a | b);
-                Ok(ScreenChanged::NoChange)
+                self.execute_alu(AluOperation::Or, first_register, second_register)
             }
             // 0x8XY2
             BitwiseAnd(first_register, second_register) => {
-                let a = self.registers.get_register(first_register);
-                let b = self.registers.get_register(second_register);
-
-                self.registers.set_register(first_register, a & b);
-                Ok(ScreenChanged::NoChange)
+                self.execute_alu(AluOperation::And, first_register, second_register)
             }
             // 0x8XY3
             BitwiseXor(first_register, second_register) => {
-                let a = self.registers.get_register(first_register);
-                let b = self.registers.get_register(second_register);
-
-                self.registers.set_register(first_register, a ^ b);
-                Ok(ScreenChanged::NoChange)
+                self.execute_alu(AluOperation::Xor, first_register, second_register)
             }
             // 0x8XY4
             IncrementByRegister(first_register, second_register) => {
-                let a = self.registers.get_register(first_register);
-                let b = self.registers.get_register(second_register);
-
-                let (value, overflowed) = u8::overflowing_add(a, b);
-                self.registers.set_register(first_register, value);
-
-                self.registers.vf = match overflowed {
-                    true => 1,
-                    false => 0,
-                };
-
-                Ok(ScreenChanged::NoChange)
+                self.execute_alu(AluOperation::Add, first_register, second_register)
             }
             // 0x8XY5
             DecrementByRegister(first_register, second_register) => {
-                let a = self.registers.get_register(first_register);
-                let b = self.registers.get_register(second_register);
-
-                let (value, overflowed) = u8::overflowing_sub(a, b);
-                self.registers.set_register(first_register, value);
-
-                self.registers.vf = match overflowed {
-                    true => 0,
-                    false => 1,
-                };
-
-                Ok(ScreenChanged::NoChange)
+                self.execute_alu(AluOperation::Subtract, first_register, second_register)
             }
             // 0x8XY6
-            RightShift(register) => {
-                let a = self.registers.get_register(register);
-                let value = a >> 1;
-                let underflowed_bit = (a & 0x01) as u8;
-
-                self.registers.set_register(register, value);
-                self.registers.vf = underflowed_bit;
-
-                Ok(ScreenChanged::NoChange)
-            }
-            // 0x8XY5
-            DecrementByRegisterRev(first_register, second_register) => {
-                let a = self.registers.get_register(first_register);
-                let b = self.registers.get_register(second_register);
-
-                let (value, overflowed) = u8::overflowing_sub(b, a);
-                self.registers.set_register(first_register, value);
-
-                self.registers.vf = match overflowed {
-                    true => 0,
-                    false => 1,
-                };
-
-                Ok(ScreenChanged::NoChange)
+            RightShift(first_register, second_register) => {
+                self.execute_alu(AluOperation::ShiftRight, first_register, second_register)
             }
+            // 0x8XY7
+            DecrementByRegisterRev(first_register, second_register) => self.execute_alu(
+                AluOperation::SubtractReversed,
+                first_register,
+                second_register,
+            ),
             // 0x8XYE
-            LeftShift(register) => {
-                let a = self.registers.get_register(register);
-                let value = a << 1;
-                let overflowed_bit = ((a & 0x80) >> 7) as u8;
-
-                self.registers.set_register(register, value);
-                self.registers.vf = overflowed_bit;
-
-                Ok(ScreenChanged::NoChange)
+            LeftShift(first_register, second_register) => {
+                self.execute_alu(AluOperation::ShiftLeft, first_register, second_register)
             }
             // 0x9XY0
             JumpIfRegistersNotEq(first_register, second_register) => {
                 let a = self.registers.get_register(first_register);
                 let b = self.registers.get_register(second_register);
                 if a != b {
-                    self.registers.program_counter += INSTRUCTION_SIZE_BYTES;
+                    self.registers.program_counter = self
+                        .registers
+                        .program_counter
+                        .wrapping_add(INSTRUCTION_SIZE_BYTES);
                 }
 
                 Ok(ScreenChanged::NoChange)
@@ -286,9 +573,20 @@ impl CPU {
                 self.registers.index_register = *address;
                 Ok(ScreenChanged::NoChange)
             }
+            // 0xBNNN
+            JumpWithOffset(address) => {
+                let offset_register = match self.config.quirks.jump {
+                    true => Register::from_nibble((*address >> 8) as u8),
+                    false => Register::V0,
+                };
+                let offset = self.registers.get_register(&offset_register) as u16;
+
+                self.registers.program_counter = *address + offset;
+                Ok(ScreenChanged::NoChange)
+            }
             // 0xCXNN
             SetRandomAnd(register, mask) => {
-                let random_value: u8 = rand::random();
+                let random_value: u8 = self.rng.gen();
 
                 let value = random_value & mask;
 
@@ -301,11 +599,19 @@ impl CPU {
                 let y = self.registers.get_register(y_register);
                 let position = Position::new(x, y);
 
-                let bytes = self
-                    .ram
-                    .read_sprite(self.registers.index_register, *height)?;
-
-                let any_pixels_unset = self.screen.draw_sprite(&position, &bytes)?;
+                let index = self.registers.index_register;
+                let bytes = self.ram.read_sprite(index, *height)?;
+                self.record_access(|access_map| {
+                    for i in 0..*height {
+                        access_map.record_sprite_read(index.wrapping_add(i as u16));
+                    }
+                });
+
+                let edge = match self.config.quirks.wrap {
+                    true => SpriteEdge::Wrap,
+                    false => SpriteEdge::Clip,
+                };
+                let any_pixels_unset = self.screen.draw_sprite(&position, &bytes, edge)?;
                 self.registers.vf = match any_pixels_unset {
                     AnyPixelsUnset::Yes => 1,
                     AnyPixelsUnset::No => 0,
@@ -318,7 +624,10 @@ impl CPU {
                 let key = self.registers.get_register(register);
 
                 if self.inputs.get_input(key)? == InputState::NotPressed {
-                    self.registers.program_counter += INSTRUCTION_SIZE_BYTES;
+                    self.registers.program_counter = self
+                        .registers
+                        .program_counter
+                        .wrapping_add(INSTRUCTION_SIZE_BYTES);
                 }
 
                 Ok(ScreenChanged::NoChange)
@@ -348,14 +657,32 @@ impl CPU {
             IncrementIndexByRegister(register) => {
                 let value = self.registers.get_register(register);
 
-                self.registers.index_register += value as u16;
+                let index = self.registers.index_register.wrapping_add(value as u16);
+                self.registers.index_register = index;
+
+                if self.config.quirks.index_overflow {
+                    self.registers.vf = match index as usize >= MEMORY_SIZE {
+                        true => 1,
+                        false => 0,
+                    };
+                }
                 Ok(ScreenChanged::NoChange)
             }
             // 0xFX29
             GetFontCharacter(register) => {
                 let value = self.registers.get_register(register) as u16;
 
-                let character_address = FONT_ADDRESS + value * 5;
+                let character_address = self.config.font_address + value * SMALL_GLYPH_SIZE as u16;
+
+                self.registers.index_register = character_address;
+                Ok(ScreenChanged::NoChange)
+            }
+            // 0xFX30
+            GetLargeFontCharacter(register) => {
+                let value = self.registers.get_register(register) as u16;
+
+                let character_address =
+                    self.config.large_font_address + value * LARGE_GLYPH_SIZE as u16;
 
                 self.registers.index_register = character_address;
                 Ok(ScreenChanged::NoChange)
@@ -370,8 +697,17 @@ impl CPU {
                 let ones_place = value % 10;
 
                 self.ram.write_byte(base_address, hundreds_place)?;
-                self.ram.write_byte(base_address + 1, tens_place)?;
-                self.ram.write_byte(base_address + 2, ones_place)?;
+                self.ram
+                    .write_byte(base_address.wrapping_add(1), tens_place)?;
+                self.ram
+                    .write_byte(base_address.wrapping_add(2), ones_place)?;
+
+                let instruction_address = self.get_instruction_address();
+                self.record_access(|access_map| {
+                    for i in 0..3 {
+                        access_map.record_write(instruction_address, base_address.wrapping_add(i));
+                    }
+                });
 
                 Ok(ScreenChanged::NoChange)
             }
@@ -385,10 +721,16 @@ impl CPU {
                 {
                     let value = self.registers.get_register(register);
 
-                    let dest_address = base_address + (i as u16);
+                    let dest_address = base_address.wrapping_add(i as u16);
                     self.ram.write_byte(dest_address, value)?;
+
+                    let instruction_address = self.get_instruction_address();
+                    self.record_access(|access_map| {
+                        access_map.record_write(instruction_address, dest_address)
+                    });
                 }
 
+                self.increment_index_for_memory_quirks(last_register);
                 Ok(ScreenChanged::NoChange)
             }
             // 0xFX65
@@ -399,17 +741,118 @@ impl CPU {
                     .iter()
                     .enumerate()
                 {
-                    let src_address = base_address + (i as u16);
+                    let src_address = base_address.wrapping_add(i as u16);
                     let value = self.ram.read_byte(src_address)?;
+                    self.record_access(|access_map| access_map.record_read(src_address));
 
                     self.registers.set_register(register, value);
                 }
 
+                self.increment_index_for_memory_quirks(last_register);
                 Ok(ScreenChanged::NoChange)
             }
             i => panic!("Unhandled instruction: {:?}", i),
         }
     }
+
+    /// Returns the address of the instruction being executed, for use once the program counter has
+    /// been moved past it.
+    fn get_instruction_address(&self) -> Address {
+        self.registers
+            .program_counter
+            .wrapping_sub(INSTRUCTION_SIZE_BYTES)
+    }
+
+    /// Runs an 8XYN instruction, writing the result to VX and then the flag to VF.
+    fn execute_alu(
+        &mut self,
+        operation: AluOperation,
+        first_register: &Register,
+        second_register: &Register,
+    ) -> Result<ScreenChanged, String> {
+        let a = self.registers.get_register(first_register);
+        let b = self.registers.get_register(second_register);
+
+        let result = alu::apply(operation, a, b, &self.config.quirks);
+
+        self.registers.set_register(first_register, result.value);
+        if let FlagEffect::Set(flag) = result.flag {
+            self.registers.vf = flag;
+        }
+
+        Ok(ScreenChanged::NoChange)
+    }
+
+    fn increment_index_for_memory_quirks(&mut self, last_register: &Register) {
+        if self.config.quirks.memory_leave_i_unchanged {
+            return;
+        }
+
+        let num_registers = last_register.to_nibble() as u16;
+        let increment = match self.config.quirks.memory_increment_by_x {
+            true => num_registers,
+            false => num_registers + 1,
+        };
+        self.registers.index_register = self.registers.index_register.wrapping_add(increment);
+    }
+}
+
+/// A register that can be viewed and changed by a debugger.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebugRegister {
+    V(Register),
+    Index,
+    ProgramCounter,
+    /// Number of return addresses on the stack.
+    StackPointer,
+    DelayTimer,
+    SoundTimer,
+}
+
+impl DebugRegister {
+    /// All of the registers, in the order debuggers list them.
+    pub fn all() -> Vec<DebugRegister> {
+        let mut registers: Vec<DebugRegister> = (0..16)
+            .map(|n| DebugRegister::V(Register::from_nibble(n)))
+            .collect();
+        registers.extend_from_slice(&[
+            DebugRegister::Index,
+            DebugRegister::ProgramCounter,
+            DebugRegister::StackPointer,
+            DebugRegister::DelayTimer,
+            DebugRegister::SoundTimer,
+        ]);
+
+        registers
+    }
+
+    pub fn get_name(&self) -> String {
+        match self {
+            DebugRegister::V(register) => format!("v{:x}", register.to_nibble()),
+            DebugRegister::Index => "i".to_string(),
+            DebugRegister::ProgramCounter => "pc".to_string(),
+            DebugRegister::StackPointer => "sp".to_string(),
+            DebugRegister::DelayTimer => "dt".to_string(),
+            DebugRegister::SoundTimer => "st".to_string(),
+        }
+    }
+
+    /// Returns the size of the register in bytes.
+    pub fn get_size(&self) -> usize {
+        match self {
+            DebugRegister::Index | DebugRegister::ProgramCounter => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// A subroutine call that has not returned yet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StackFrame {
+    /// Address of the 2NNN instruction that made the call.
+    pub call_address: Address,
+    /// Address that execution will continue at when the subroutine returns.
+    pub return_address: Address,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -495,16 +938,82 @@ impl Registers {
 fn cpu_load_default_font() {
     let mut cpu = CPU::default();
 
-    assert_eq!(Ok(0x00), cpu.ram.read_byte(FONT_ADDRESS));
+    let font_address = cpu.config.font_address;
+    assert_eq!(Ok(0x00), cpu.ram.read_byte(font_address));
 
     assert_eq!(Ok(()), cpu.load_default_font());
 
-    for i in 0..80 {
+    let font = Font::default();
+    for i in 0..SMALL_FONT_SIZE {
         assert_eq!(
-            Ok(DEFAULT_FONT[i]),
-            cpu.ram.read_byte(FONT_ADDRESS + (i as u16))
+            Ok(font.get_small()[i]),
+            cpu.ram.read_byte(font_address + (i as u16))
         );
     }
+
+    let large_font_address = cpu.config.large_font_address;
+    for i in 0..LARGE_FONT_SIZE {
+        assert_eq!(
+            Ok(font.get_large()[i]),
+            cpu.ram.read_byte(large_font_address + (i as u16))
+        );
+    }
+}
+
+#[test]
+fn cpu_get_font_characters() {
+    let mut cpu = CPU::default();
+    cpu.registers.v1 = 0xA;
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::GetFontCharacter(Register::V1))
+    );
+    assert_eq!(cpu.config.font_address + 50, cpu.registers.index_register);
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::GetLargeFontCharacter(Register::V1))
+    );
+    assert_eq!(
+        cpu.config.large_font_address + 100,
+        cpu.registers.index_register
+    );
+}
+
+#[test]
+fn cpu_load_rom_too_large() {
+    let mut cpu = CPU::default();
+
+    let rom = vec![0xFF; 3585];
+    let expected =
+        Err("ROM is too large to fit in memory: 3585 bytes (max 3584 bytes)".to_string());
+    assert_eq!(expected, cpu.load_rom(&rom));
+
+    assert_eq!(Ok(0x00), cpu.ram.read_byte(cpu.config.load_address));
+}
+
+#[test]
+fn cpu_load_rom_custom_address() {
+    let mut cpu = CPU::new(MachineConfig::eti_660(), 0);
+
+    assert_eq!(Ok(()), cpu.load_rom(&[0x12, 0x34]));
+    cpu.initialize_program_counter();
+
+    assert_eq!(Ok(0x1234), cpu.ram.read_u16(0x0600));
+    assert_eq!(0x0600, cpu.registers.program_counter);
+}
+
+#[test]
+fn cpu_load_rom_overlapping_font() {
+    let config = MachineConfig {
+        font_address: 0x0210,
+        ..MachineConfig::default()
+    };
+    let mut cpu = CPU::new(config, 0);
+
+    let expected = Err("ROM (0x200-0x220) would overlap the font (0x210-0x260)".to_string());
+    assert_eq!(expected, cpu.load_rom(&[0xFF; 0x20]));
 }
 
 #[test]
@@ -542,3 +1051,645 @@ fn cpu_dump_load_registers() {
     assert_eq!(48, cpu.registers.v1);
     assert_eq!(48, cpu.registers.v0);
 }
+
+#[test]
+fn cpu_set_random_and_same_seed() {
+    let mut cpu_a = CPU::new(MachineConfig::default(), 42);
+    let mut cpu_b = CPU::new(MachineConfig::default(), 42);
+
+    for _ in 0..16 {
+        let instruction = Instruction::SetRandomAnd(Register::V3, 0xFF);
+        assert_eq!(Ok(ScreenChanged::NoChange), cpu_a.execute(&instruction));
+        assert_eq!(Ok(ScreenChanged::NoChange), cpu_b.execute(&instruction));
+
+        assert_eq!(cpu_a.registers.v3, cpu_b.registers.v3);
+    }
+}
+
+#[test]
+fn cpu_shift_quirk() {
+    let mut cpu = CPU::default();
+    cpu.registers.v1 = 0b0000_0011;
+    cpu.registers.v2 = 0b1000_0100;
+
+    cpu.config.quirks.shift = true;
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::RightShift(Register::V1, Register::V2))
+    );
+    assert_eq!(0b0000_0001, cpu.registers.v1);
+    assert_eq!(1, cpu.registers.vf);
+
+    cpu.config.quirks.shift = false;
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::LeftShift(Register::V1, Register::V2))
+    );
+    assert_eq!(0b0000_1000, cpu.registers.v1);
+    assert_eq!(1, cpu.registers.vf);
+}
+
+#[test]
+fn cpu_memory_quirks() {
+    let mut cpu = CPU::default();
+    cpu.registers.index_register = 0x0400;
+
+    cpu.config.quirks.memory_increment_by_x = false;
+    cpu.config.quirks.memory_leave_i_unchanged = false;
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::DumpRegisters(Register::V2))
+    );
+    assert_eq!(0x0403, cpu.registers.index_register);
+
+    cpu.config.quirks.memory_increment_by_x = true;
+    cpu.config.quirks.memory_leave_i_unchanged = false;
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::LoadRegisters(Register::V2))
+    );
+    assert_eq!(0x0405, cpu.registers.index_register);
+}
+
+#[test]
+fn cpu_logic_quirk() {
+    let mut cpu = CPU::default();
+    cpu.registers.vf = 1;
+
+    cpu.config.quirks.logic = true;
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::BitwiseOr(Register::V1, Register::V2))
+    );
+    assert_eq!(0, cpu.registers.vf);
+}
+
+#[test]
+fn cpu_wrap_quirk() {
+    let mut cpu = CPU::default();
+    assert_eq!(Ok(()), cpu.ram.write_byte(0x0300, 0xFF));
+    cpu.registers.index_register = 0x0300;
+    cpu.registers.v0 = 0xFF;
+    cpu.registers.v1 = 0x03;
+
+    // The start position wraps to (63, 3), and the rest of the sprite is clipped
+    assert_eq!(
+        Ok(ScreenChanged::Changed),
+        cpu.execute(&Instruction::DrawSprite(Register::V0, Register::V1, 1))
+    );
+    assert_eq!(
+        Ok(crate::screen::Pixel::On),
+        cpu.screen.get_value(&Position::new(63, 3))
+    );
+    assert_eq!(
+        Ok(crate::screen::Pixel::Off),
+        cpu.screen.get_value(&Position::new(0, 3))
+    );
+
+    cpu.screen.clear();
+    cpu.config.quirks.wrap = true;
+    assert_eq!(
+        Ok(ScreenChanged::Changed),
+        cpu.execute(&Instruction::DrawSprite(Register::V0, Register::V1, 1))
+    );
+    assert_eq!(
+        Ok(crate::screen::Pixel::On),
+        cpu.screen.get_value(&Position::new(63, 3))
+    );
+    assert_eq!(
+        Ok(crate::screen::Pixel::On),
+        cpu.screen.get_value(&Position::new(6, 3))
+    );
+}
+
+#[test]
+fn cpu_jump_with_offset_quirk() {
+    let mut cpu = CPU::default();
+    cpu.registers.v0 = 0x01;
+    cpu.registers.v3 = 0x02;
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::JumpWithOffset(0x0310))
+    );
+    assert_eq!(0x0311, cpu.registers.program_counter);
+
+    cpu.config.quirks.jump = true;
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::JumpWithOffset(0x0310))
+    );
+    assert_eq!(0x0312, cpu.registers.program_counter);
+}
+
+#[test]
+fn cpu_call_stack_overflow() {
+    let config = MachineConfig {
+        stack_depth: 2,
+        ..MachineConfig::default()
+    };
+    let mut cpu = CPU::new(config, 0);
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::Call(0x0300))
+    );
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::Call(0x0400))
+    );
+
+    let expected = Err(
+        "Stack overflow: call to 0x0500 with 2 return addresses already on the stack (max 2)"
+            .to_string(),
+    );
+    assert_eq!(expected, cpu.execute(&Instruction::Call(0x0500)));
+
+    cpu.set_symbols(SymbolMap::parse("label 0x04f0 update_enemies").unwrap());
+    let expected = Err(
+        "Stack overflow: call to update_enemies+0x10 with 2 return addresses already on the stack (max 2)"
+            .to_string(),
+    );
+    assert_eq!(expected, cpu.execute(&Instruction::Call(0x0500)));
+}
+
+#[test]
+fn cpu_step_error_location() {
+    let mut cpu = CPU::new(MachineConfig::default(), 0);
+    let rom = [
+        0x00, 0xE0, // 0x200: Clear display
+        0x00, 0xEE, // 0x202: Return with nothing on the stack
+    ];
+    assert_eq!(Ok(()), cpu.load_rom(&rom));
+    cpu.initialize_program_counter();
+    cpu.set_symbols(SymbolMap::parse("label 0x0200 main").unwrap());
+
+    let time = Instant::now();
+    let inputs = Inputs::default();
+    assert_eq!(Ok(ScreenChanged::Changed), cpu.step(&time, &inputs));
+
+    let expected = Err("Error at main+0x2: No address on the stack to return to.".to_string());
+    assert_eq!(expected, cpu.step(&time, &inputs));
+    assert_eq!(0x0202, cpu.registers.program_counter);
+}
+
+#[test]
+fn cpu_call_stack_frames() {
+    let mut cpu = CPU::default();
+    cpu.registers.program_counter = 0x0200;
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::Call(0x0300))
+    );
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::Call(0x0400))
+    );
+
+    let expected = vec![
+        StackFrame {
+            call_address: 0x0300,
+            return_address: 0x0302,
+        },
+        StackFrame {
+            call_address: 0x0200,
+            return_address: 0x0202,
+        },
+    ];
+    assert_eq!(Ok(expected), cpu.get_call_stack());
+}
+
+#[test]
+fn cpu_vip_stack_in_ram() {
+    let mut cpu = CPU::new(MachineConfig::default().with_vip_stack(), 0);
+    cpu.registers.program_counter = 0x0200;
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::Call(0x0300))
+    );
+    assert_eq!(Ok(0x0202), cpu.ram.read_u16(0x0EB6));
+
+    // Programs can change where a subroutine returns to by writing to the stack
+    assert_eq!(Ok(()), cpu.ram.write_u16(0x0EB6, 0x0250));
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::Return())
+    );
+    assert_eq!(0x0250, cpu.registers.program_counter);
+}
+
+#[test]
+fn cpu_step_self_modifying_code() {
+    let mut cpu = CPU::new(MachineConfig::default(), 0);
+    let rom = [
+        0x60, 0x12, // 0x200: V0 = 0x12
+        0x61, 0x04, // 0x202: V1 = 0x04
+        0xA2, 0x08, // 0x204: I = 0x208
+        0xF1,
+        0x55, // 0x206: Write V0 and V1 over the next instruction, making it "jump 0x204"
+        0x00, 0xE0, // 0x208: Clear display
+    ];
+    assert_eq!(Ok(()), cpu.load_rom(&rom));
+    cpu.initialize_program_counter();
+
+    let time = Instant::now();
+    let inputs = Inputs::default();
+
+    // Run the original instruction once so that it is cached
+    cpu.registers.program_counter = 0x0208;
+    assert_eq!(Ok(ScreenChanged::Changed), cpu.step(&time, &inputs));
+
+    cpu.registers.program_counter = 0x0200;
+    for _ in 0..5 {
+        assert_eq!(Ok(ScreenChanged::NoChange), cpu.step(&time, &inputs));
+    }
+    assert_eq!(0x0204, cpu.registers.program_counter);
+}
+
+#[test]
+fn cpu_access_tracking() {
+    let mut cpu = CPU::new(MachineConfig::default(), 0);
+    let rom = [
+        0xA2, 0x00, // 0x200: I = 0x200
+        0xD0, 0x02, // 0x202: Draw the first two bytes of the program as a sprite
+        0xA2, 0x0A, // 0x204: I = 0x20A
+        0x61, 0xE0, // 0x206: V1 = 0xE0
+        0xF1,
+        0x55, // 0x208: Write V0 and V1 over the next instruction, making it "clear display"
+        0x12, 0x0A, // 0x20A: Jump to 0x20A
+    ];
+    assert_eq!(Ok(()), cpu.load_rom(&rom));
+    cpu.initialize_program_counter();
+    cpu.set_access_tracking_enabled(true);
+
+    let time = Instant::now();
+    let inputs = Inputs::default();
+    for _ in 0..6 {
+        assert!(cpu.step(&time, &inputs).is_ok());
+    }
+
+    let access_map = cpu.get_access_map().unwrap();
+    assert_eq!(
+        vec![(0x020A, 0x0208), (0x020B, 0x0208)],
+        access_map
+            .get_self_modified()
+            .iter()
+            .map(|(address, writer)| (*address, *writer))
+            .collect::<Vec<(Address, Address)>>()
+    );
+    assert_eq!(
+        vec![0x0200, 0x0201],
+        access_map
+            .get_sprite_code_overlaps()
+            .iter()
+            .copied()
+            .collect::<Vec<Address>>()
+    );
+    assert_eq!("R-X", access_map.describe(0x0200));
+}
+
+#[test]
+fn cpu_profiling() {
+    let mut cpu = CPU::new(MachineConfig::default(), 0);
+    let rom = [
+        0x22, 0x04, // 0x200: Call 0x204
+        0x12, 0x00, // 0x202: Jump to 0x200
+        0x60, 0x01, // 0x204: V0 = 1
+        0x00, 0xEE, // 0x206: Return
+    ];
+    assert_eq!(Ok(()), cpu.load_rom(&rom));
+    cpu.initialize_program_counter();
+    cpu.set_profiling_enabled(true);
+
+    let time = Instant::now();
+    let inputs = Inputs::default();
+    for _ in 0..8 {
+        assert_eq!(Ok(ScreenChanged::NoChange), cpu.step(&time, &inputs));
+    }
+
+    let profiler = cpu.get_profiler().unwrap();
+    assert_eq!(2, profiler.get_address_stats()[&0x0206].count);
+    assert_eq!(4, profiler.get_subroutine_stats()[&0x0204].total_cycles);
+}
+
+#[test]
+fn cpu_coverage() {
+    use crate::coverage::BranchCoverage;
+
+    let mut cpu = CPU::new(MachineConfig::default(), 0);
+    let rom = [
+        0x70, 0x01, // 0x200: V0 += 1
+        0x30, 0x02, // 0x202: Skip if V0 == 2
+        0x12, 0x00, // 0x204: Jump to 0x200
+        0x12, 0x06, // 0x206: Jump to 0x206
+    ];
+    assert_eq!(Ok(()), cpu.load_rom(&rom));
+    cpu.initialize_program_counter();
+    cpu.set_coverage_enabled(true);
+
+    let time = Instant::now();
+    let inputs = Inputs::default();
+    for _ in 0..6 {
+        assert_eq!(Ok(ScreenChanged::NoChange), cpu.step(&time, &inputs));
+    }
+
+    let coverage = cpu.get_coverage().unwrap();
+    assert_eq!(2, coverage.get_hits(0x0200));
+    assert_eq!(1, coverage.get_hits(0x0204));
+    assert_eq!(1, coverage.get_hits(0x0206));
+    assert_eq!(
+        Some(&BranchCoverage {
+            skipped: 1,
+            not_skipped: 1
+        }),
+        coverage.get_branch(0x0202)
+    );
+}
+
+#[test]
+fn cpu_increment_register_wraps() {
+    let mut cpu = CPU::default();
+    cpu.registers.v2 = 0xFF;
+    cpu.registers.vf = 0x05;
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::IncrementRegister(Register::V2, 0x02))
+    );
+    assert_eq!(0x01, cpu.registers.v2);
+    assert_eq!(0x05, cpu.registers.vf);
+}
+
+#[test]
+fn cpu_program_counter_wraps() {
+    let mut cpu = CPU::default();
+    cpu.registers.program_counter = 0xFFFE;
+    cpu.registers.v1 = 0x02;
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::JumpIfEqValue(Register::V1, 0x02))
+    );
+    assert_eq!(0x0002, cpu.registers.program_counter);
+}
+
+#[test]
+fn cpu_increment_index_wraps() {
+    let mut cpu = CPU::default();
+    cpu.registers.index_register = 0xFFFF;
+    cpu.registers.v1 = 0x02;
+    cpu.registers.vf = 0x05;
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::IncrementIndexByRegister(Register::V1))
+    );
+    assert_eq!(0x0001, cpu.registers.index_register);
+    assert_eq!(0x05, cpu.registers.vf);
+}
+
+#[test]
+fn cpu_index_overflow_quirk() {
+    let mut cpu = CPU::default();
+    cpu.config.quirks.index_overflow = true;
+    cpu.registers.index_register = 0x0FFE;
+    cpu.registers.v1 = 0x01;
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::IncrementIndexByRegister(Register::V1))
+    );
+    assert_eq!(0x0FFF, cpu.registers.index_register);
+    assert_eq!(0, cpu.registers.vf);
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::IncrementIndexByRegister(Register::V1))
+    );
+    assert_eq!(0x1000, cpu.registers.index_register);
+    assert_eq!(1, cpu.registers.vf);
+}
+
+#[test]
+fn cpu_memory_quirks_index_wraps() {
+    let mut cpu = CPU::default();
+    cpu.config.quirks.memory_leave_i_unchanged = false;
+    cpu.registers.index_register = 0xFFFF;
+
+    assert!(cpu
+        .execute(&Instruction::LoadRegisters(Register::V0))
+        .is_err());
+
+    cpu.registers.index_register = 0x0FFF;
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::LoadRegisters(Register::V0))
+    );
+    assert_eq!(0x1000, cpu.registers.index_register);
+}
+
+#[test]
+fn cpu_timers_stop_at_zero() {
+    let mut cpu = CPU::default();
+    cpu.registers.delay_timer = 2;
+    cpu.registers.sound_timer = 200;
+
+    let start = Instant::now();
+    cpu.handle_timers(&start);
+
+    // Enough time for 3 ticks
+    cpu.handle_timers(&(start + TIMER_TICK_DURATION * 3 + Duration::from_micros(1)));
+    assert_eq!(0, cpu.registers.delay_timer);
+    assert_eq!(197, cpu.registers.sound_timer);
+
+    // Enough time for over 255 ticks
+    cpu.handle_timers(&(start + TIMER_TICK_DURATION * 300));
+    assert_eq!(0, cpu.registers.sound_timer);
+}
+
+#[test]
+fn cpu_alu_flag_semantics() {
+    use crate::quirks::Quirks;
+    use Instruction::*;
+    use Register::{Vf, V1, V2};
+
+    let vip = Quirks {
+        shift: false,
+        logic: true,
+        ..Quirks::default()
+    };
+    let modern = Quirks {
+        shift: false,
+        logic: false,
+        ..Quirks::default()
+    };
+    let schip = Quirks {
+        shift: true,
+        logic: false,
+        ..Quirks::default()
+    };
+
+    // (quirks, instruction, starting register values, expected register values)
+    type RegisterValues = Vec<(Register, u8)>;
+    #[rustfmt::skip]
+    let cases: Vec<(Quirks, Instruction, RegisterValues, RegisterValues)> = vec![
+        // 0x8XY0
+        (modern, CopyRegister(V1, Vf), vec![(V1, 0x01), (Vf, 0x07)], vec![(V1, 0x07), (Vf, 0x07)]),
+        (modern, CopyRegister(Vf, V2), vec![(V2, 0x09), (Vf, 0x07)], vec![(Vf, 0x09)]),
+        // 0x8XY1
+        (vip, BitwiseOr(V1, V2), vec![(V1, 0x0F), (V2, 0xF0), (Vf, 0x05)], vec![(V1, 0xFF), (Vf, 0x00)]),
+        (vip, BitwiseOr(Vf, V2), vec![(V2, 0xF0), (Vf, 0x0F)], vec![(Vf, 0x00)]),
+        (vip, BitwiseOr(V1, Vf), vec![(V1, 0x0F), (Vf, 0xF0)], vec![(V1, 0xFF), (Vf, 0x00)]),
+        (vip, BitwiseOr(V1, V1), vec![(V1, 0x0F), (Vf, 0x05)], vec![(V1, 0x0F), (Vf, 0x00)]),
+        (modern, BitwiseOr(V1, V2), vec![(V1, 0x0F), (V2, 0xF0), (Vf, 0x05)], vec![(V1, 0xFF), (Vf, 0x05)]),
+        (modern, BitwiseOr(Vf, V2), vec![(V2, 0xF0), (Vf, 0x0F)], vec![(Vf, 0xFF)]),
+        // 0x8XY2
+        (vip, BitwiseAnd(Vf, V2), vec![(V2, 0x3C), (Vf, 0x0F)], vec![(Vf, 0x00)]),
+        (modern, BitwiseAnd(Vf, V2), vec![(V2, 0x3C), (Vf, 0x0F)], vec![(Vf, 0x0C)]),
+        (modern, BitwiseAnd(V1, Vf), vec![(V1, 0x3C), (Vf, 0x0F)], vec![(V1, 0x0C), (Vf, 0x0F)]),
+        // 0x8XY3
+        (vip, BitwiseXor(V1, V1), vec![(V1, 0xAA), (Vf, 0x05)], vec![(V1, 0x00), (Vf, 0x00)]),
+        (modern, BitwiseXor(Vf, V2), vec![(V2, 0x0F), (Vf, 0xFF)], vec![(Vf, 0xF0)]),
+        // 0x8XY4
+        (modern, IncrementByRegister(V1, V2), vec![(V1, 0xFF), (V2, 0x02)], vec![(V1, 0x01), (Vf, 0x01)]),
+        (modern, IncrementByRegister(Vf, V2), vec![(V2, 0x02), (Vf, 0xFF)], vec![(Vf, 0x01)]),
+        (modern, IncrementByRegister(V1, Vf), vec![(V1, 0x10), (Vf, 0x20)], vec![(V1, 0x30), (Vf, 0x00)]),
+        (modern, IncrementByRegister(V1, V1), vec![(V1, 0x80)], vec![(V1, 0x00), (Vf, 0x01)]),
+        // 0x8XY5
+        (modern, DecrementByRegister(V1, V2), vec![(V1, 0x05), (V2, 0x03)], vec![(V1, 0x02), (Vf, 0x01)]),
+        (modern, DecrementByRegister(Vf, V2), vec![(V2, 0x05), (Vf, 0x03)], vec![(Vf, 0x00)]),
+        (modern, DecrementByRegister(V1, Vf), vec![(V1, 0x05), (Vf, 0x03)], vec![(V1, 0x02), (Vf, 0x01)]),
+        (modern, DecrementByRegister(V1, V1), vec![(V1, 0x05)], vec![(V1, 0x00), (Vf, 0x01)]),
+        // 0x8XY6
+        (vip, RightShift(V1, V2), vec![(V1, 0xFF), (V2, 0x03)], vec![(V1, 0x01), (Vf, 0x01)]),
+        (vip, RightShift(Vf, V2), vec![(V2, 0x02), (Vf, 0xFF)], vec![(Vf, 0x00)]),
+        (vip, RightShift(V1, Vf), vec![(V1, 0x00), (Vf, 0x03)], vec![(V1, 0x01), (Vf, 0x01)]),
+        (vip, RightShift(V1, V1), vec![(V1, 0x03)], vec![(V1, 0x01), (Vf, 0x01)]),
+        (schip, RightShift(V1, V2), vec![(V1, 0x02), (V2, 0xFF)], vec![(V1, 0x01), (Vf, 0x00)]),
+        (schip, RightShift(Vf, V2), vec![(V2, 0x00), (Vf, 0x03)], vec![(Vf, 0x01)]),
+        (schip, RightShift(V1, Vf), vec![(V1, 0x04), (Vf, 0x01)], vec![(V1, 0x02), (Vf, 0x00)]),
+        // 0x8XY7
+        (modern, DecrementByRegisterRev(V1, V2), vec![(V1, 0x05), (V2, 0x03)], vec![(V1, 0xFE), (Vf, 0x00)]),
+        (modern, DecrementByRegisterRev(Vf, V2), vec![(V2, 0x05), (Vf, 0x03)], vec![(Vf, 0x01)]),
+        (modern, DecrementByRegisterRev(V1, Vf), vec![(V1, 0x03), (Vf, 0x05)], vec![(V1, 0x02), (Vf, 0x01)]),
+        (modern, DecrementByRegisterRev(V1, V1), vec![(V1, 0x05)], vec![(V1, 0x00), (Vf, 0x01)]),
+        // 0x8XYE
+        (vip, LeftShift(V1, V2), vec![(V1, 0x00), (V2, 0x81)], vec![(V1, 0x02), (Vf, 0x01)]),
+        (vip, LeftShift(Vf, V2), vec![(V2, 0x01), (Vf, 0xFF)], vec![(Vf, 0x00)]),
+        (vip, LeftShift(V1, Vf), vec![(V1, 0x00), (Vf, 0x81)], vec![(V1, 0x02), (Vf, 0x01)]),
+        (schip, LeftShift(V1, V2), vec![(V1, 0x40), (V2, 0xFF)], vec![(V1, 0x80), (Vf, 0x00)]),
+        (schip, LeftShift(Vf, V2), vec![(V2, 0x00), (Vf, 0x80)], vec![(Vf, 0x01)]),
+        (schip, LeftShift(V1, V1), vec![(V1, 0xC0)], vec![(V1, 0x80), (Vf, 0x01)]),
+    ];
+
+    for (quirks, instruction, initial, expected) in cases.iter() {
+        let mut cpu = CPU::default();
+        cpu.config.quirks = *quirks;
+        for (register, value) in initial.iter() {
+            cpu.registers.set_register(register, *value);
+        }
+
+        assert_eq!(Ok(ScreenChanged::NoChange), cpu.execute(instruction));
+
+        for (register, value) in expected.iter() {
+            assert_eq!(
+                *value,
+                cpu.registers.get_register(register),
+                "{:?} of {:?} with {:?}",
+                register,
+                instruction,
+                quirks
+            );
+        }
+    }
+}
+
+#[test]
+fn cpu_vip_timing() {
+    let config = MachineConfig {
+        timing: Timing::CosmacVip,
+        ..MachineConfig::default()
+    };
+    let mut cpu = CPU::new(config, 0);
+    let rom = [
+        0x60, 0x03, // 0x200: V0 = 3
+        0xF0, 0x15, // 0x202: Delay timer = V0
+        0x12, 0x04, // 0x204: Jump to 0x204
+    ];
+    assert_eq!(Ok(()), cpu.load_rom(&rom));
+    cpu.initialize_program_counter();
+
+    let time = Instant::now();
+    let inputs = Inputs::default();
+
+    assert_eq!(Ok(ScreenChanged::NoChange), cpu.step(&time, &inputs));
+    assert_eq!(46, cpu.get_cycle_count());
+    assert_eq!(Ok(ScreenChanged::NoChange), cpu.step(&time, &inputs));
+    assert_eq!(96, cpu.get_cycle_count());
+
+    // The delay timer only ticks on the display interrupt, no matter how much time passes
+    let later = time + Duration::from_secs(10);
+    while cpu.get_cycle_count() < timing::MACHINE_CYCLES_PER_FRAME {
+        assert_eq!(3, cpu.registers.delay_timer);
+        assert_eq!(Ok(ScreenChanged::NoChange), cpu.step(&later, &inputs));
+    }
+    assert_eq!(2, cpu.registers.delay_timer);
+    assert!(
+        cpu.get_cycle_count()
+            >= timing::MACHINE_CYCLES_PER_FRAME + timing::INTERRUPT_MACHINE_CYCLES
+    );
+}
+
+#[test]
+fn cpu_vip_timing_draw_waits_for_vblank() {
+    let config = MachineConfig {
+        timing: Timing::CosmacVip,
+        ..MachineConfig::default()
+    };
+    let mut cpu = CPU::new(config, 0);
+    cpu.config.quirks.vblank = true;
+
+    assert_eq!(
+        Ok(ScreenChanged::Changed),
+        cpu.execute_timed(&Instruction::DrawSprite(Register::V0, Register::V1, 1))
+    );
+
+    let expected_cycles = timing::MACHINE_CYCLES_PER_FRAME
+        + timing::INTERRUPT_MACHINE_CYCLES
+        + timing::get_instruction_cycles(
+            &Instruction::DrawSprite(Register::V0, Register::V1, 1),
+            false,
+        );
+    assert_eq!(expected_cycles, cpu.get_cycle_count());
+}
+
+#[test]
+fn cpu_debug_registers() {
+    let mut cpu = CPU::default();
+    assert_eq!(
+        Ok(()),
+        cpu.set_debug_register(DebugRegister::V(Register::Va), 0x1FF)
+    );
+    assert_eq!(0xFF, cpu.registers.va);
+    assert_eq!(Ok(()), cpu.set_debug_register(DebugRegister::Index, 0x0345));
+    assert_eq!(0x0345, cpu.get_debug_register(DebugRegister::Index));
+
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        cpu.execute(&Instruction::Call(0x0300))
+    );
+    assert_eq!(1, cpu.get_debug_register(DebugRegister::StackPointer));
+    assert!(cpu
+        .set_debug_register(DebugRegister::StackPointer, 2)
+        .is_err());
+    assert_eq!(
+        Ok(()),
+        cpu.set_debug_register(DebugRegister::StackPointer, 0)
+    );
+    assert_eq!(0, cpu.get_debug_register(DebugRegister::StackPointer));
+
+    assert_eq!(21, DebugRegister::all().len());
+    assert_eq!("vf", DebugRegister::V(Register::Vf).get_name());
+}
@@ -0,0 +1,915 @@
+//! A server for the Debug Adapter Protocol, so that editors such as VS Code can debug ROMs.
+//!
+//! Run the interpreter with `dap --port 4711`, then attach to it from the editor. In VS Code this
+//! is done with a launch configuration that sets `"debugServer": 4711`, along with these launch
+//! arguments:
+//!
+//! * `program` - path of the ROM to run
+//! * `symbols` - path of a symbol file for the ROM, which allows breakpoints to be set on source
+//!   lines (optional, see the `symbols` module)
+//! * `stopOnEntry` - whether to pause before running the first instruction (optional)
+//!
+//! Breakpoints can be set on source lines or on instruction addresses. The registers, timers, and
+//! call stack are shown as variables, and RAM can be viewed and edited through memory references.
+
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::{fs, thread, time};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::cpu::{DebugRegister, ScreenChanged, CPU};
+#[cfg(test)]
+use crate::debugger::MockStream;
+use crate::debugger::{Debugger, ExecutionControl, StepDecision, StopReason};
+use crate::ram::{Address, MEMORY_SIZE};
+use crate::symbols::SymbolMap;
+
+const HEADER_END: &[u8] = b"\r\n\r\n";
+const CONTENT_LENGTH_HEADER: &str = "Content-Length: ";
+
+/// CHIP-8 programs only have one thread of execution, so it always has the same id.
+const THREAD_ID: u64 = 1;
+
+const REGISTERS_REFERENCE: u64 = 1;
+const TIMERS_REFERENCE: u64 = 2;
+const STACK_REFERENCE: u64 = 3;
+
+const POLL_MILLISECONDS: u64 = 10;
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LaunchArguments {
+    pub program: String,
+    #[serde(default)]
+    pub symbols: Option<String>,
+    #[serde(default)]
+    pub stop_on_entry: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct Message {
+    seq: u64,
+    #[serde(rename = "type")]
+    message_type: String,
+    #[serde(default)]
+    command: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+pub struct DapServer<S: Read + Write> {
+    stream: S,
+    control: ExecutionControl,
+    /// Bytes received that have not been parsed into messages yet.
+    input: Vec<u8>,
+    next_seq: u64,
+    symbols: SymbolMap,
+    /// Directory that the source files named in the symbol file are relative to.
+    source_directory: String,
+    /// Breakpoints on source lines, by the path of the file they were set in. The editor replaces
+    /// all of the breakpoints in a file at once.
+    source_breakpoints: BTreeMap<String, Vec<Address>>,
+    instruction_breakpoints: Vec<Address>,
+    stop_on_entry: bool,
+    /// Whether the editor has finished setting up breakpoints, so the program can start.
+    configured: bool,
+    /// Stop to report once the response to the current request has been sent.
+    pending_stop: Option<StopReason>,
+    quit: bool,
+}
+
+impl DapServer<TcpStream> {
+    /// Waits for an editor to connect on the given local port.
+    pub fn listen(port: u16) -> Result<DapServer<TcpStream>, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        println!("Waiting for a debug client to connect on port {}", port);
+
+        let (stream, address) = listener.accept().map_err(|e| e.to_string())?;
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        println!("Debug client connected from {}", address);
+
+        Ok(DapServer::new(stream))
+    }
+}
+
+impl<S: Read + Write> DapServer<S> {
+    pub fn new(stream: S) -> DapServer<S> {
+        DapServer {
+            stream,
+            control: ExecutionControl::default(),
+            input: vec![],
+            next_seq: 1,
+            symbols: SymbolMap::default(),
+            source_directory: String::new(),
+            source_breakpoints: BTreeMap::new(),
+            instruction_breakpoints: vec![],
+            stop_on_entry: false,
+            configured: false,
+            pending_stop: None,
+            quit: false,
+        }
+    }
+
+    /// Waits for the editor to ask to launch a ROM, and then loads it with the given function,
+    /// which is also given the ROM's symbols.
+    ///
+    /// The editor is told whether the launch succeeded, and once it has, it is asked to send its
+    /// breakpoints.
+    pub fn launch<F, T>(&mut self, load: F) -> Result<T, String>
+    where
+        F: FnOnce(&LaunchArguments, &SymbolMap) -> Result<T, String>,
+    {
+        let (request, arguments) = self.wait_for_launch()?;
+
+        let result = self
+            .load_symbols(&arguments)
+            .and_then(|_| load(&arguments, &self.symbols));
+        match &result {
+            Ok(_) => {
+                self.send_response(&request, Ok(json!({})))?;
+                self.send_event("initialized", json!({}))?;
+            }
+            Err(message) => self.send_response(&request, Err(message.clone()))?,
+        }
+
+        result
+    }
+
+    /// Lets the editor know that the program has finished running.
+    pub fn terminate(&mut self) {
+        // The editor may have already disconnected, in which case there is no one left to tell
+        let _ = self.send_event("terminated", json!({}));
+    }
+
+    fn wait_for_launch(&mut self) -> Result<(Message, LaunchArguments), String> {
+        loop {
+            self.receive()?;
+
+            while let Some(request) = self.next_request() {
+                match request.command.as_str() {
+                    "initialize" => self.send_response(&request, Ok(get_capabilities()))?,
+                    "launch" => {
+                        let arguments = serde_json::from_value(request.arguments.clone())
+                            .map_err(|e| format!("Invalid launch arguments: {}", e));
+                        match arguments {
+                            Ok(arguments) => return Ok((request, arguments)),
+                            Err(message) => self.send_response(&request, Err(message))?,
+                        }
+                    }
+                    "disconnect" => {
+                        self.send_response(&request, Ok(json!({})))?;
+                        return Err("Debug client disconnected before launching a ROM".to_string());
+                    }
+                    command => {
+                        let message = format!("Cannot handle {} before launching a ROM", command);
+                        self.send_response(&request, Err(message))?;
+                    }
+                }
+            }
+
+            thread::sleep(time::Duration::from_millis(POLL_MILLISECONDS));
+        }
+    }
+
+    fn load_symbols(&mut self, arguments: &LaunchArguments) -> Result<(), String> {
+        self.stop_on_entry = arguments.stop_on_entry;
+
+        if let Some(symbols_path) = &arguments.symbols {
+            let text = fs::read_to_string(symbols_path)
+                .map_err(|e| format!("Failed to read symbol file {}: {}", symbols_path, e))?;
+            self.symbols = SymbolMap::parse(&text)?;
+            self.source_directory = Path::new(symbols_path)
+                .parent()
+                .map(|directory| directory.to_string_lossy().to_string())
+                .unwrap_or_default();
+        }
+
+        Ok(())
+    }
+
+    /// Reads whatever bytes the editor has sent, without waiting for more.
+    fn receive(&mut self) -> Result<(), String> {
+        let mut buffer = [0; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err("Debug client disconnected".to_string()),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+
+    /// Takes the next complete request out of the received bytes.
+    /// Malformed messages are logged and dropped, so one bad message doesn't end the session.
+    fn next_request(&mut self) -> Option<Message> {
+        loop {
+            let header_length = find(&self.input, HEADER_END)?;
+            let content_start = header_length + HEADER_END.len();
+
+            let header = String::from_utf8_lossy(&self.input[..header_length]).to_string();
+            let content_length = match parse_content_length(&header) {
+                Ok(content_length) => content_length,
+                Err(message) => {
+                    // Only the header can be dropped, since the content's length is unknown
+                    println!("Dropping debug adapter message: {}", message);
+                    self.input.drain(..content_start);
+                    continue;
+                }
+            };
+
+            if self.input.len() < content_start + content_length {
+                return None;
+            }
+
+            let message: Vec<u8> = self
+                .input
+                .drain(..content_start + content_length)
+                .skip(content_start)
+                .collect();
+            let message: Message = match serde_json::from_slice(&message) {
+                Ok(message) => message,
+                Err(e) => {
+                    println!("Dropping invalid debug adapter message: {}", e);
+                    continue;
+                }
+            };
+
+            // Only requests need handling, since no requests are ever sent to the editor
+            if message.message_type == "request" {
+                return Some(message);
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<(), String> {
+        message["seq"] = json!(self.next_seq);
+        self.next_seq += 1;
+
+        let content = message.to_string();
+        let message = format!(
+            "{}{}\r\n\r\n{}",
+            CONTENT_LENGTH_HEADER,
+            content.len(),
+            content
+        );
+        self.stream
+            .write_all(message.as_bytes())
+            .map_err(|e| e.to_string())?;
+        self.stream.flush().map_err(|e| e.to_string())
+    }
+
+    fn send_response(
+        &mut self,
+        request: &Message,
+        body: Result<Value, String>,
+    ) -> Result<(), String> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request.seq,
+            "command": request.command,
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+
+        self.send(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> Result<(), String> {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }))
+    }
+
+    fn send_stopped_event(&mut self, reason: &StopReason) -> Result<(), String> {
+        let mut body = json!({
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        body["reason"] = json!(match reason {
+            StopReason::Entry => "entry",
+            StopReason::Breakpoint(_) => "breakpoint",
+            StopReason::Step => "step",
+            StopReason::Pause => "pause",
+            StopReason::Error(_) => "exception",
+        });
+
+        if let StopReason::Error(message) = reason {
+            body["text"] = json!(message);
+            self.send_event(
+                "output",
+                json!({
+                    "category": "stderr",
+                    "output": format!("Error: {}\n", message),
+                }),
+            )?;
+        }
+
+        self.send_event("stopped", body)
+    }
+
+    fn handle_request(&mut self, cpu: &mut CPU, request: &Message) -> Result<Value, String> {
+        let arguments = &request.arguments;
+
+        match request.command.as_str() {
+            "setBreakpoints" => Ok(self.set_source_breakpoints(arguments)),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => {
+                self.configured = true;
+                match self.stop_on_entry {
+                    true => self.pending_stop = Some(StopReason::Entry),
+                    false => self.control.resume(cpu.get_program_counter()),
+                }
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({
+                "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }],
+            })),
+            "stackTrace" => self.get_stack_trace(cpu),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Timers", "variablesReference": TIMERS_REFERENCE, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+                ],
+            })),
+            "variables" => get_variables(cpu, get_u64_argument(arguments, "variablesReference")?),
+            "continue" => {
+                self.control.resume(cpu.get_program_counter());
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" | "stepIn" => {
+                self.control.step();
+                Ok(json!({}))
+            }
+            "pause" => {
+                if !self.control.is_paused() {
+                    self.control.pause();
+                    self.pending_stop = Some(StopReason::Pause);
+                }
+                Ok(json!({}))
+            }
+            "readMemory" => read_memory(cpu, arguments),
+            "writeMemory" => write_memory(cpu, arguments),
+            "disconnect" | "terminate" => {
+                self.quit = true;
+                Ok(json!({}))
+            }
+            command => Err(format!("Unsupported request: {}", command)),
+        }
+    }
+
+    fn set_source_breakpoints(&mut self, arguments: &Value) -> Value {
+        let path = arguments["source"]["path"].as_str().unwrap_or("");
+        let lines: Vec<u64> = arguments["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut addresses = vec![];
+        let mut breakpoints = vec![];
+        for line in lines {
+            match self.symbols.find_address(path, line as u32) {
+                Some((address, actual_line)) => {
+                    addresses.push(address);
+                    breakpoints.push(json!({
+                        "verified": true,
+                        "line": actual_line,
+                        "instructionReference": format_address(address),
+                    }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No instructions were assembled from this line",
+                })),
+            }
+        }
+
+        self.source_breakpoints.insert(path.to_string(), addresses);
+        self.update_breakpoints();
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let mut addresses = vec![];
+        for breakpoint in arguments["breakpoints"].as_array().unwrap_or(&vec![]) {
+            addresses.push(get_memory_address(
+                breakpoint,
+                "instructionReference",
+                "offset",
+            )?);
+        }
+
+        self.instruction_breakpoints = addresses.clone();
+        self.update_breakpoints();
+
+        let breakpoints: Vec<Value> = addresses
+            .iter()
+            .map(|address| {
+                json!({
+                    "verified": true,
+                    "instructionReference": format_address(*address),
+                })
+            })
+            .collect();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn update_breakpoints(&mut self) {
+        self.control.clear_breakpoints();
+
+        let addresses = self
+            .source_breakpoints
+            .values()
+            .flatten()
+            .chain(self.instruction_breakpoints.iter());
+        for address in addresses {
+            self.control.add_breakpoint(*address);
+        }
+    }
+
+    fn get_stack_trace(&self, cpu: &CPU) -> Result<Value, String> {
+        // The current instruction, followed by the calls that led to it
+        let call_stack = cpu.get_call_stack()?;
+        let addresses = std::iter::once(cpu.get_program_counter())
+            .chain(call_stack.iter().map(|frame| frame.call_address));
+
+        let frames: Vec<Value> = addresses
+            .enumerate()
+            .map(|(id, address)| self.get_stack_frame(id, address))
+            .collect();
+
+        Ok(json!({
+            "totalFrames": frames.len(),
+            "stackFrames": frames,
+        }))
+    }
+
+    fn get_stack_frame(&self, id: usize, address: Address) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": self.symbols.format_address(address),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format_address(address),
+        });
+
+        if let Some(location) = self.symbols.get_source_location(address) {
+            let path = Path::new(&self.source_directory).join(&location.file);
+            frame["source"] = json!({
+                "name": location.file,
+                "path": path.to_string_lossy(),
+            });
+            frame["line"] = json!(location.line);
+            frame["column"] = json!(1);
+        }
+
+        frame
+    }
+}
+
+impl<S: Read + Write> Debugger for DapServer<S> {
+    fn before_step(&mut self, cpu: &mut CPU) -> Result<StepDecision, String> {
+        if let Err(message) = self.receive() {
+            println!("{}", message);
+            return Ok(StepDecision::Quit);
+        }
+
+        while let Some(request) = self.next_request() {
+            let body = self.handle_request(cpu, &request);
+            self.send_response(&request, body)?;
+
+            if let Some(reason) = self.pending_stop.take() {
+                self.send_stopped_event(&reason)?;
+            }
+        }
+
+        if self.quit {
+            return Ok(StepDecision::Quit);
+        }
+        if !self.configured {
+            return Ok(StepDecision::Pause);
+        }
+
+        if let Some(reason) = self.control.check_before_step(cpu.get_program_counter()) {
+            self.send_stopped_event(&reason)?;
+        }
+
+        match self.control.is_paused() {
+            true => Ok(StepDecision::Pause),
+            false => Ok(StepDecision::Step),
+        }
+    }
+
+    fn after_step(
+        &mut self,
+        _cpu: &mut CPU,
+        result: Result<ScreenChanged, String>,
+    ) -> Result<ScreenChanged, String> {
+        match result {
+            Ok(screen_changed) => {
+                if let Some(reason) = self.control.check_after_step() {
+                    self.send_stopped_event(&reason)?;
+                }
+                Ok(screen_changed)
+            }
+            Err(message) => {
+                self.control.pause();
+                self.send_stopped_event(&StopReason::Error(message))?;
+                Ok(ScreenChanged::NoChange)
+            }
+        }
+    }
+}
+
+fn get_capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsReadMemoryRequest": true,
+        "supportsWriteMemoryRequest": true,
+        "supportsTerminateRequest": true,
+    })
+}
+
+fn get_variables(cpu: &CPU, reference: u64) -> Result<Value, String> {
+    let variables: Vec<Value> = match reference {
+        REGISTERS_REFERENCE | TIMERS_REFERENCE => DebugRegister::all()
+            .into_iter()
+            .filter(|register| {
+                let is_timer = matches!(
+                    register,
+                    DebugRegister::DelayTimer | DebugRegister::SoundTimer
+                );
+                is_timer == (reference == TIMERS_REFERENCE)
+            })
+            .map(|register| {
+                let value = cpu.get_debug_register(register);
+                let mut variable = json!({
+                    "name": register.get_name(),
+                    "value": format!("0x{:0width$x} ({})", value, value, width = register.get_size() * 2),
+                    "variablesReference": 0,
+                });
+
+                // Registers that hold addresses can be opened in the memory view
+                if let DebugRegister::Index | DebugRegister::ProgramCounter = register {
+                    variable["memoryReference"] = json!(format_address(value));
+                }
+                variable
+            })
+            .collect(),
+        STACK_REFERENCE => cpu
+            .get_call_stack()?
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                json!({
+                    "name": format!("#{}", i),
+                    "value": format!("returns to {}", format_address(frame.return_address)),
+                    "variablesReference": 0,
+                    "memoryReference": format_address(frame.return_address),
+                })
+            })
+            .collect(),
+        _ => return Err(format!("Unknown variables reference: {}", reference)),
+    };
+
+    Ok(json!({ "variables": variables }))
+}
+
+fn read_memory(cpu: &CPU, arguments: &Value) -> Result<Value, String> {
+    let address = get_memory_address(arguments, "memoryReference", "offset")?;
+    let count = get_u64_argument(arguments, "count")? as usize;
+
+    // Anything past the end of memory is reported as unreadable
+    let readable = count.min(MEMORY_SIZE.saturating_sub(address as usize));
+    let bytes = cpu.read_memory(address, readable)?;
+
+    Ok(json!({
+        "address": format_address(address),
+        "data": encode_base64(&bytes),
+        "unreadableBytes": count - readable,
+    }))
+}
+
+fn write_memory(cpu: &mut CPU, arguments: &Value) -> Result<Value, String> {
+    let address = get_memory_address(arguments, "memoryReference", "offset")?;
+    let data = arguments["data"]
+        .as_str()
+        .ok_or("Missing argument: data".to_string())?;
+    let bytes = decode_base64(data)?;
+
+    cpu.write_memory(address, &bytes)?;
+
+    Ok(json!({ "bytesWritten": bytes.len() }))
+}
+
+/// Gets an address from a memory or instruction reference argument, along with its offset.
+fn get_memory_address(
+    arguments: &Value,
+    reference_name: &str,
+    offset_name: &str,
+) -> Result<Address, String> {
+    let reference = arguments[reference_name]
+        .as_str()
+        .ok_or(format!("Missing argument: {}", reference_name))?;
+    let address = i64::from_str_radix(reference.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("Invalid {}: {}", reference_name, reference))?;
+    let offset = arguments[offset_name].as_i64().unwrap_or(0);
+
+    let address = address + offset;
+    if address < 0 || address as usize >= MEMORY_SIZE {
+        return Err(format!("Address is outside of memory: 0x{:x}", address));
+    }
+
+    Ok(address as Address)
+}
+
+fn get_u64_argument(arguments: &Value, name: &str) -> Result<u64, String> {
+    arguments[name]
+        .as_u64()
+        .ok_or(format!("Missing argument: {}", name))
+}
+
+fn format_address(address: Address) -> String {
+    format!("0x{:04x}", address)
+}
+
+fn find(bytes: &[u8], pattern: &[u8]) -> Option<usize> {
+    bytes
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | (*byte as u32) << (16 - 8 * i)
+        });
+
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => {
+                    let index = (group >> (18 - 6 * i)) & 0x3F;
+                    encoded.push(BASE64_ALPHABET[index as usize] as char);
+                }
+                false => encoded.push('='),
+            }
+        }
+    }
+
+    encoded
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut group = 0u32;
+    let mut num_bits = 0;
+
+    for c in text.chars().filter(|c| *c != '=') {
+        let value = BASE64_ALPHABET
+            .iter()
+            .position(|a| *a as char == c)
+            .ok_or(format!("Invalid base64 data: {}", text))?;
+
+        group = (group << 6) | value as u32;
+        num_bits += 6;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            bytes.push((group >> num_bits) as u8);
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn parse_content_length(header: &str) -> Result<usize, String> {
+    header
+        .lines()
+        .find_map(|line| line.strip_prefix(CONTENT_LENGTH_HEADER))
+        .ok_or(format!("Message is missing a content length: {}", header))?
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid message header: {}", header))
+}
+
+#[cfg(test)]
+fn create_test_messages(messages: &[Value]) -> Vec<u8> {
+    messages
+        .iter()
+        .enumerate()
+        .map(|(i, message)| {
+            let mut message = message.clone();
+            message["seq"] = json!(i + 1);
+            message["type"] = json!("request");
+
+            let content = message.to_string();
+            format!(
+                "{}{}\r\n\r\n{}",
+                CONTENT_LENGTH_HEADER,
+                content.len(),
+                content
+            )
+        })
+        .collect::<String>()
+        .into_bytes()
+}
+
+#[cfg(test)]
+fn read_test_messages(output: &[u8]) -> Vec<Value> {
+    let mut server = DapServer::new(MockStream::new(&[]));
+    server.input = output.to_vec();
+
+    let mut messages = vec![];
+    while let Some(header_length) = find(&server.input, HEADER_END) {
+        let header = String::from_utf8_lossy(&server.input[..header_length]).to_string();
+        let content_length: usize = header[CONTENT_LENGTH_HEADER.len()..].parse().unwrap();
+        let content_start = header_length + HEADER_END.len();
+
+        let content: Vec<u8> = server
+            .input
+            .drain(..content_start + content_length)
+            .skip(content_start)
+            .collect();
+        messages.push(serde_json::from_slice(&content).unwrap());
+    }
+
+    messages
+}
+
+#[test]
+fn dap_server_launch() {
+    let input = create_test_messages(&[
+        json!({ "command": "initialize", "arguments": { "adapterID": "chip8" } }),
+        json!({ "command": "launch", "arguments": { "program": "game.ch8", "stopOnEntry": true } }),
+    ]);
+    let mut server = DapServer::new(MockStream::new(&input));
+
+    let program = server.launch(|arguments, _| Ok(arguments.program.clone()));
+    assert_eq!(Ok("game.ch8".to_string()), program);
+    assert!(server.stop_on_entry);
+
+    let messages = read_test_messages(&server.stream.output);
+    assert_eq!(3, messages.len());
+    assert_eq!(json!("initialize"), messages[0]["command"]);
+    assert_eq!(
+        json!(true),
+        messages[0]["body"]["supportsConfigurationDoneRequest"]
+    );
+    assert_eq!(json!("launch"), messages[1]["command"]);
+    assert_eq!(json!(true), messages[1]["success"]);
+    assert_eq!(json!("initialized"), messages[2]["event"]);
+}
+
+#[test]
+fn dap_server_drops_malformed_messages() {
+    let mut input = b"Content-Length: many\r\n\r\n".to_vec();
+    input.extend_from_slice(b"Content-Length: 9\r\n\r\n{\"seq\": }");
+    input.extend(create_test_messages(&[
+        json!({ "command": "configurationDone" }),
+    ]));
+    let mut server = DapServer::new(MockStream::new(&input));
+
+    let mut cpu = CPU::default();
+    assert_eq!(Ok(()), cpu.load_rom(&[0x12, 0x00]));
+    cpu.initialize_program_counter();
+
+    assert_eq!(Ok(StepDecision::Step), server.before_step(&mut cpu));
+
+    let messages = read_test_messages(&server.stream.output);
+    assert_eq!(1, messages.len());
+    assert_eq!(json!("configurationDone"), messages[0]["command"]);
+    assert_eq!(json!(true), messages[0]["success"]);
+}
+
+#[test]
+fn dap_server_breakpoints() {
+    let input = create_test_messages(&[
+        json!({
+            "command": "setBreakpoints",
+            "arguments": {
+                "source": { "path": "/home/user/game.8o" },
+                "breakpoints": [{ "line": 3 }, { "line": 9 }],
+            },
+        }),
+        json!({ "command": "configurationDone" }),
+    ]);
+    let mut server = DapServer::new(MockStream::new(&input));
+    server.symbols = SymbolMap::parse("line 0x0200 game.8o:2\nline 0x0202 game.8o:4").unwrap();
+
+    let mut cpu = CPU::default();
+    assert_eq!(Ok(()), cpu.load_rom(&[0x60, 0x01, 0x12, 0x00]));
+    cpu.initialize_program_counter();
+
+    assert_eq!(Ok(StepDecision::Step), server.before_step(&mut cpu));
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        server.after_step(&mut cpu, Ok(ScreenChanged::NoChange))
+    );
+    assert_eq!(
+        Ok(()),
+        cpu.set_debug_register(DebugRegister::ProgramCounter, 0x0202)
+    );
+    assert_eq!(Ok(StepDecision::Pause), server.before_step(&mut cpu));
+
+    let messages = read_test_messages(&server.stream.output);
+    assert_eq!(
+        json!([
+            { "verified": true, "line": 4, "instructionReference": "0x0202" },
+            { "verified": false, "line": 9, "message": "No instructions were assembled from this line" },
+        ]),
+        messages[0]["body"]["breakpoints"]
+    );
+    assert_eq!(json!("stopped"), messages[2]["event"]);
+    assert_eq!(json!("breakpoint"), messages[2]["body"]["reason"]);
+}
+
+#[test]
+fn dap_server_variables_and_memory() {
+    let mut server = DapServer::new(MockStream::new(&[]));
+    let mut cpu = CPU::default();
+    assert_eq!(Ok(()), cpu.load_rom(&[0x12, 0x34]));
+    cpu.initialize_program_counter();
+
+    let request = |command: &str, arguments: Value| Message {
+        seq: 1,
+        message_type: "request".to_string(),
+        command: command.to_string(),
+        arguments,
+    };
+
+    let variables = server
+        .handle_request(
+            &mut cpu,
+            &request(
+                "variables",
+                json!({ "variablesReference": TIMERS_REFERENCE }),
+            ),
+        )
+        .unwrap();
+    assert_eq!(
+        json!(["dt", "st"]),
+        json!(variables["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|variable| variable["name"].clone())
+            .collect::<Vec<Value>>())
+    );
+
+    let memory = server.handle_request(
+        &mut cpu,
+        &request(
+            "readMemory",
+            json!({ "memoryReference": "0x0200", "count": 2 }),
+        ),
+    );
+    assert_eq!(
+        Ok(json!({ "address": "0x0200", "data": "EjQ=", "unreadableBytes": 0 })),
+        memory
+    );
+
+    let written = server.handle_request(
+        &mut cpu,
+        &request(
+            "writeMemory",
+            json!({ "memoryReference": "0x0300", "offset": 1, "data": "vu8=" }),
+        ),
+    );
+    assert_eq!(Ok(json!({ "bytesWritten": 2 })), written);
+    assert_eq!(Ok(vec![0xBE, 0xEF]), cpu.read_memory(0x0301, 2));
+}
+
+#[test]
+fn dap_base64() {
+    assert_eq!("", encode_base64(&[]));
+    assert_eq!("TWFu", encode_base64(b"Man"));
+    assert_eq!("TWE=", encode_base64(b"Ma"));
+    assert_eq!("TQ==", encode_base64(b"M"));
+
+    assert_eq!(Ok(b"Man".to_vec()), decode_base64("TWFu"));
+    assert_eq!(Ok(b"Ma".to_vec()), decode_base64("TWE="));
+    assert!(decode_base64("T!==").is_err());
+}
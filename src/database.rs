@@ -0,0 +1,257 @@
+//! Lookup of per-ROM configuration (platform, quirks, tick rate, key bindings, and colors) by ROM
+//! hash.
+//!
+//! Databases use the file format of the community CHIP-8 database
+//! (https://github.com/chip-8/chip-8-database), which is made up of three files:
+//!
+//! * `platforms.json` - the platforms ROMs can target, with their default quirks and tick rate
+//! * `programs.json` - the known programs and the ROMs that make up each of them
+//! * `sha1-hashes.json` - a map from ROM SHA-1 hash to index into `programs.json`
+//!
+//! Only the platforms are bundled into the interpreter, so that ROMs not in any database still get
+//! their platform's quirks. Looking up ROMs needs a checkout of the full community database, loaded
+//! from its `database` directory.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::quirks::{QuirkOverrides, Quirks};
+
+const BUNDLED_PLATFORMS: &str = include_str!("../database/platforms.json");
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Platform {
+    pub id: String,
+    pub name: String,
+    pub default_tickrate: Option<u32>,
+    pub quirks: Quirks,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Program {
+    title: String,
+    description: Option<String>,
+    release: Option<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+    tickrate: Option<u32>,
+    start_address: Option<u16>,
+    font_style: Option<String>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+    colors: Option<Colors>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+/// The configuration to use for running a specific ROM.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RomConfig {
+    pub title: String,
+    pub description: Option<String>,
+    pub release: Option<String>,
+    pub authors: Vec<String>,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    /// Number of instructions to run per 60 Hz frame.
+    pub tickrate: Option<u32>,
+    /// Address the ROM needs to be loaded at, if not the platform default.
+    pub start_address: Option<u16>,
+    /// Name of the style of font the ROM expects (ex. "vip").
+    pub font_style: Option<String>,
+    /// Keypad keys to bind to named roles (ex. "up", "a").
+    pub keys: HashMap<String, u8>,
+    /// Colors of the display, as "#RRGGBB" strings. The first is the background color and the
+    /// second is the foreground color.
+    pub colors: Vec<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Database {
+    platforms: Vec<Platform>,
+    programs: Vec<Program>,
+    sha1_hashes: HashMap<String, usize>,
+}
+
+/// Returns the bundled platform with the given ID.
+pub fn get_bundled_platform(id: &str) -> Result<Platform, String> {
+    let platforms: Vec<Platform> = parse_json(BUNDLED_PLATFORMS, "platforms")?;
+    platforms
+        .into_iter()
+        .find(|p| p.id == id)
+        .ok_or(format!("Unrecognized platform: {}", id))
+}
+
+impl Database {
+    /// Loads a database from a directory containing `platforms.json`, `programs.json`, and
+    /// `sha1-hashes.json`.
+    pub fn load_dir(directory: &Path) -> Result<Database, String> {
+        let read = |filename: &str| -> Result<String, String> {
+            let filepath = directory.join(filename);
+            fs::read_to_string(&filepath)
+                .map_err(|e| format!("Failed to read {}: {}", filepath.display(), e))
+        };
+
+        Database::parse(
+            &read("platforms.json")?,
+            &read("programs.json")?,
+            &read("sha1-hashes.json")?,
+        )
+    }
+
+    pub fn parse(platforms: &str, programs: &str, sha1_hashes: &str) -> Result<Database, String> {
+        Ok(Database {
+            platforms: parse_json(platforms, "platforms")?,
+            programs: parse_json(programs, "programs")?,
+            sha1_hashes: parse_json(sha1_hashes, "sha1-hashes")?,
+        })
+    }
+
+    pub fn get_platform(&self, id: &str) -> Option<&Platform> {
+        self.platforms.iter().find(|p| p.id == id)
+    }
+
+    /// Returns the configuration for the ROM with the given SHA-1 hash, if it is in the database.
+    pub fn lookup(&self, rom_sha1: &str) -> Result<Option<RomConfig>, String> {
+        let rom_sha1 = rom_sha1.to_lowercase();
+
+        let program = match self.sha1_hashes.get(&rom_sha1) {
+            Some(index) => self.programs.get(*index).ok_or(format!(
+                "Database hash {} refers to a missing program: {}",
+                rom_sha1, index
+            ))?,
+            None => return Ok(None),
+        };
+
+        let rom = program.roms.get(&rom_sha1).ok_or(format!(
+            "Database program \"{}\" has no ROM with hash {}",
+            program.title, rom_sha1
+        ))?;
+
+        let platform_id = rom.platforms.first().ok_or(format!(
+            "Database ROM {} does not list any platforms",
+            rom_sha1
+        ))?;
+        let platform = self.get_platform(platform_id).ok_or(format!(
+            "Unrecognized platform in database: {}",
+            platform_id
+        ))?;
+
+        let quirks = match rom.quirky_platforms.get(platform_id) {
+            Some(overrides) => platform.quirks.with_overrides(overrides),
+            None => platform.quirks,
+        };
+
+        Ok(Some(RomConfig {
+            title: program.title.clone(),
+            description: program.description.clone(),
+            release: program.release.clone(),
+            authors: program.authors.clone(),
+            platform: platform.clone(),
+            quirks,
+            tickrate: rom.tickrate.or(platform.default_tickrate),
+            start_address: rom.start_address,
+            font_style: rom.font_style.clone(),
+            keys: rom.keys.clone(),
+            colors: rom
+                .colors
+                .as_ref()
+                .map(|c| c.pixels.clone())
+                .unwrap_or_default(),
+        }))
+    }
+}
+
+fn parse_json<T: DeserializeOwned>(text: &str, name: &str) -> Result<T, String> {
+    serde_json::from_str(text).map_err(|e| format!("Failed to parse {} database: {}", name, e))
+}
+
+#[test]
+fn database_bundled_platform() {
+    let platform = get_bundled_platform("originalChip8").unwrap();
+    assert_eq!(Some(15), platform.default_tickrate);
+    assert!(platform.quirks.vblank);
+
+    assert!(get_bundled_platform("missing").is_err());
+}
+
+#[test]
+fn database_lookup() {
+    let platforms = r#"[
+        {
+            "id": "modernChip8",
+            "name": "Modern CHIP-8",
+            "defaultTickrate": 12,
+            "quirks": {
+                "shift": false,
+                "memoryIncrementByX": false,
+                "memoryLeaveIUnchanged": false,
+                "wrap": false,
+                "jump": false,
+                "vblank": false,
+                "logic": false
+            }
+        }
+    ]"#;
+    let programs = r##"[
+        {
+            "title": "Test Game",
+            "authors": ["Someone"],
+            "roms": {
+                "a9993e364706816aba3e25717850c26c9cd0d89d": {
+                    "file": "test.ch8",
+                    "platforms": ["modernChip8"],
+                    "quirkyPlatforms": {
+                        "modernChip8": { "shift": true }
+                    },
+                    "keys": { "up": 5, "a": 6 },
+                    "colors": { "pixels": ["#000000", "#ffffff"] }
+                }
+            }
+        }
+    ]"##;
+    let sha1_hashes = r#"{ "a9993e364706816aba3e25717850c26c9cd0d89d": 0 }"#;
+
+    let database = Database::parse(platforms, programs, sha1_hashes).unwrap();
+
+    assert_eq!(
+        Ok(None),
+        database.lookup("0000000000000000000000000000000000000000")
+    );
+
+    let config = database
+        .lookup("A9993E364706816ABA3E25717850C26C9CD0D89D")
+        .unwrap()
+        .unwrap();
+    assert_eq!("Test Game", config.title);
+    assert_eq!(vec!["Someone".to_string()], config.authors);
+    assert_eq!("modernChip8", config.platform.id);
+    assert!(config.quirks.shift);
+    assert!(!config.quirks.logic);
+    assert_eq!(Some(12), config.tickrate);
+    assert_eq!(Some(&5), config.keys.get("up"));
+    assert_eq!(
+        vec!["#000000".to_string(), "#ffffff".to_string()],
+        config.colors
+    );
+}
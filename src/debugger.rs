@@ -0,0 +1,286 @@
+//! Hooks that let a debugger pause, step, and inspect a running CPU, along with the breakpoint
+//! and stepping logic that debugger front ends share.
+
+use std::collections::BTreeSet;
+#[cfg(test)]
+use std::io::{ErrorKind, Read, Write};
+
+use crate::cpu::{ScreenChanged, CPU};
+use crate::ram::Address;
+
+/// What the run loop should do before running the next instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StepDecision {
+    /// Run the next instruction.
+    Step,
+    /// Stay paused. The run loop should keep the view responsive and ask again shortly.
+    Pause,
+    /// Stop running the program.
+    Quit,
+}
+
+/// Something that controls the CPU from outside of the program, such as a remote debugger.
+pub trait Debugger {
+    /// Called before each step to decide whether the next instruction runs.
+    fn before_step(&mut self, cpu: &mut CPU) -> Result<StepDecision, String>;
+
+    /// Called with the result of each step. The debugger can stop at an error instead of passing
+    /// it on, in which case it returns `Ok`.
+    fn after_step(
+        &mut self,
+        cpu: &mut CPU,
+        result: Result<ScreenChanged, String>,
+    ) -> Result<ScreenChanged, String>;
+}
+
+/// Several debuggers controlling the CPU together, such as a GDB stub alongside the cheat console.
+/// Every debugger is asked before each step, and the CPU only runs if none of them pause or quit.
+pub struct DebuggerGroup<'a> {
+    debuggers: Vec<&'a mut dyn Debugger>,
+}
+
+impl<'a> DebuggerGroup<'a> {
+    pub fn new(debuggers: Vec<&'a mut dyn Debugger>) -> DebuggerGroup<'a> {
+        DebuggerGroup { debuggers }
+    }
+}
+
+impl<'a> Debugger for DebuggerGroup<'a> {
+    fn before_step(&mut self, cpu: &mut CPU) -> Result<StepDecision, String> {
+        let mut decision = StepDecision::Step;
+        for debugger in self.debuggers.iter_mut() {
+            decision = match (decision, debugger.before_step(cpu)?) {
+                (StepDecision::Quit, _) | (_, StepDecision::Quit) => StepDecision::Quit,
+                (StepDecision::Pause, _) | (_, StepDecision::Pause) => StepDecision::Pause,
+                _ => StepDecision::Step,
+            };
+        }
+
+        Ok(decision)
+    }
+
+    fn after_step(
+        &mut self,
+        cpu: &mut CPU,
+        result: Result<ScreenChanged, String>,
+    ) -> Result<ScreenChanged, String> {
+        self.debuggers
+            .iter_mut()
+            .fold(result, |result, debugger| debugger.after_step(cpu, result))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StopReason {
+    /// Stopped before running the first instruction.
+    Entry,
+    Breakpoint(Address),
+    Step,
+    /// Stopped because the debugger asked to pause.
+    Pause,
+    Error(String),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ExecutionState {
+    Running,
+    Paused,
+    /// Run one instruction and then pause.
+    Stepping,
+}
+
+/// Tracks whether the CPU is running or paused, and stops it at breakpoints.
+#[derive(Clone, Debug)]
+pub struct ExecutionControl {
+    breakpoints: BTreeSet<Address>,
+    state: ExecutionState,
+    /// Breakpoint to run past, so that resuming from a breakpoint doesn't stop at it again.
+    resumed_from: Option<Address>,
+}
+
+impl Default for ExecutionControl {
+    /// Starts out paused, so that a debugger can set up breakpoints before the program starts.
+    fn default() -> Self {
+        ExecutionControl {
+            breakpoints: BTreeSet::new(),
+            state: ExecutionState::Paused,
+            resumed_from: None,
+        }
+    }
+}
+
+impl ExecutionControl {
+    pub fn add_breakpoint(&mut self, address: Address) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: Address) {
+        self.breakpoints.remove(&address);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn get_breakpoints(&self) -> impl Iterator<Item = &Address> {
+        self.breakpoints.iter()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state == ExecutionState::Paused
+    }
+
+    pub fn resume(&mut self, program_counter: Address) {
+        self.state = ExecutionState::Running;
+        self.resumed_from = Some(program_counter);
+    }
+
+    pub fn step(&mut self) {
+        self.state = ExecutionState::Stepping;
+    }
+
+    pub fn pause(&mut self) {
+        self.state = ExecutionState::Paused;
+    }
+
+    /// Checks whether the CPU should stop before running the instruction at the given address.
+    pub fn check_before_step(&mut self, program_counter: Address) -> Option<StopReason> {
+        let resumed_from = self.resumed_from.take();
+
+        if self.state == ExecutionState::Running
+            && self.breakpoints.contains(&program_counter)
+            && resumed_from != Some(program_counter)
+        {
+            self.state = ExecutionState::Paused;
+            return Some(StopReason::Breakpoint(program_counter));
+        }
+
+        None
+    }
+
+    /// Checks whether the CPU should stop now that an instruction has run.
+    pub fn check_after_step(&mut self) -> Option<StopReason> {
+        if self.state == ExecutionState::Stepping {
+            self.state = ExecutionState::Paused;
+            return Some(StopReason::Step);
+        }
+
+        None
+    }
+}
+
+/// An in-memory stream for testing debugger front ends, which reports that no more data is
+/// available once its input runs out, like a non-blocking socket.
+#[cfg(test)]
+pub(crate) struct MockStream {
+    input: std::io::Cursor<Vec<u8>>,
+    pub(crate) output: Vec<u8>,
+}
+
+#[cfg(test)]
+impl MockStream {
+    pub(crate) fn new(input: &[u8]) -> MockStream {
+        MockStream {
+            input: std::io::Cursor::new(input.to_vec()),
+            output: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+impl Read for MockStream {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self.input.read(buffer)? {
+            0 => Err(ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Write for MockStream {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buffer)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn execution_control_breakpoints() {
+    let mut control = ExecutionControl::default();
+    control.add_breakpoint(0x0204);
+    assert!(control.is_paused());
+
+    control.resume(0x0200);
+    assert_eq!(None, control.check_before_step(0x0200));
+    assert_eq!(None, control.check_before_step(0x0202));
+    assert_eq!(
+        Some(StopReason::Breakpoint(0x0204)),
+        control.check_before_step(0x0204)
+    );
+    assert!(control.is_paused());
+
+    // Resuming from the breakpoint runs past it
+    control.resume(0x0204);
+    assert_eq!(None, control.check_before_step(0x0204));
+    assert_eq!(None, control.check_after_step());
+
+    control.step();
+    assert_eq!(None, control.check_before_step(0x0206));
+    assert_eq!(Some(StopReason::Step), control.check_after_step());
+    assert!(control.is_paused());
+}
+
+#[test]
+fn debugger_group() {
+    struct FixedDebugger {
+        decision: StepDecision,
+        steps: usize,
+    }
+
+    impl Debugger for FixedDebugger {
+        fn before_step(&mut self, _cpu: &mut CPU) -> Result<StepDecision, String> {
+            Ok(self.decision)
+        }
+
+        fn after_step(
+            &mut self,
+            _cpu: &mut CPU,
+            result: Result<ScreenChanged, String>,
+        ) -> Result<ScreenChanged, String> {
+            self.steps += 1;
+            result
+        }
+    }
+
+    let mut cpu = CPU::default();
+    let mut first = FixedDebugger {
+        decision: StepDecision::Step,
+        steps: 0,
+    };
+    let mut second = FixedDebugger {
+        decision: StepDecision::Pause,
+        steps: 0,
+    };
+
+    let mut group = DebuggerGroup::new(vec![&mut first, &mut second]);
+    assert_eq!(Ok(StepDecision::Pause), group.before_step(&mut cpu));
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        group.after_step(&mut cpu, Ok(ScreenChanged::NoChange))
+    );
+    assert_eq!(1, first.steps);
+    assert_eq!(1, second.steps);
+
+    first.decision = StepDecision::Quit;
+    let mut group = DebuggerGroup::new(vec![&mut first, &mut second]);
+    assert_eq!(Ok(StepDecision::Quit), group.before_step(&mut cpu));
+
+    first.decision = StepDecision::Step;
+    second.decision = StepDecision::Step;
+    let mut group = DebuggerGroup::new(vec![&mut first, &mut second]);
+    assert_eq!(Ok(StepDecision::Step), group.before_step(&mut cpu));
+}
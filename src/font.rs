@@ -0,0 +1,241 @@
+//! Fonts for the hex digit characters that programs can draw with FX29 (small font) and FX30
+//! (large font).
+//!
+//! Glyph data from: https://github.com/JohnEarnest/Octo/blob/gh-pages/js/shared.js
+
+use std::fmt;
+use std::str::FromStr;
+
+pub const NUM_GLYPHS: usize = 16;
+pub const SMALL_GLYPH_SIZE: usize = 5;
+pub const LARGE_GLYPH_SIZE: usize = 10;
+pub const SMALL_FONT_SIZE: usize = NUM_GLYPHS * SMALL_GLYPH_SIZE;
+pub const LARGE_FONT_SIZE: usize = NUM_GLYPHS * LARGE_GLYPH_SIZE;
+
+const VIP_FONT: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x60, 0x20, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+const DREAM_6800_FONT: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x40, 0x40, 0x40, 0x40, 0x40, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+const ETI_660_FONT: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x20, 0x20, 0x20, 0x20, 0x20, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0x80, 0x80, 0xE0, 0xA0, 0xE0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0x20, 0x20, 0xE0, 0xA0, 0xE0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+// Also from: https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#font
+const SUPER_CHIP_FONT: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+// SUPER-CHIP 1.1 only has large glyphs for 0-9, so A-F are filled in with the glyphs that Octo
+// uses for them.
+const SUPER_CHIP_LARGE_FONT: [u8; LARGE_FONT_SIZE] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+/// The fonts of the historical machines that ran CHIP-8 programs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FontStyle {
+    Vip,
+    Dream6800,
+    Eti660,
+    SuperChip,
+}
+
+impl FromStr for FontStyle {
+    type Err = String;
+
+    /// Parses a font style name, as used in the CHIP-8 database.
+    fn from_str(name: &str) -> Result<FontStyle, String> {
+        match name {
+            "vip" => Ok(FontStyle::Vip),
+            "dream6800" => Ok(FontStyle::Dream6800),
+            "eti660" => Ok(FontStyle::Eti660),
+            "schip" => Ok(FontStyle::SuperChip),
+            _ => Err(format!("Unrecognized font style: {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for FontStyle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FontStyle::Vip => "vip",
+            FontStyle::Dream6800 => "dream6800",
+            FontStyle::Eti660 => "eti660",
+            FontStyle::SuperChip => "schip",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Font {
+    small: [u8; SMALL_FONT_SIZE],
+    large: [u8; LARGE_FONT_SIZE],
+}
+
+impl Default for Font {
+    fn default() -> Self {
+        Font::from_style(FontStyle::SuperChip)
+    }
+}
+
+impl Font {
+    /// Returns the font of the given style. Only SUPER-CHIP had a large font, so all of the
+    /// styles use its large font.
+    pub fn from_style(style: FontStyle) -> Font {
+        let small = match style {
+            FontStyle::Vip => VIP_FONT,
+            FontStyle::Dream6800 => DREAM_6800_FONT,
+            FontStyle::Eti660 => ETI_660_FONT,
+            FontStyle::SuperChip => SUPER_CHIP_FONT,
+        };
+
+        Font {
+            small,
+            large: SUPER_CHIP_LARGE_FONT,
+        }
+    }
+
+    /// Reads a font from the contents of a font file.
+    ///
+    /// A font file contains the 80 bytes of the small font, optionally followed by the 160 bytes
+    /// of the large font. If the large font is not given, the SUPER-CHIP large font is used.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Font, String> {
+        let mut font = Font::default();
+
+        match bytes.len() {
+            SMALL_FONT_SIZE => {
+                font.small.copy_from_slice(bytes);
+            }
+            n if n == SMALL_FONT_SIZE + LARGE_FONT_SIZE => {
+                font.small.copy_from_slice(&bytes[..SMALL_FONT_SIZE]);
+                font.large.copy_from_slice(&bytes[SMALL_FONT_SIZE..]);
+            }
+            n => {
+                return Err(format!(
+                    "Font file must be {} or {} bytes, but was {} bytes",
+                    SMALL_FONT_SIZE,
+                    SMALL_FONT_SIZE + LARGE_FONT_SIZE,
+                    n
+                ))
+            }
+        }
+
+        Ok(font)
+    }
+
+    pub fn get_small(&self) -> &[u8] {
+        &self.small
+    }
+
+    pub fn get_large(&self) -> &[u8] {
+        &self.large
+    }
+}
+
+#[test]
+fn font_style_from_str() {
+    assert_eq!(Ok(FontStyle::Dream6800), "dream6800".parse());
+    assert_eq!(
+        Err("Unrecognized font style: octo".to_string()),
+        "octo".parse::<FontStyle>()
+    );
+}
+
+#[test]
+fn font_from_bytes() {
+    let small_only = Font::from_bytes(&VIP_FONT).unwrap();
+    assert_eq!(&VIP_FONT[..], small_only.get_small());
+    assert_eq!(&SUPER_CHIP_LARGE_FONT[..], small_only.get_large());
+
+    let mut bytes = ETI_660_FONT.to_vec();
+    bytes.extend_from_slice(&[0xAA; LARGE_FONT_SIZE]);
+    let with_large = Font::from_bytes(&bytes).unwrap();
+    assert_eq!(&ETI_660_FONT[..], with_large.get_small());
+    assert_eq!(&[0xAA; LARGE_FONT_SIZE][..], with_large.get_large());
+
+    assert_eq!(
+        Err("Font file must be 80 or 240 bytes, but was 3 bytes".to_string()),
+        Font::from_bytes(&[0x00, 0x01, 0x02])
+    );
+}
@@ -0,0 +1,583 @@
+//! A stub for the GDB remote serial protocol, so that GDB can be attached to a running ROM.
+//!
+//! Run the interpreter with `--gdb 1234`, then connect to it from GDB:
+//!
+//! ```text
+//! (gdb) target remote localhost:1234
+//! ```
+//!
+//! The stub describes its registers to GDB with a target description, so any GDB build that
+//! supports target descriptions can be used. The registers are V0-VF, I, PC, SP (the number of
+//! return addresses on the stack), DT, and ST, with I and PC being 16 bits and the rest 8 bits.
+//! Multi-byte registers are sent little endian.
+//!
+//! Software breakpoints, single stepping, continuing, interrupting with Ctrl-C, and memory reads
+//! and writes are supported.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::{DebugRegister, ScreenChanged, CPU};
+#[cfg(test)]
+use crate::debugger::MockStream;
+use crate::debugger::{Debugger, ExecutionControl, StepDecision, StopReason};
+use crate::ram::Address;
+
+const INTERRUPT_BYTE: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+pub struct GdbStub<S: Read + Write> {
+    stream: S,
+    control: ExecutionControl,
+    /// Bytes received that have not been parsed into packets yet.
+    input: Vec<u8>,
+    /// Whether GDB asked to stop acknowledging packets.
+    no_ack: bool,
+    /// Whether GDB has detached, leaving the program running on its own.
+    detached: bool,
+}
+
+impl GdbStub<TcpStream> {
+    /// Waits for GDB to connect on the given local port.
+    pub fn listen(port: u16) -> Result<GdbStub<TcpStream>, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        println!("Waiting for GDB to connect on port {}", port);
+
+        let (stream, address) = listener.accept().map_err(|e| e.to_string())?;
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        println!("GDB connected from {}", address);
+
+        Ok(GdbStub::new(stream))
+    }
+}
+
+impl<S: Read + Write> GdbStub<S> {
+    pub fn new(stream: S) -> GdbStub<S> {
+        GdbStub {
+            stream,
+            control: ExecutionControl::default(),
+            input: vec![],
+            no_ack: false,
+            detached: false,
+        }
+    }
+
+    /// Reads whatever bytes GDB has sent, without waiting for more.
+    fn receive(&mut self) -> Result<(), String> {
+        let mut buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err("GDB disconnected".to_string()),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+
+    /// Takes the next complete packet out of the received bytes, acknowledging it.
+    fn next_incoming(&mut self) -> Result<Option<Incoming>, String> {
+        loop {
+            match self.input.first() {
+                None => return Ok(None),
+                Some(&INTERRUPT_BYTE) => {
+                    self.input.remove(0);
+                    return Ok(Some(Incoming::Interrupt));
+                }
+                Some(b'$') => break,
+                // Acknowledgements of our packets, and anything else between packets
+                Some(_) => {
+                    self.input.remove(0);
+                }
+            }
+        }
+
+        let end = match self.input.iter().position(|b| *b == b'#') {
+            Some(end) if self.input.len() >= end + 3 => end,
+            _ => return Ok(None),
+        };
+
+        let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..]).unwrap_or("");
+
+        let valid = u8::from_str_radix(checksum, 16) == Ok(get_checksum(data));
+        if !self.no_ack {
+            let ack: &[u8] = if valid { b"+" } else { b"-" };
+            self.stream.write_all(ack).map_err(|e| e.to_string())?;
+        }
+        if !valid {
+            return Ok(None);
+        }
+
+        Ok(Some(Incoming::Packet(
+            String::from_utf8_lossy(data).to_string(),
+        )))
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), String> {
+        let packet = format!("${}#{:02x}", data, get_checksum(data.as_bytes()));
+        self.stream
+            .write_all(packet.as_bytes())
+            .map_err(|e| e.to_string())?;
+        self.stream.flush().map_err(|e| e.to_string())
+    }
+
+    fn send_stop_reply(&mut self, reason: &StopReason) -> Result<(), String> {
+        let signal = match reason {
+            StopReason::Entry | StopReason::Breakpoint(_) | StopReason::Step => SIGTRAP,
+            StopReason::Pause => SIGINT,
+            StopReason::Error(message) => {
+                // Show the error in the GDB console before stopping
+                let output = format!("Error: {}\n", message);
+                self.send_packet(&format!("O{}", encode_hex(output.as_bytes())))?;
+                SIGILL
+            }
+        };
+
+        self.send_packet(&format!("S{:02x}", signal))
+    }
+
+    /// Handles a packet, returning the reply to send, if it needs one right away.
+    fn handle_packet(&mut self, cpu: &mut CPU, packet: &str) -> Result<Option<String>, String> {
+        let mut chars = packet.chars();
+        let command = chars.next();
+        let arguments = chars.as_str();
+
+        if command == Some('Q') && arguments == "StartNoAckMode" {
+            self.send_packet("OK")?;
+            self.no_ack = true;
+            return Ok(None);
+        }
+
+        // Malformed packets get an error reply rather than stopping the interpreter
+        match self.handle_command(cpu, command, arguments) {
+            Ok(reply) => Ok(reply),
+            Err(PacketError::Malformed(_)) => Ok(Some("E01".to_string())),
+            Err(PacketError::Failed(message)) => Err(message),
+        }
+    }
+
+    /// Returns the reply to a packet, or `None` if the reply is sent once the CPU stops.
+    fn handle_command(
+        &mut self,
+        cpu: &mut CPU,
+        command: Option<char>,
+        arguments: &str,
+    ) -> Result<Option<String>, PacketError> {
+        let reply = match command {
+            Some('?') => format!("S{:02x}", SIGTRAP),
+            Some('g') => DebugRegister::all()
+                .iter()
+                .map(|register| encode_register(cpu, *register))
+                .collect(),
+            Some('G') => {
+                let mut bytes = decode_hex(arguments)?.into_iter();
+                for register in DebugRegister::all() {
+                    let value_bytes: Vec<u8> = bytes.by_ref().take(register.get_size()).collect();
+                    cpu.set_debug_register(register, decode_little_endian(&value_bytes))
+                        .map_err(PacketError::Failed)?;
+                }
+                "OK".to_string()
+            }
+            Some('p') => match get_register(arguments)? {
+                Some(register) => encode_register(cpu, register),
+                None => "E01".to_string(),
+            },
+            Some('P') => {
+                let (number, value) = split_pair(arguments, '=')?;
+                match get_register(number)? {
+                    Some(register) => {
+                        let value = decode_little_endian(&decode_hex(value)?);
+                        match cpu.set_debug_register(register, value) {
+                            Ok(()) => "OK".to_string(),
+                            Err(_) => "E02".to_string(),
+                        }
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            Some('m') => {
+                let (address, length) = split_pair(arguments, ',')?;
+                match cpu.read_memory(parse_hex(address)?, parse_hex(length)? as usize) {
+                    Ok(bytes) => encode_hex(&bytes),
+                    Err(_) => "E01".to_string(),
+                }
+            }
+            Some('M') => {
+                let (location, data) = split_pair(arguments, ':')?;
+                let (address, _) = split_pair(location, ',')?;
+                match cpu.write_memory(parse_hex(address)?, &decode_hex(data)?) {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => "E01".to_string(),
+                }
+            }
+            Some('Z') | Some('z') => {
+                let parts: Vec<&str> = arguments.split(',').collect();
+                match parts.as_slice() {
+                    // Software and hardware breakpoints are handled the same way
+                    [kind, address, ..] if *kind == "0" || *kind == "1" => {
+                        let address = parse_hex(address)?;
+                        match command {
+                            Some('Z') => self.control.add_breakpoint(address),
+                            _ => self.control.remove_breakpoint(address),
+                        }
+                        "OK".to_string()
+                    }
+                    // Watchpoints are not supported
+                    _ => String::new(),
+                }
+            }
+            Some('c') | Some('s') => {
+                if !arguments.is_empty() {
+                    cpu.set_debug_register(DebugRegister::ProgramCounter, parse_hex(arguments)?)
+                        .map_err(PacketError::Failed)?;
+                }
+                match command {
+                    Some('c') => self.control.resume(cpu.get_program_counter()),
+                    _ => self.control.step(),
+                }
+
+                // The reply is sent once the CPU stops again
+                return Ok(None);
+            }
+            Some('D') => {
+                self.control.clear_breakpoints();
+                self.control.resume(cpu.get_program_counter());
+                self.detached = true;
+                "OK".to_string()
+            }
+            Some('H') => "OK".to_string(),
+            Some('q') => self.handle_query(arguments),
+            // Anything unsupported gets an empty reply
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+
+    fn handle_query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+        if let Some(location) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            return match split_pair(location, ',') {
+                Ok((offset, length)) => {
+                    let description = get_target_description();
+                    let offset = parse_hex(offset).unwrap_or(0) as usize;
+                    let length = parse_hex(length).unwrap_or(0) as usize;
+
+                    let start = offset.min(description.len());
+                    let end = (offset + length).min(description.len());
+                    let prefix = if end < description.len() { "m" } else { "l" };
+                    format!("{}{}", prefix, &description[start..end])
+                }
+                Err(_) => "E00".to_string(),
+            };
+        }
+
+        match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+impl<S: Read + Write> Debugger for GdbStub<S> {
+    fn before_step(&mut self, cpu: &mut CPU) -> Result<StepDecision, String> {
+        if self.detached {
+            return Ok(StepDecision::Step);
+        }
+
+        if let Err(message) = self.receive() {
+            // Keep the program running if GDB goes away
+            println!("{}", message);
+            self.detached = true;
+            return Ok(StepDecision::Step);
+        }
+
+        while let Some(incoming) = self.next_incoming()? {
+            match incoming {
+                Incoming::Interrupt => {
+                    if !self.control.is_paused() {
+                        self.control.pause();
+                        self.send_stop_reply(&StopReason::Pause)?;
+                    }
+                }
+                Incoming::Packet(packet) if packet == "k" => return Ok(StepDecision::Quit),
+                Incoming::Packet(packet) => {
+                    if let Some(reply) = self.handle_packet(cpu, &packet)? {
+                        self.send_packet(&reply)?;
+                    }
+                }
+            }
+        }
+
+        if let Some(reason) = self.control.check_before_step(cpu.get_program_counter()) {
+            self.send_stop_reply(&reason)?;
+        }
+
+        match self.control.is_paused() {
+            true => Ok(StepDecision::Pause),
+            false => Ok(StepDecision::Step),
+        }
+    }
+
+    fn after_step(
+        &mut self,
+        _cpu: &mut CPU,
+        result: Result<ScreenChanged, String>,
+    ) -> Result<ScreenChanged, String> {
+        if self.detached {
+            return result;
+        }
+
+        match result {
+            Ok(screen_changed) => {
+                if let Some(reason) = self.control.check_after_step() {
+                    self.send_stop_reply(&reason)?;
+                }
+                Ok(screen_changed)
+            }
+            Err(message) => {
+                self.control.pause();
+                self.send_stop_reply(&StopReason::Error(message))?;
+                Ok(ScreenChanged::NoChange)
+            }
+        }
+    }
+}
+
+fn get_target_description() -> String {
+    let registers: String = DebugRegister::all()
+        .iter()
+        .map(|register| {
+            format!(
+                "<reg name=\"{}\" bitsize=\"{}\" type=\"uint{}\"/>",
+                register.get_name(),
+                register.get_size() * 8,
+                register.get_size() * 8
+            )
+        })
+        .collect();
+
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.chip8.core\">{}</feature></target>",
+        registers
+    )
+}
+
+fn get_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+fn get_register(number: &str) -> Result<Option<DebugRegister>, String> {
+    let number = parse_hex(number)? as usize;
+    Ok(DebugRegister::all().get(number).copied())
+}
+
+fn encode_register(cpu: &CPU, register: DebugRegister) -> String {
+    let value = cpu.get_debug_register(register);
+    encode_hex(&value.to_le_bytes()[..register.get_size()])
+}
+
+/// Why a packet couldn't be handled.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum PacketError {
+    /// The packet couldn't be parsed, which GDB is told about with an error reply.
+    Malformed(String),
+    /// Handling the packet failed, which stops the interpreter.
+    Failed(String),
+}
+
+/// Errors from parsing a packet's arguments.
+impl From<String> for PacketError {
+    fn from(message: String) -> Self {
+        PacketError::Malformed(message)
+    }
+}
+
+fn decode_little_endian(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| (value << 8) | *byte as u16)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex data: {}", hex));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Invalid hex data: {}", hex))
+        })
+        .collect()
+}
+
+fn parse_hex(value: &str) -> Result<Address, String> {
+    Address::from_str_radix(value, 16).map_err(|_| format!("Invalid hex number: {}", value))
+}
+
+fn split_pair(value: &str, separator: char) -> Result<(&str, &str), String> {
+    let mut parts = value.splitn(2, separator);
+    match (parts.next(), parts.next()) {
+        (Some(first), Some(second)) => Ok((first, second)),
+        _ => Err(format!("Invalid packet arguments: {}", value)),
+    }
+}
+
+#[cfg(test)]
+fn create_test_stub(input: &str) -> GdbStub<MockStream> {
+    GdbStub::new(MockStream::new(input.as_bytes()))
+}
+
+#[test]
+fn gdb_stub_packets() {
+    let mut stub = create_test_stub("+$g#67$m200,2#5d");
+
+    assert_eq!(Ok(()), stub.receive());
+    assert_eq!(
+        Ok(Some(Incoming::Packet("g".to_string()))),
+        stub.next_incoming()
+    );
+    assert_eq!(
+        Ok(Some(Incoming::Packet("m200,2".to_string()))),
+        stub.next_incoming()
+    );
+    assert_eq!(Ok(None), stub.next_incoming());
+    assert_eq!(b"++".to_vec(), stub.stream.output);
+
+    stub.stream.output.clear();
+    assert_eq!(Ok(()), stub.send_packet("OK"));
+    assert_eq!(b"$OK#9a".to_vec(), stub.stream.output);
+}
+
+#[test]
+fn gdb_stub_handle_packet() {
+    use crate::instruction::Register;
+
+    let mut stub = create_test_stub("");
+    let mut cpu = CPU::default();
+    assert_eq!(Ok(()), cpu.load_rom(&[0x12, 0x34]));
+    cpu.initialize_program_counter();
+    assert_eq!(
+        Ok(()),
+        cpu.set_debug_register(DebugRegister::V(Register::V1), 0xAB)
+    );
+
+    let expected_registers = format!("00ab{}00000002000000", "00".repeat(14));
+    assert_eq!(
+        Ok(Some(expected_registers)),
+        stub.handle_packet(&mut cpu, "g")
+    );
+    assert_eq!(
+        Ok(Some("0002".to_string())),
+        stub.handle_packet(&mut cpu, "p11")
+    );
+    assert_eq!(
+        Ok(Some("1234".to_string())),
+        stub.handle_packet(&mut cpu, "m200,2")
+    );
+    assert_eq!(
+        Ok(Some("OK".to_string())),
+        stub.handle_packet(&mut cpu, "M300,2:beef")
+    );
+    assert_eq!(Ok(vec![0xBE, 0xEF]), cpu.read_memory(0x0300, 2));
+    assert_eq!(
+        Ok(Some("OK".to_string())),
+        stub.handle_packet(&mut cpu, "P10=2103")
+    );
+    assert_eq!(0x0321, cpu.get_debug_register(DebugRegister::Index));
+
+    // Malformed packets get an error reply, and unknown ones an empty reply
+    let packets = [
+        ("m20x,2", "E01"),
+        ("M300,2:bee", "E01"),
+        ("Z0,xyz", "E01"),
+        ("p\u{e9}", "E01"),
+        ("M300,2:a\u{e9}b", "E01"),
+        ("", ""),
+        ("\u{e9}", ""),
+    ];
+    for (packet, expected) in packets.iter() {
+        assert_eq!(
+            Ok(Some(expected.to_string())),
+            stub.handle_packet(&mut cpu, packet)
+        );
+    }
+
+    // Packets that parse but fail are passed on rather than hidden behind an error reply
+    let raised_stack_pointer = format!("G00ab{}21030002010000", "00".repeat(14));
+    assert_eq!(
+        Err("Stack pointer can't be raised past the current stack depth (0)".to_string()),
+        stub.handle_packet(&mut cpu, &raised_stack_pointer)
+    );
+}
+
+#[test]
+fn gdb_stub_error_stop() {
+    use crate::views::Inputs;
+    use std::time::Instant;
+
+    let mut stub = create_test_stub("$c#63");
+    let mut cpu = CPU::default();
+    assert_eq!(Ok(()), cpu.load_rom(&[0x00, 0xEE]));
+    cpu.initialize_program_counter();
+
+    assert_eq!(Ok(StepDecision::Step), stub.before_step(&mut cpu));
+    let result = cpu.step(&Instant::now(), &Inputs::default());
+    stub.stream.output.clear();
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        stub.after_step(&mut cpu, result)
+    );
+
+    // The program counter is left on the failing instruction, so continuing runs it again
+    let output = String::from_utf8(stub.stream.output.clone()).unwrap();
+    let message = encode_hex(b"Error: Error at 0x0200: No address on the stack to return to.\n");
+    assert!(output.starts_with(&format!("$O{}#", message)));
+    assert!(output.ends_with("$S04#b7"));
+    assert_eq!(0x0200, cpu.get_program_counter());
+}
+
+#[test]
+fn gdb_stub_breakpoint_and_step() {
+    let mut stub = create_test_stub("$Z0,202,2#a8$c#63");
+    let mut cpu = CPU::default();
+    assert_eq!(Ok(()), cpu.load_rom(&[0x60, 0x01, 0x12, 0x00]));
+    cpu.initialize_program_counter();
+
+    assert_eq!(Ok(StepDecision::Step), stub.before_step(&mut cpu));
+    assert_eq!(
+        Ok(ScreenChanged::NoChange),
+        stub.after_step(&mut cpu, Ok(ScreenChanged::NoChange))
+    );
+    cpu.set_debug_register(DebugRegister::ProgramCounter, 0x0202)
+        .unwrap();
+
+    stub.stream.output.clear();
+    assert_eq!(Ok(StepDecision::Pause), stub.before_step(&mut cpu));
+    assert_eq!(b"$S05#b8".to_vec(), stub.stream.output);
+}
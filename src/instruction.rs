@@ -20,11 +20,12 @@ pub enum Instruction {
     BitwiseXor(Register, Register),             // 0x8XY3
     IncrementByRegister(Register, Register),    // 0x8XY4
     DecrementByRegister(Register, Register),    // 0x8XY5
-    RightShift(Register),                       // 0x8XY6
+    RightShift(Register, Register),             // 0x8XY6
     DecrementByRegisterRev(Register, Register), // 0x8XY7
-    LeftShift(Register),                        // 0x8XYE
+    LeftShift(Register, Register),              // 0x8XYE
     JumpIfRegistersNotEq(Register, Register),   // 0x9XY0
     SetIndexRegister(Address),                  // 0xANNN
+    JumpWithOffset(Address),                    // 0xBNNN
     SetRandomAnd(Register, u8),                 // 0xCXNN
     DrawSprite(Register, Register, u8),         // 0xDXYN
     SkipIfNotPressed(Register),                 // 0xEXA1
@@ -33,6 +34,7 @@ pub enum Instruction {
     SetSoundTimer(Register),                    // 0xFX18
     IncrementIndexByRegister(Register),         // 0xFX1E
     GetFontCharacter(Register),                 // 0xFX29
+    GetLargeFontCharacter(Register),            // 0xFX30
     StoreBinCodedDec(Register),                 // 0xFX33
     DumpRegisters(Register),                    // 0xFX55
     LoadRegisters(Register),                    // 0xFX65
@@ -119,10 +121,11 @@ impl Instruction {
 
                 Ok(DecrementByRegister(first_register, second_register))
             }
-            (0x8, a, _, 0x6) => {
-                let register = Register::from_nibble(a);
+            (0x8, a, b, 0x6) => {
+                let first_register = Register::from_nibble(a);
+                let second_register = Register::from_nibble(b);
 
-                Ok(RightShift(register))
+                Ok(RightShift(first_register, second_register))
             }
             (0x8, a, b, 0x7) => {
                 let first_register = Register::from_nibble(a);
@@ -130,10 +133,11 @@ impl Instruction {
 
                 Ok(DecrementByRegisterRev(first_register, second_register))
             }
-            (0x8, a, _, 0xE) => {
-                let register = Register::from_nibble(a);
+            (0x8, a, b, 0xE) => {
+                let first_register = Register::from_nibble(a);
+                let second_register = Register::from_nibble(b);
 
-                Ok(LeftShift(register))
+                Ok(LeftShift(first_register, second_register))
             }
             (0x9, a, b, 0x0) => {
                 let first_register = Register::from_nibble(a);
@@ -145,6 +149,10 @@ impl Instruction {
                 let address = Instruction::get_address(bytes);
                 Ok(SetIndexRegister(address))
             }
+            (0xB, _, _, _) => {
+                let address = Instruction::get_address(bytes);
+                Ok(JumpWithOffset(address))
+            }
             (0xC, a, _, _) => {
                 let register = Register::from_nibble(a);
                 let value = Instruction::get_value(bytes);
@@ -188,6 +196,11 @@ impl Instruction {
 
                 Ok(GetFontCharacter(register))
             }
+            (0xF, a, 0x3, 0x0) => {
+                let register = Register::from_nibble(a);
+
+                Ok(GetLargeFontCharacter(register))
+            }
             (0xF, a, 0x3, 0x3) => {
                 let register = Register::from_nibble(a);
 
@@ -237,7 +250,7 @@ pub enum Register {
 }
 
 impl Register {
-    fn from_nibble(nibble: u8) -> Register {
+    pub fn from_nibble(nibble: u8) -> Register {
         use Register::*;
 
         match nibble {
@@ -266,7 +279,7 @@ impl Register {
         }
     }
 
-    fn to_nibble(self) -> u8 {
+    pub fn to_nibble(self) -> u8 {
         use Register::*;
 
         match self {
@@ -353,17 +366,24 @@ fn instruction_from_u16() {
         Ok(DecrementByRegister(Register::Vf, Register::Ve)),
         Instruction::from_u16(0x8FE5)
     );
-    assert_eq!(Ok(RightShift(Register::V1)), Instruction::from_u16(0x8106));
+    assert_eq!(
+        Ok(RightShift(Register::V1, Register::V2)),
+        Instruction::from_u16(0x8126)
+    );
     assert_eq!(
         Ok(DecrementByRegisterRev(Register::V1, Register::V2)),
         Instruction::from_u16(0x8127)
     );
-    assert_eq!(Ok(LeftShift(Register::V1)), Instruction::from_u16(0x810E));
+    assert_eq!(
+        Ok(LeftShift(Register::V1, Register::V2)),
+        Instruction::from_u16(0x812E)
+    );
     assert_eq!(
         Ok(JumpIfRegistersNotEq(Register::V1, Register::V2)),
         Instruction::from_u16(0x9120)
     );
     assert_eq!(Ok(SetIndexRegister(0x22A)), Instruction::from_u16(0xA22A));
+    assert_eq!(Ok(JumpWithOffset(0x22A)), Instruction::from_u16(0xB22A));
     assert_eq!(
         Ok(SetRandomAnd(Register::V1, 0x23)),
         Instruction::from_u16(0xC123)
@@ -396,6 +416,10 @@ fn instruction_from_u16() {
         Ok(GetFontCharacter(Register::V1)),
         Instruction::from_u16(0xF129)
     );
+    assert_eq!(
+        Ok(GetLargeFontCharacter(Register::V1)),
+        Instruction::from_u16(0xF130)
+    );
     assert_eq!(
         Ok(StoreBinCodedDec(Register::V1)),
         Instruction::from_u16(0xF133)
@@ -1,6 +1,29 @@
+pub mod access_map;
+pub mod alu;
 pub mod bit_operations;
+pub mod cartridge;
+pub mod cheats;
+pub mod clock;
+pub mod config;
+pub mod control_flow;
+pub mod coverage;
 pub mod cpu;
+pub mod dap;
+pub mod database;
+pub mod debugger;
+pub mod font;
+pub mod gdb;
 pub mod instruction;
+pub mod machine;
+pub mod movie;
+pub mod octo;
+pub mod patch;
+pub mod profiler;
+pub mod quirks;
 pub mod ram;
+pub mod rom;
 pub mod screen;
+pub mod symbols;
+pub mod timing;
 pub mod views;
+pub mod zip;
@@ -0,0 +1,192 @@
+//! A CPU along with everything needed to start it over, so that a program can be reset or
+//! reloaded without restarting the interpreter.
+
+use crate::config::MachineConfig;
+use crate::cpu::CPU;
+use crate::font::Font;
+use crate::symbols::SymbolMap;
+
+/// Builds a `Machine`, loading the font and ROM into memory in the right order.
+///
+/// ```rust
+/// # use chip8_interpreter::machine::MachineBuilder;
+/// let rom = vec![0x00, 0xE0];
+/// let machine = MachineBuilder::new(rom).rng_seed(42).build().unwrap();
+///
+/// assert_eq!(0x0200, machine.cpu().get_program_counter());
+/// ```
+#[derive(Clone, Debug)]
+pub struct MachineBuilder {
+    rom: Vec<u8>,
+    config: MachineConfig,
+    font: Font,
+    rng_seed: Option<u64>,
+    use_instruction_cache: bool,
+    track_accesses: bool,
+    profile: bool,
+    coverage: bool,
+    symbols: SymbolMap,
+}
+
+impl MachineBuilder {
+    pub fn new(rom: Vec<u8>) -> MachineBuilder {
+        MachineBuilder {
+            rom,
+            config: MachineConfig::default(),
+            font: Font::default(),
+            rng_seed: None,
+            use_instruction_cache: true,
+            track_accesses: false,
+            profile: false,
+            coverage: false,
+            symbols: SymbolMap::default(),
+        }
+    }
+
+    pub fn config(mut self, config: MachineConfig) -> MachineBuilder {
+        self.config = config;
+        self
+    }
+
+    pub fn font(mut self, font: Font) -> MachineBuilder {
+        self.font = font;
+        self
+    }
+
+    /// Seeds the random number generator. If no seed is given, a random one is used.
+    pub fn rng_seed(mut self, rng_seed: u64) -> MachineBuilder {
+        self.rng_seed = Some(rng_seed);
+        self
+    }
+
+    pub fn instruction_cache(mut self, enabled: bool) -> MachineBuilder {
+        self.use_instruction_cache = enabled;
+        self
+    }
+
+    /// Sets whether the CPU records how the program accesses memory. See `CPU::get_access_map`.
+    pub fn access_tracking(mut self, enabled: bool) -> MachineBuilder {
+        self.track_accesses = enabled;
+        self
+    }
+
+    /// Sets whether the CPU profiles the program. See `CPU::get_profiler`.
+    pub fn profiling(mut self, enabled: bool) -> MachineBuilder {
+        self.profile = enabled;
+        self
+    }
+
+    /// Sets whether the CPU records code coverage. See `CPU::get_coverage`.
+    pub fn coverage(mut self, enabled: bool) -> MachineBuilder {
+        self.coverage = enabled;
+        self
+    }
+
+    /// Sets the symbols of the ROM, which are used to show addresses in error messages.
+    pub fn symbols(mut self, symbols: SymbolMap) -> MachineBuilder {
+        self.symbols = symbols;
+        self
+    }
+
+    pub fn build(self) -> Result<Machine, String> {
+        let rng_seed = self.rng_seed.unwrap_or_else(rand::random);
+        let cpu = self.create_cpu(&self.rom, rng_seed)?;
+
+        Ok(Machine {
+            cpu,
+            builder: self,
+            rng_seed,
+        })
+    }
+
+    fn create_cpu(&self, rom: &[u8], rng_seed: u64) -> Result<CPU, String> {
+        let mut cpu = CPU::new(self.config, rng_seed);
+        cpu.set_instruction_cache_enabled(self.use_instruction_cache);
+        cpu.set_access_tracking_enabled(self.track_accesses);
+        cpu.set_profiling_enabled(self.profile);
+        cpu.set_coverage_enabled(self.coverage);
+        cpu.set_symbols(self.symbols.clone());
+
+        cpu.load_font(&self.font)?;
+        cpu.load_rom(rom)?;
+        cpu.initialize_program_counter();
+
+        Ok(cpu)
+    }
+}
+
+#[derive(Debug)]
+pub struct Machine {
+    cpu: CPU,
+    /// The settings the machine was built with, which are used again to reset it.
+    builder: MachineBuilder,
+    rng_seed: u64,
+}
+
+impl Machine {
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn get_rom(&self) -> &[u8] {
+        &self.builder.rom
+    }
+
+    /// Starts the program over from the beginning, as if the machine had been turned off and on
+    /// again. The random number generator is reseeded with the same seed, so the program plays
+    /// out the same way given the same inputs.
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.cpu = self.builder.create_cpu(&self.builder.rom, self.rng_seed)?;
+
+        Ok(())
+    }
+
+    /// Replaces the ROM with the given one and resets. If the new ROM can't be loaded, the machine
+    /// is left running the old one.
+    pub fn reload(&mut self, rom: Vec<u8>) -> Result<(), String> {
+        self.cpu = self.builder.create_cpu(&rom, self.rng_seed)?;
+        self.builder.rom = rom;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn machine_reset() {
+    use crate::cpu::ScreenChanged;
+    use crate::views::Inputs;
+    use std::time::Instant;
+
+    let rom = vec![
+        0x60, 0x05, // 0x200: V0 = 5
+        0x12, 0x00, // 0x202: Jump to 0x200
+    ];
+    let mut machine = MachineBuilder::new(rom).rng_seed(42).build().unwrap();
+    let fresh_cpu = MachineBuilder::new(machine.get_rom().to_vec())
+        .rng_seed(42)
+        .build()
+        .unwrap();
+
+    let result = machine.cpu_mut().step(&Instant::now(), &Inputs::default());
+    assert_eq!(Ok(ScreenChanged::NoChange), result);
+    assert_eq!(0x0202, machine.cpu().get_program_counter());
+
+    assert_eq!(Ok(()), machine.reset());
+    assert_eq!(fresh_cpu.cpu(), machine.cpu());
+}
+
+#[test]
+fn machine_reload() {
+    let mut machine = MachineBuilder::new(vec![0x00, 0xE0]).build().unwrap();
+
+    let too_large = vec![0x00; 4096];
+    assert!(machine.reload(too_large).is_err());
+    assert_eq!(&[0x00, 0xE0], machine.get_rom());
+
+    assert_eq!(Ok(()), machine.reload(vec![0x00, 0xEE]));
+    assert_eq!(&[0x00, 0xEE], machine.get_rom());
+}
@@ -1,46 +1,976 @@
 extern crate clap;
+extern crate rand;
 
 use std::fs::File;
 use std::io;
-use std::io::{BufReader, Read};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
 use std::{thread, time};
 
-use clap::{App, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use minifb::{Key, Window, WindowOptions};
 
-use chip8_interpreter::views::View;
-use chip8_interpreter::{cpu, screen, views};
+use chip8_interpreter::cartridge::{OctoCartridge, OctoOptions};
+use chip8_interpreter::cheats::{CheatConsole, CheatList};
+use chip8_interpreter::clock::VirtualClock;
+use chip8_interpreter::config::{MachineConfig, Timing};
+use chip8_interpreter::control_flow::ControlFlowGraph;
+use chip8_interpreter::dap::DapServer;
+use chip8_interpreter::database::{self, Database};
+use chip8_interpreter::debugger::{Debugger, DebuggerGroup, StepDecision};
+use chip8_interpreter::font::Font;
+use chip8_interpreter::gdb::GdbStub;
+use chip8_interpreter::machine::{Machine, MachineBuilder};
+use chip8_interpreter::quirks::Quirks;
+use chip8_interpreter::ram::Address;
+use chip8_interpreter::symbols::SymbolMap;
+use chip8_interpreter::views::{HotkeyAction, Inputs, Keymap, Palette, View};
+use chip8_interpreter::{cartridge, cpu, movie, octo, patch, rom, timing, views, zip};
 
 const MAX_INSTRUCTIONS_PER_SECOND: u64 = 700;
-const ONE_SECOND_IN_MICROSECONDS: u64 = 1000000;
+const TIMER_TICKS_PER_SECOND: u64 = 60;
+const DEFAULT_PLATFORM_ID: &str = "modernChip8";
+const PAUSED_POLL_MILLISECONDS: u64 = 16;
+/// Extensions of the files that are looked for when loading a ROM from a zip file.
+const ZIPPED_ROM_EXTENSIONS: &[&str] = &["ch8", "c8", "sc8", "xo8", "gif"];
 
 fn main() {
     let matches = App::new("chip8_interpreter")
         .version("0.1.0")
         .author("Christopher Wells")
         .about("")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("ROM").required(true).index(1))
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("MOVIE")
+                .takes_value(true)
+                .help("Records the inputs of the session to the given movie file"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .conflicts_with("record")
+                .help("Reloads the ROM whenever its file changes"),
+        )
+        .arg(
+            Arg::with_name("gdb")
+                .long("gdb")
+                .value_name("PORT")
+                .takes_value(true)
+                .conflicts_with("record")
+                .help("Waits for GDB to connect on the given local port before running the ROM"),
+        )
+        .arg(
+            Arg::with_name("cheats")
+                .long("cheats")
+                .value_name("DIR")
+                .takes_value(true)
+                .conflicts_with("record")
+                .help("Reads cheat console commands from standard input, loading and saving the cheats for the ROM in the given directory"),
+        )
+        .arg(database_arg())
+        .arg(symbols_arg())
+        .arg(patch_arg())
+        .arg(octo_options_arg())
+        .args(&analysis_args())
+        .args(&memory_layout_args())
+        .args(&font_args())
+        .args(&quirk_args())
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Replays a movie file recorded with --record, using the settings it was recorded with")
+                .arg(Arg::with_name("MOVIE").required(true).index(1))
+                .arg(Arg::with_name("ROM").required(true).index(2))
+                .arg(symbols_arg())
+                .args(&analysis_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Reports information about a ROM without running it")
+                .arg(Arg::with_name("ROM").required(true).index(1))
+                .arg(database_arg())
+                .args(&memory_layout_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("cfg")
+                .about("Prints the control flow graph of a ROM in the Graphviz DOT format")
+                .arg(Arg::with_name("ROM").required(true).index(1))
+                .arg(database_arg())
+                .arg(symbols_arg())
+                .args(&memory_layout_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about(
+                    "Runs a ROM headless as fast as possible and reports instructions per second",
+                )
+                .arg(Arg::with_name("ROM").required(true).index(1))
+                .arg(
+                    Arg::with_name("cycles")
+                        .long("cycles")
+                        .value_name("N")
+                        .takes_value(true)
+                        .default_value("10_000_000")
+                        .help("Number of instructions to run"),
+                )
+                .arg(
+                    Arg::with_name("no-instruction-cache")
+                        .long("no-instruction-cache")
+                        .help("Decodes every instruction as it is run instead of caching them"),
+                )
+                .arg(database_arg())
+                .args(&memory_layout_args())
+                .args(&font_args())
+                .args(&quirk_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Creates an IPS patch that turns one ROM into another")
+                .arg(Arg::with_name("ORIGINAL").required(true).index(1))
+                .arg(Arg::with_name("MODIFIED").required(true).index(2))
+                .arg(Arg::with_name("PATCH").required(true).index(3)),
+        )
+        .subcommand(
+            SubCommand::with_name("dap")
+                .about("Runs a Debug Adapter Protocol server that editors can debug ROMs through")
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .takes_value(true)
+                        .default_value("4711")
+                        .help("Local port to wait for the editor to connect on"),
+                )
+                .arg(database_arg())
+                .args(&memory_layout_args())
+                .args(&font_args())
+                .args(&quirk_args()),
+        )
         .get_matches();
 
-    run(&matches).unwrap();
+    let result = match matches.subcommand() {
+        ("replay", Some(replay_matches)) => replay(replay_matches),
+        ("info", Some(info_matches)) => info(info_matches),
+        ("cfg", Some(cfg_matches)) => cfg(cfg_matches),
+        ("bench", Some(bench_matches)) => bench(bench_matches),
+        ("diff", Some(diff_matches)) => diff(diff_matches),
+        ("dap", Some(dap_matches)) => dap(dap_matches),
+        _ => run(&matches),
+    };
+
+    result.unwrap();
+}
+
+fn database_arg() -> Arg<'static, 'static> {
+    Arg::with_name("database")
+        .long("database")
+        .value_name("DIR")
+        .takes_value(true)
+        .help(
+            "Looks up ROM configuration in the CHIP-8 database in the given directory, instead of \
+             using the default configuration",
+        )
+}
+
+fn symbols_arg() -> Arg<'static, 'static> {
+    Arg::with_name("symbols")
+        .long("symbols")
+        .value_name("FILE")
+        .takes_value(true)
+        .help("Loads labels and source lines for the ROM from the given symbol file")
+}
+
+fn patch_arg() -> Arg<'static, 'static> {
+    Arg::with_name("patch")
+        .long("patch")
+        .value_name("FILE")
+        .takes_value(true)
+        .help("Applies the given IPS or BPS patch to the ROM before loading it")
+}
+
+fn octo_options_arg() -> Arg<'static, 'static> {
+    Arg::with_name("octo-options")
+        .long("octo-options")
+        .value_name("CARTRIDGE")
+        .takes_value(true)
+        .help("Uses the tick rate, quirks, colors, and font saved in the given Octo cartridge GIF, in place of those of the cartridge being run")
+}
+
+fn analysis_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("access-map")
+            .long("access-map")
+            .help("Reports self-modifying code, code drawn as sprites, and how each address was accessed on exit"),
+        Arg::with_name("profile")
+            .long("profile")
+            .help("Reports the addresses, instructions, and subroutines that took the most cycles on exit"),
+        Arg::with_name("profile-folded")
+            .long("profile-folded")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes the cycles spent in each chain of subroutine calls to the given file on exit, in the folded stack format used by flame graph tools"),
+        Arg::with_name("coverage")
+            .long("coverage")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes a listing of the ROM annotated with how many times each instruction ran and which way each skip went to the given file on exit"),
+        Arg::with_name("lcov")
+            .long("lcov")
+            .value_name("FILE")
+            .takes_value(true)
+            .requires("symbols")
+            .help("Writes the coverage of the source lines in the symbol file to the given file on exit, as an lcov tracefile"),
+    ]
+}
+
+fn memory_layout_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("load-address")
+            .long("load-address")
+            .value_name("ADDRESS")
+            .takes_value(true)
+            .help("Address to load the ROM at (ex. 0x600 for ETI-660 programs)"),
+        Arg::with_name("font-address")
+            .long("font-address")
+            .value_name("ADDRESS")
+            .takes_value(true)
+            .help("Address to load the font at, followed by the large font"),
+        Arg::with_name("initial-pc")
+            .long("initial-pc")
+            .value_name("ADDRESS")
+            .takes_value(true)
+            .help("Address to start running at. Defaults to the load address"),
+        Arg::with_name("stack-depth")
+            .long("stack-depth")
+            .value_name("N")
+            .takes_value(true)
+            .help("Maximum number of nested subroutine calls before a stack overflow"),
+        Arg::with_name("vip-stack").long("vip-stack").help(
+            "Stores the stack in RAM at 0xEA0 like the COSMAC VIP, which limits it to 12 entries",
+        ),
+    ]
+}
+
+fn font_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("font-style")
+            .long("font-style")
+            .value_name("STYLE")
+            .takes_value(true)
+            .possible_values(&["vip", "dream6800", "eti660", "schip"])
+            .help("Uses the font of the given machine"),
+        Arg::with_name("font")
+            .long("font")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with("font-style")
+            .help("Loads the font from the given file (80 bytes, optionally followed by 160 bytes of large font)"),
+    ]
+}
+
+fn quirk_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("sprite-edge")
+            .long("sprite-edge")
+            .value_name("EDGE")
+            .takes_value(true)
+            .possible_values(&["clip", "wrap"])
+            .help("Whether sprites drawn past the edge of the screen are clipped or wrap around"),
+        Arg::with_name("index-overflow")
+            .long("index-overflow")
+            .help("Sets VF when FX1E moves I past the end of memory, like the Amiga interpreter"),
+        Arg::with_name("timing")
+            .long("timing")
+            .value_name("TIMING")
+            .takes_value(true)
+            .possible_values(&["fixed", "vip"])
+            .help("Runs every instruction at the same speed, or as fast as each one ran on the COSMAC VIP"),
+    ]
+}
+
+/// Parses an address given either in hex with a "0x" prefix or in decimal.
+fn parse_address(value: &str) -> Result<Address, String> {
+    let result = match value.strip_prefix("0x") {
+        Some(hex) => Address::from_str_radix(hex, 16),
+        None => value.parse::<Address>(),
+    };
+
+    result.map_err(|e| format!("Invalid address \"{}\": {}", value, e))
+}
+
+/// Applies any memory layout settings given on the command line to the machine config.
+fn apply_memory_layout_args(args: &ArgMatches, machine: &mut MachineConfig) -> Result<(), String> {
+    if let Some(load_address) = args.value_of("load-address") {
+        machine.load_address = parse_address(load_address)?;
+        machine.initial_program_counter = machine.load_address;
+    }
+    if let Some(font_address) = args.value_of("font-address") {
+        *machine = machine.with_font_address(parse_address(font_address)?);
+    }
+    if let Some(initial_pc) = args.value_of("initial-pc") {
+        machine.initial_program_counter = parse_address(initial_pc)?;
+    }
+    if args.is_present("vip-stack") {
+        *machine = machine.with_vip_stack();
+    }
+    if let Some(stack_depth) = args.value_of("stack-depth") {
+        machine.stack_depth = stack_depth
+            .parse()
+            .map_err(|e| format!("Invalid stack depth \"{}\": {}", stack_depth, e))?;
+    }
+
+    machine.validate()
+}
+
+fn apply_quirk_args(args: &ArgMatches, quirks: &mut Quirks) {
+    if let Some(sprite_edge) = args.value_of("sprite-edge") {
+        quirks.wrap = sprite_edge == "wrap";
+    }
+    if args.is_present("index-overflow") {
+        quirks.index_overflow = true;
+    }
+}
+
+/// Settings for running a specific ROM, taken from the ROM database if the ROM is in it.
+struct RunConfig {
+    platform_id: String,
+    machine: MachineConfig,
+    font: Font,
+    instructions_per_second: u64,
+    keymap: Keymap,
+    palette: Palette,
+    symbols: SymbolMap,
+    track_accesses: bool,
+    profile: bool,
+    coverage: bool,
+    patch: Option<Vec<u8>>,
+}
+
+impl RunConfig {
+    /// Returns the default settings for running ROMs on the given platform.
+    fn for_platform(platform_id: &str) -> Result<Self, String> {
+        let platform = database::get_bundled_platform(platform_id)?;
+        Ok(RunConfig {
+            platform_id: platform.id,
+            machine: MachineConfig {
+                quirks: platform.quirks,
+                ..MachineConfig::default()
+            },
+            font: Font::default(),
+            instructions_per_second: MAX_INSTRUCTIONS_PER_SECOND,
+            keymap: Keymap::default(),
+            palette: Palette::default(),
+            symbols: SymbolMap::default(),
+            track_accesses: false,
+            profile: false,
+            coverage: false,
+            patch: None,
+        })
+    }
+
+    /// Returns the default settings, with the settings that a movie was recorded with.
+    fn from_movie(movie: &movie::Movie) -> Result<Self, String> {
+        let settings = movie.get_settings();
+        Ok(RunConfig {
+            machine: settings.machine,
+            font: settings.font.clone(),
+            instructions_per_second: settings.instructions_per_second,
+            patch: settings.patch.clone(),
+            ..RunConfig::for_platform(DEFAULT_PLATFORM_ID)?
+        })
+    }
+
+    /// Returns the settings that a movie of a run with this configuration needs to be replayed.
+    fn get_movie_settings(&self) -> movie::MovieSettings {
+        movie::MovieSettings {
+            machine: self.machine,
+            font: self.font.clone(),
+            instructions_per_second: self.instructions_per_second,
+            patch: self.patch.clone(),
+        }
+    }
+
+    fn get_max_rom_size(&self) -> usize {
+        rom::max_rom_size(&self.platform_id, self.machine.load_address)
+    }
+
+    fn apply_patch(&self, rom: Vec<u8>) -> Result<Vec<u8>, String> {
+        match &self.patch {
+            Some(patch) => {
+                patch::apply(patch, &rom).map_err(|e| format!("Failed to apply patch: {}", e))
+            }
+            None => Ok(rom),
+        }
+    }
+}
+
+fn load_database(args: &ArgMatches) -> Result<Option<Database>, String> {
+    match args.value_of("database") {
+        Some(directory) => Ok(Some(Database::load_dir(Path::new(directory))?)),
+        None => Ok(None),
+    }
+}
+
+fn lookup_run_config(
+    args: &ArgMatches,
+    rom: &[u8],
+    octo_options: Option<&OctoOptions>,
+) -> Result<RunConfig, String> {
+    let mut run_config = lookup_database_run_config(args, rom)?;
+    if let Some(options) = octo_options {
+        apply_octo_options(options, &mut run_config)?;
+    }
+    if let Some(cartridge_filepath) = args.value_of("octo-options") {
+        let options = load_octo_options(cartridge_filepath)?;
+        apply_octo_options(&options, &mut run_config)?;
+    }
+    apply_memory_layout_args(args, &mut run_config.machine)?;
+    apply_quirk_args(args, &mut run_config.machine.quirks);
+    if let Some(timing) = args.value_of("timing") {
+        run_config.machine.timing = match timing {
+            "vip" => Timing::CosmacVip,
+            _ => Timing::Fixed,
+        };
+    }
+
+    if let Some(font_style) = args.value_of("font-style") {
+        run_config.font = Font::from_style(font_style.parse()?);
+    }
+    if let Some(font_filepath) = args.value_of("font") {
+        let font_bytes = un_io_result(load_file_bytes(font_filepath))?;
+        run_config.font = Font::from_bytes(&font_bytes)?;
+    }
+
+    apply_analysis_args(args, &mut run_config)?;
+    if let Some(patch_filepath) = args.value_of("patch") {
+        run_config.patch = Some(un_io_result(load_file_bytes(patch_filepath))?);
+    }
+
+    Ok(run_config)
+}
+
+/// Applies the symbol file and the analysis settings given on the command line.
+fn apply_analysis_args(args: &ArgMatches, run_config: &mut RunConfig) -> Result<(), String> {
+    if let Some(symbols_filepath) = args.value_of("symbols") {
+        run_config.symbols = load_symbols(symbols_filepath)?;
+    }
+    run_config.track_accesses = args.is_present("access-map");
+    run_config.profile = args.is_present("profile") || args.is_present("profile-folded");
+    run_config.coverage = args.is_present("coverage") || args.is_present("lcov");
+
+    Ok(())
+}
+
+/// Uses the options saved in an Octo cartridge, on top of any configuration from the database.
+fn apply_octo_options(options: &OctoOptions, run_config: &mut RunConfig) -> Result<(), String> {
+    run_config.machine.quirks = run_config
+        .machine
+        .quirks
+        .with_overrides(&options.get_quirk_overrides());
+
+    if let Some(tickrate) = options.tickrate {
+        if tickrate == 0 {
+            return Err("Octo cartridge has a tick rate of 0".to_string());
+        }
+        run_config.instructions_per_second = tickrate as u64 * TIMER_TICKS_PER_SECOND;
+    }
+
+    if let Some(fill_color) = options.fill_color.as_ref() {
+        run_config.palette.on = Palette::parse_color(fill_color)?;
+    }
+    if let Some(background_color) = options.background_color.as_ref() {
+        run_config.palette.off = Palette::parse_color(background_color)?;
+    }
+
+    // Octo has some fonts of its own, which the default font is used in place of
+    if let Some(font_style) = options.font_style.as_ref() {
+        match font_style.parse() {
+            Ok(font_style) => run_config.font = Font::from_style(font_style),
+            Err(message) => println!("Warning: {}", message),
+        }
+    }
+
+    Ok(())
+}
+
+fn load_symbols(filepath: &str) -> Result<SymbolMap, String> {
+    let bytes = un_io_result(load_file_bytes(filepath))?;
+    SymbolMap::parse(&String::from_utf8_lossy(&bytes))
+}
+
+fn lookup_database_run_config(args: &ArgMatches, rom: &[u8]) -> Result<RunConfig, String> {
+    let database = match load_database(args)? {
+        Some(database) => database,
+        None => return RunConfig::for_platform(DEFAULT_PLATFORM_ID),
+    };
+
+    let rom_config = match database.lookup(&rom::sha1_hex(rom))? {
+        Some(rom_config) => rom_config,
+        None => {
+            println!("ROM not found in database, using default configuration");
+            return RunConfig::for_platform(DEFAULT_PLATFORM_ID);
+        }
+    };
+
+    println!("Title: {}", rom_config.title);
+    if !rom_config.authors.is_empty() {
+        println!("Authors: {}", rom_config.authors.join(", "));
+    }
+    if let Some(release) = rom_config.release.as_ref() {
+        println!("Released: {}", release);
+    }
+    if let Some(description) = rom_config.description.as_ref() {
+        println!("{}", description);
+    }
+    println!("Platform: {}", rom_config.platform.name);
+
+    let mut run_config = RunConfig::for_platform(&rom_config.platform.id)?;
+    run_config.machine.quirks = rom_config.quirks;
+
+    // The database has some font styles that aren't supported, which the default font is used in
+    // place of
+    if let Some(font_style) = rom_config.font_style.as_ref() {
+        match font_style.parse() {
+            Ok(font_style) => run_config.font = Font::from_style(font_style),
+            Err(message) => println!("Warning: {}", message),
+        }
+    }
+
+    if let Some(start_address) = rom_config.start_address {
+        run_config.machine.load_address = start_address;
+        run_config.machine.initial_program_counter = start_address;
+    }
+
+    if let Some(tickrate) = rom_config.tickrate {
+        if tickrate == 0 {
+            return Err("ROM database entry has a tick rate of 0".to_string());
+        }
+        run_config.instructions_per_second = tickrate as u64 * TIMER_TICKS_PER_SECOND;
+    }
+
+    for (name, key_id) in rom_config.keys.iter() {
+        if let Err(message) = run_config.keymap.bind_named(name, *key_id) {
+            println!("Warning: {}", message);
+        }
+    }
+
+    if let [off, on, ..] = rom_config.colors.as_slice() {
+        run_config.palette = Palette {
+            on: Palette::parse_color(on)?,
+            off: Palette::parse_color(off)?,
+        };
+    }
+
+    Ok(run_config)
 }
 
 fn run(args: &ArgMatches) -> Result<(), String> {
-    let mut cpu = cpu::CPU::default();
-    println!("Created CPU representation");
+    let rom_filepath = args
+        .value_of("ROM")
+        .ok_or("User did not provide ROM argument")?;
+    let (rom, run_config) = load_checked_rom(args, rom_filepath)?;
+
+    let rng_seed: u64 = rand::random();
+    let mut movie = args.value_of("record").map(|_| {
+        movie::Movie::new(
+            rom::sha1_hex(&rom),
+            rng_seed,
+            run_config.get_movie_settings(),
+        )
+    });
+
+    let mut machine = create_machine(rom, rng_seed, &run_config)?;
+    println!("Loaded ROM: {}", rom_filepath);
+
+    let mut watcher = match args.is_present("watch") {
+        true => Some(FileWatcher::new(Path::new(rom_filepath))),
+        false => None,
+    };
+
+    let mut gdb_stub = match args.value_of("gdb") {
+        Some(port) => {
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid GDB port: {}", port))?;
+            Some(GdbStub::listen(port)?)
+        }
+        None => None,
+    };
+
+    let mut cheat_console = match args.value_of("cheats") {
+        Some(directory) => {
+            let directory = Path::new(directory);
+            let rom = machine.get_rom();
+            let cheats = CheatList::load_for_rom(directory, rom)?;
+            println!("Loaded {} cheats", cheats.get_cheats().len());
+            println!("Type help for a list of cheat console commands");
+
+            Some(CheatConsole::new(
+                read_stdin_lines(),
+                io::stdout(),
+                cheats,
+                CheatList::get_path(directory, rom),
+            ))
+        }
+        None => None,
+    };
 
-    cpu.load_default_font()?;
-    println!("Loaded default font");
+    let mut view = create_view(&run_config);
+    println!("Created view");
+
+    println!("Starting execution");
+    println!("Press F1 to reset or F5 to reload the ROM");
+    let mut debuggers: Vec<&mut dyn Debugger> = vec![];
+    if let Some(stub) = gdb_stub.as_mut() {
+        debuggers.push(stub);
+    }
+    if let Some(console) = cheat_console.as_mut() {
+        debuggers.push(console);
+    }
+    let has_debuggers = !debuggers.is_empty();
+    let mut debugger_group = DebuggerGroup::new(debuggers);
+
+    let result = run_loop(
+        &mut machine,
+        &mut view,
+        &run_config,
+        match has_debuggers {
+            true => Some(&mut debugger_group),
+            false => None,
+        },
+        |cycle, view, machine| {
+            let mut action = view.get_hotkey_action();
+            if watcher.as_mut().is_some_and(|w| w.has_changed()) {
+                action = Some(HotkeyAction::Reload);
+            }
+
+            if let Some(action) = action {
+                // Resetting part way through would make the movie impossible to replay
+                if movie.is_some() {
+                    println!("Reset and reload are disabled while recording");
+                } else {
+                    match action {
+                        HotkeyAction::Reset => machine.reset()?,
+                        HotkeyAction::Reload => reload_rom(machine, rom_filepath, &run_config),
+                    }
+                    view.update(&machine.cpu().screen);
+                }
+            }
+
+            let inputs = view.get_inputs()?;
+            if let Some(movie) = movie.as_mut() {
+                movie.record(cycle, &inputs)?;
+            }
+
+            Ok(Some(inputs))
+        },
+    );
+
+    // The movie is written even if the run failed, so that the failure can be replayed
+    if let (Some(movie), Some(movie_filepath)) = (movie, args.value_of("record")) {
+        let mut file = un_io_result(File::create(movie_filepath))?;
+        movie.write(&mut file)?;
+        println!("Wrote movie: {}", movie_filepath);
+    }
+    write_analysis_reports(args, &machine, &run_config)?;
+
+    result
+}
+
+fn replay(args: &ArgMatches) -> Result<(), String> {
+    let movie_filepath = args
+        .value_of("MOVIE")
+        .ok_or("User did not provide MOVIE argument")?;
+    let movie_file = un_io_result(File::open(movie_filepath))?;
+    let movie = movie::Movie::read(BufReader::new(movie_file))?;
+    println!("Loaded movie: {}", movie_filepath);
 
     let rom_filepath = args
         .value_of("ROM")
         .ok_or("User did not provide ROM argument")?;
-    load_rom(&mut cpu, rom_filepath)?;
+    let mut run_config = RunConfig::from_movie(&movie)?;
+    apply_analysis_args(args, &mut run_config)?;
+    let (rom, _) = load_rom_file(rom_filepath)?;
+    let rom = run_config.apply_patch(rom)?;
+
+    let rom_sha1 = rom::sha1_hex(&rom);
+    if rom_sha1 != movie.get_rom_sha1() {
+        return Err(format!(
+            "ROM does not match the one the movie was recorded with. Expected SHA-1 {}, but got {}",
+            movie.get_rom_sha1(),
+            rom_sha1
+        ));
+    }
+
+    let mut machine = create_machine(rom, movie.get_rng_seed(), &run_config)?;
     println!("Loaded ROM: {}", rom_filepath);
 
-    cpu.initialize_program_counter();
-    println!("Initialized program counter");
+    let mut view = create_view(&run_config);
+    println!("Created view");
+
+    println!("Starting replay");
+    let result = run_loop(&mut machine, &mut view, &run_config, None, |cycle, _, _| {
+        if cycle >= movie.get_num_cycles() {
+            return Ok(None);
+        }
+
+        movie.get_inputs(cycle).map(Some)
+    });
+    write_analysis_reports(args, &machine, &run_config)?;
+    result?;
+    println!("Finished replay");
 
+    Ok(())
+}
+
+fn info(args: &ArgMatches) -> Result<(), String> {
+    let rom_filepath = args
+        .value_of("ROM")
+        .ok_or("User did not provide ROM argument")?;
+    let (rom, octo_options) = load_rom_file(rom_filepath)?;
+    let run_config = lookup_run_config(args, &rom, octo_options.as_ref())?;
+    let info = rom::RomInfo::analyze(&rom, run_config.machine.load_address);
+
+    println!("File: {}", rom_filepath);
+    println!("Size: {} bytes", info.size);
+    println!("SHA-1: {}", info.sha1);
+
+    println!("Load address: 0x{:04x}", run_config.machine.load_address);
+    println!("Detected platform: {}", info.detected_platform);
+    for (address, bytes, platform) in info.platform_instructions.iter() {
+        println!("  0x{:04x}: 0x{:04x} ({})", address, bytes, platform);
+    }
+
+    println!("Undecodable words: {}", info.undecodable_words.len());
+    for (address, _, message) in info.undecodable_words.iter() {
+        println!("  0x{:04x}: {}", address, message);
+    }
+
+    let graph = ControlFlowGraph::analyze(
+        &rom,
+        run_config.machine.load_address,
+        run_config.machine.initial_program_counter,
+    );
+    println!("Subroutines: {}", graph.get_subroutines().count());
+    println!("Data ranges (not reachable as code):");
+    for (address, length) in graph.get_data_ranges().iter() {
+        println!("  0x{:04x}: {} bytes", address, length);
+    }
+    for (address, message) in graph.get_warnings().iter() {
+        println!("Control flow warning at 0x{:04x}: {}", address, message);
+    }
+
+    match rom::validate(&rom, run_config.get_max_rom_size()) {
+        Ok(warnings) => {
+            for warning in warnings.iter() {
+                println!("Warning: {}", warning);
+            }
+            println!("Valid: yes");
+        }
+        Err(message) => println!("Valid: no ({})", message),
+    }
+
+    Ok(())
+}
+
+fn cfg(args: &ArgMatches) -> Result<(), String> {
+    let rom_filepath = args
+        .value_of("ROM")
+        .ok_or("User did not provide ROM argument")?;
+    let (rom, octo_options) = load_rom_file(rom_filepath)?;
+    let run_config = lookup_run_config(args, &rom, octo_options.as_ref())?;
+
+    let graph = ControlFlowGraph::analyze(
+        &rom,
+        run_config.machine.load_address,
+        run_config.machine.initial_program_counter,
+    );
+    print!("{}", graph.to_dot(&run_config.symbols));
+
+    Ok(())
+}
+
+fn bench(args: &ArgMatches) -> Result<(), String> {
+    let rom_filepath = args
+        .value_of("ROM")
+        .ok_or("User did not provide ROM argument")?;
+    let num_cycles = args.value_of("cycles").unwrap_or_default();
+    let num_cycles: u64 = num_cycles
+        .replace('_', "")
+        .parse()
+        .map_err(|e| format!("Invalid number of cycles \"{}\": {}", num_cycles, e))?;
+    let (rom, run_config) = load_checked_rom(args, rom_filepath)?;
+
+    let mut machine = MachineBuilder::new(rom)
+        .config(run_config.machine)
+        .font(run_config.font.clone())
+        .rng_seed(0)
+        .instruction_cache(!args.is_present("no-instruction-cache"))
+        .build()?;
+    let cpu = machine.cpu_mut();
+
+    // Timers still tick based on the ROM's usual speed, so that the ROM behaves the same as it
+    // would when run normally
+    let clock = VirtualClock::new(time::Instant::now(), run_config.instructions_per_second);
+    let inputs = Inputs::default();
+
+    let start = time::Instant::now();
+    for cycle in 0..num_cycles {
+        if let Err(message) = cpu.step(&clock.time_at(cycle), &inputs) {
+            print_call_stack(cpu);
+            return Err(format!("Error on cycle {}: {}", cycle, message));
+        }
+    }
+    let elapsed = start.elapsed();
+
+    println!("Cycles: {}", num_cycles);
+    println!("Elapsed: {:.3}s", elapsed.as_secs_f64());
+    println!(
+        "Instructions per second: {:.0}",
+        num_cycles as f64 / elapsed.as_secs_f64()
+    );
+
+    Ok(())
+}
+
+fn diff(args: &ArgMatches) -> Result<(), String> {
+    let original_filepath = args
+        .value_of("ORIGINAL")
+        .ok_or("User did not provide ORIGINAL argument")?;
+    let modified_filepath = args
+        .value_of("MODIFIED")
+        .ok_or("User did not provide MODIFIED argument")?;
+    let patch_filepath = args
+        .value_of("PATCH")
+        .ok_or("User did not provide PATCH argument")?;
+
+    let original = un_io_result(load_file_bytes(original_filepath))?;
+    let modified = un_io_result(load_file_bytes(modified_filepath))?;
+    let patch = patch::create_ips(&original, &modified)?;
+    un_io_result(std::fs::write(patch_filepath, &patch))?;
+    println!("Wrote patch: {} ({} bytes)", patch_filepath, patch.len());
+
+    Ok(())
+}
+
+fn dap(args: &ArgMatches) -> Result<(), String> {
+    let port = args.value_of("port").ok_or("User did not provide port")?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("Invalid port: {}", port))?;
+
+    let mut server = DapServer::listen(port)?;
+    let (mut machine, run_config) = server.launch(|launch, symbols| {
+        let (rom, mut run_config) = load_checked_rom(args, &launch.program)?;
+        run_config.symbols = symbols.clone();
+        let machine = create_machine(rom, rand::random(), &run_config)?;
+        println!("Loaded ROM: {}", launch.program);
+
+        Ok((machine, run_config))
+    })?;
+
+    let mut view = create_view(&run_config);
+    println!("Created view");
+
+    let result = run_loop(
+        &mut machine,
+        &mut view,
+        &run_config,
+        Some(&mut server),
+        |_, view, _| view.get_inputs().map(Some),
+    );
+    server.terminate();
+
+    result
+}
+
+/// Loads a ROM and its configuration, checking that the ROM can be run.
+fn load_checked_rom(args: &ArgMatches, rom_filepath: &str) -> Result<(Vec<u8>, RunConfig), String> {
+    let (rom, octo_options) = load_rom_file(rom_filepath)?;
+    // Patched ROMs aren't in the database, so their configuration is looked up by the original
+    let run_config = lookup_run_config(args, &rom, octo_options.as_ref())?;
+    let rom = run_config.apply_patch(rom)?;
+
+    let warnings = rom::validate(&rom, run_config.get_max_rom_size())
+        .map_err(|message| format!("Invalid ROM {}: {}", rom_filepath, message))?;
+    for warning in warnings.iter() {
+        println!("Warning: {}", warning);
+    }
+
+    Ok((rom, run_config))
+}
+
+fn create_machine(rom: Vec<u8>, rng_seed: u64, run_config: &RunConfig) -> Result<Machine, String> {
+    let machine = MachineBuilder::new(rom)
+        .config(run_config.machine)
+        .font(run_config.font.clone())
+        .rng_seed(rng_seed)
+        .symbols(run_config.symbols.clone())
+        .access_tracking(run_config.track_accesses)
+        .profiling(run_config.profile)
+        .coverage(run_config.coverage)
+        .build()?;
+    println!("Created machine");
+
+    Ok(machine)
+}
+
+/// Reloads the ROM from disk, leaving the current one running if the new one can't be loaded.
+fn reload_rom(machine: &mut Machine, rom_filepath: &str, run_config: &RunConfig) {
+    let result = load_rom_file(rom_filepath).and_then(|(rom, _)| {
+        let rom = run_config.apply_patch(rom)?;
+        rom::validate(&rom, run_config.get_max_rom_size())?;
+        machine.reload(rom)
+    });
+
+    match result {
+        Ok(()) => println!("Reloaded ROM: {}", rom_filepath),
+        Err(message) => println!("Failed to reload ROM {}: {}", rom_filepath, message),
+    }
+}
+
+/// Watches a file for changes by checking its modification time every so often.
+struct FileWatcher {
+    path: PathBuf,
+    last_modified: Option<time::SystemTime>,
+    last_check: time::Instant,
+}
+
+impl FileWatcher {
+    const CHECK_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+    fn new(path: &Path) -> FileWatcher {
+        FileWatcher {
+            path: path.to_path_buf(),
+            last_modified: FileWatcher::get_modified(path),
+            last_check: time::Instant::now(),
+        }
+    }
+
+    fn has_changed(&mut self) -> bool {
+        if self.last_check.elapsed() < FileWatcher::CHECK_INTERVAL {
+            return false;
+        }
+        self.last_check = time::Instant::now();
+
+        let modified = FileWatcher::get_modified(&self.path);
+        if modified.is_none() || modified == self.last_modified {
+            return false;
+        }
+
+        self.last_modified = modified;
+        true
+    }
+
+    fn get_modified(path: &Path) -> Option<time::SystemTime> {
+        path.metadata().and_then(|m| m.modified()).ok()
+    }
+}
+
+fn create_view(run_config: &RunConfig) -> views::MiniFbView {
     let stdout = io::stdout();
     //let mut view = views::CliView::new(stdout.lock());
     let mut view = views::MiniFbView::new(
@@ -49,15 +979,66 @@ fn run(args: &ArgMatches) -> Result<(), String> {
         32 * 2,
         WindowOptions::default(),
     );
-    println!("Created view");
+    view.set_keymap(run_config.keymap.clone());
+    view.set_palette(run_config.palette);
 
-    println!("Starting execution");
+    view
+}
+
+/// Runs the CPU until the view is closed or `next_inputs` runs out of inputs to give.
+///
+/// If a debugger is given, it is asked before each step whether to run, pause, or quit. Timers
+/// are driven by a virtual clock based on the number of cycles run, so that a session with the
+/// same inputs always plays out the same way.
+fn run_loop<F>(
+    machine: &mut Machine,
+    view: &mut views::MiniFbView,
+    run_config: &RunConfig,
+    mut debugger: Option<&mut dyn Debugger>,
+    mut next_inputs: F,
+) -> Result<(), String>
+where
+    F: FnMut(u64, &mut views::MiniFbView, &mut Machine) -> Result<Option<Inputs>, String>,
+{
+    let step_duration =
+        time::Duration::from_secs_f64(1.0 / run_config.instructions_per_second as f64);
+    let clock = VirtualClock::new(time::Instant::now(), run_config.instructions_per_second);
 
-    view.open(&cpu.screen);
+    view.open(&machine.cpu().screen);
 
-    loop {
-        let inputs = view.get_inputs()?;
-        let screen_changed = cpu.step(&time::Instant::now(), &inputs)?;
+    let mut cycle = 0;
+    while let Some(inputs) = next_inputs(cycle, view, machine)? {
+        let cpu = machine.cpu_mut();
+
+        if let Some(debugger) = debugger.as_mut() {
+            match debugger.before_step(cpu)? {
+                StepDecision::Step => {}
+                StepDecision::Pause => {
+                    // Keep the window responsive while the debugger has the CPU paused
+                    if view.update(&cpu.screen) == views::ViewState::Closed {
+                        break;
+                    }
+                    thread::sleep(time::Duration::from_millis(PAUSED_POLL_MILLISECONDS));
+                    continue;
+                }
+                StepDecision::Quit => break,
+            }
+        }
+
+        let cycles_before_step = cpu.get_cycle_count();
+        let mut result = cpu.step(&clock.time_at(cycle), &inputs);
+        if let Some(debugger) = debugger.as_mut() {
+            result = debugger.after_step(cpu, result);
+        }
+        let screen_changed = match result {
+            Ok(screen_changed) => screen_changed,
+            Err(message) => {
+                view.close();
+                print_call_stack(cpu);
+                return Err(message);
+            }
+        };
+        cycle += 1;
 
         if screen_changed == cpu::ScreenChanged::Changed {
             // Clear the screen so we can redraw it
@@ -69,14 +1050,19 @@ fn run(args: &ArgMatches) -> Result<(), String> {
             if view.update(&cpu.screen) == views::ViewState::Closed {
                 break;
             }
+            cpu.screen.clear_dirty_rows();
 
             //let sleep_constant = time::Duration::from_millis(40);
             //thread::sleep(sleep_constant);
         }
 
-        let sleep_constant =
-            time::Duration::from_micros(ONE_SECOND_IN_MICROSECONDS / MAX_INSTRUCTIONS_PER_SECOND);
-        thread::sleep(sleep_constant);
+        let sleep_duration = match cpu.get_config().timing {
+            Timing::Fixed => step_duration,
+            Timing::CosmacVip => {
+                timing::get_cycles_duration(cpu.get_cycle_count() - cycles_before_step)
+            }
+        };
+        thread::sleep(sleep_duration);
     }
 
     view.close();
@@ -84,10 +1070,167 @@ fn run(args: &ArgMatches) -> Result<(), String> {
     Ok(())
 }
 
-fn load_rom(cpu: &mut cpu::CPU, filepath: &str) -> Result<(), String> {
-    let rom = un_io_result(load_file_bytes(filepath))?;
+/// Prints or writes out the results of any analysis that was turned on for the run.
+fn write_analysis_reports(
+    args: &ArgMatches,
+    machine: &Machine,
+    run_config: &RunConfig,
+) -> Result<(), String> {
+    let cpu = machine.cpu();
+
+    if let Some(access_map) = cpu.get_access_map() {
+        print!("{}", access_map.report(&run_config.symbols));
+    }
+
+    if let Some(profiler) = cpu.get_profiler() {
+        if args.is_present("profile") {
+            print!("{}", profiler.report(&run_config.symbols));
+        }
+        if let Some(folded_filepath) = args.value_of("profile-folded") {
+            let folded = profiler.to_folded_stacks(&run_config.symbols);
+            un_io_result(std::fs::write(folded_filepath, folded))?;
+            println!("Wrote folded stacks: {}", folded_filepath);
+        }
+    }
+
+    if let Some(coverage) = cpu.get_coverage() {
+        if let Some(listing_filepath) = args.value_of("coverage") {
+            let graph = ControlFlowGraph::analyze(
+                machine.get_rom(),
+                run_config.machine.load_address,
+                run_config.machine.initial_program_counter,
+            );
+            let listing = coverage.to_listing(
+                machine.get_rom(),
+                run_config.machine.load_address,
+                &graph,
+                &run_config.symbols,
+            );
+            un_io_result(std::fs::write(listing_filepath, listing))?;
+            println!("Wrote coverage listing: {}", listing_filepath);
+        }
+        if let Some(lcov_filepath) = args.value_of("lcov") {
+            if run_config.symbols.get_source_locations().next().is_none() {
+                return Err(
+                    "Can't write lcov coverage: the symbol file has no source lines".to_string(),
+                );
+            }
+
+            let lcov = coverage.to_lcov(
+                machine.get_rom(),
+                run_config.machine.load_address,
+                &run_config.symbols,
+            );
+            un_io_result(std::fs::write(lcov_filepath, lcov))?;
+            println!("Wrote lcov coverage: {}", lcov_filepath);
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints where the CPU stopped and the subroutine calls that led there, to help track down the
+/// cause of errors.
+fn print_call_stack(cpu: &cpu::CPU) {
+    let symbols = cpu.get_symbols();
+    let describe = |address: Address| match symbols.get_source_location(address) {
+        Some(location) => format!("{} ({})", symbols.format_address(address), location),
+        None => symbols.format_address(address),
+    };
+
+    println!("Stopped at {}", describe(cpu.get_program_counter()));
+
+    match cpu.get_call_stack() {
+        Ok(frames) => {
+            println!("Call stack (most recent call first):");
+            for frame in frames.iter() {
+                println!(
+                    "  {} (returns to {})",
+                    describe(frame.call_address),
+                    symbols.format_address(frame.return_address)
+                );
+            }
+        }
+        Err(message) => println!("Could not read the call stack: {}", message),
+    }
+}
+
+/// Loads a ROM file, unpacking the ROM from it if it is a zip file. Octo cartridges are assembled,
+/// and their options are returned along with the ROM.
+fn load_rom_file(filepath: &str) -> Result<(Vec<u8>, Option<OctoOptions>), String> {
+    let mut rom = un_io_result(load_file_bytes(filepath))?;
+    if zip::is_zip(&rom) {
+        let entry = find_zipped_rom(&rom)?;
+        println!("Unpacked {} from {}", entry.name, filepath);
+        rom = entry.data;
+    }
+
+    if cartridge::is_gif(&rom) {
+        let cartridge = OctoCartridge::parse(&rom)
+            .map_err(|e| format!("Failed to load Octo cartridge {}: {}", filepath, e))?;
+        let rom = octo::assemble(&cartridge.program)
+            .map_err(|e| format!("Failed to assemble Octo cartridge {}: {}", filepath, e))?;
+        println!("Assembled {} ({} bytes)", filepath, rom.len());
+        return Ok((rom, Some(cartridge.options)));
+    }
+
+    Ok((rom, None))
+}
+
+/// Loads the options from an Octo cartridge, which can be in a zip file.
+fn load_octo_options(filepath: &str) -> Result<OctoOptions, String> {
+    let mut gif = un_io_result(load_file_bytes(filepath))?;
+    if zip::is_zip(&gif) {
+        gif = find_zipped_rom(&gif)?.data;
+    }
+
+    let cartridge = OctoCartridge::parse(&gif)
+        .map_err(|e| format!("Failed to load Octo cartridge {}: {}", filepath, e))?;
+    Ok(cartridge.options)
+}
+
+/// Returns the ROM in the given zip file, which is either its only file or its only file with a
+/// ROM extension.
+fn find_zipped_rom(bytes: &[u8]) -> Result<zip::ZipEntry, String> {
+    let mut entries = zip::extract(bytes)?;
+    if entries.len() > 1 {
+        entries.retain(|entry| {
+            let extension = Path::new(&entry.name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or_default()
+                .to_lowercase();
+            ZIPPED_ROM_EXTENSIONS.contains(&extension.as_str())
+        });
+    }
+
+    match entries.len() {
+        0 => Err("Zip file doesn't contain a ROM".to_string()),
+        1 => Ok(entries.remove(0)),
+        _ => {
+            let names: Vec<String> = entries.into_iter().map(|entry| entry.name).collect();
+            Err(format!(
+                "Zip file contains more than one ROM: {}",
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+/// Reads lines from standard input on another thread, so that waiting for them doesn't block.
+fn read_stdin_lines() -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // Stops once standard input is closed or the program has exited
+        for line in io::stdin().lock().lines() {
+            let sent = line.map(|line| sender.send(line).is_ok());
+            if sent.ok() != Some(true) {
+                break;
+            }
+        }
+    });
 
-    cpu.load_rom(&rom)
+    receiver
 }
 
 fn un_io_result<R>(result: io::Result<R>) -> Result<R, String> {
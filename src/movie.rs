@@ -0,0 +1,501 @@
+//! Recording and replaying of the inputs given to a CPU, so that a session can be reproduced
+//! exactly.
+//!
+//! A movie file is a plain text file that looks like the following:
+//!
+//! ```text
+//! chip8-movie 2
+//! rom_sha1 a9993e364706816aba3e25717850c26c9cd0d89d
+//! rng_seed 42
+//! quirks shift=1 memoryIncrementByX=0 memoryLeaveIUnchanged=1 wrap=0 jump=0 vblank=0 logic=0 indexOverflow=0
+//! timing fixed
+//! instructions_per_second 700
+//! load_address 0x0200
+//! initial_pc 0x0200
+//! font_address 0x0050
+//! large_font_address 0x00a0
+//! stack_depth 16
+//! stack_location internal
+//! font f0909090f0...
+//! patch none
+//! cycles 1200
+//! 0 0000
+//! 350 0080
+//! 372 0000
+//! ```
+//!
+//! The settings after the RNG seed are the ones the ROM was run with, which are used again when
+//! the movie is replayed. The font is the 240 bytes of the small and large fonts in hex, and the
+//! patch is the patch file that was applied to the ROM in hex, if there was one.
+//!
+//! Each of the lines after the header gives a cycle index and the inputs (as a hex bitmask of
+//! pressed keys) that were given to `CPU::step` starting at that cycle. Inputs are only written
+//! out when they change.
+
+use std::io::{BufRead, Write};
+
+use crate::config::{MachineConfig, StackLocation, Timing};
+use crate::font::Font;
+use crate::quirks::Quirks;
+use crate::ram::Address;
+use crate::views::Inputs;
+
+const MOVIE_HEADER: &str = "chip8-movie 2";
+
+/// The settings that change how a ROM runs, which a replay has to use for it to match the
+/// recording.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MovieSettings {
+    pub machine: MachineConfig,
+    pub font: Font,
+    pub instructions_per_second: u64,
+    /// The patch file that was applied to the ROM, if any.
+    pub patch: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Movie {
+    rom_sha1: String,
+    rng_seed: u64,
+    settings: MovieSettings,
+    num_cycles: u64,
+    input_changes: Vec<(u64, Inputs)>,
+}
+
+impl Movie {
+    pub fn new(rom_sha1: String, rng_seed: u64, settings: MovieSettings) -> Movie {
+        Movie {
+            rom_sha1,
+            rng_seed,
+            settings,
+            num_cycles: 0,
+            input_changes: vec![],
+        }
+    }
+
+    pub fn get_rom_sha1(&self) -> &str {
+        &self.rom_sha1
+    }
+
+    pub fn get_rng_seed(&self) -> u64 {
+        self.rng_seed
+    }
+
+    pub fn get_settings(&self) -> &MovieSettings {
+        &self.settings
+    }
+
+    pub fn get_num_cycles(&self) -> u64 {
+        self.num_cycles
+    }
+
+    /// Records the inputs that were passed to the CPU on the given cycle.
+    ///
+    /// Cycles must be recorded in order, starting from 0.
+    pub fn record(&mut self, cycle: u64, inputs: &Inputs) -> Result<(), String> {
+        if cycle != self.num_cycles {
+            return Err(format!(
+                "Recorded cycle out of order. Expected cycle {}, but got {}",
+                self.num_cycles, cycle
+            ));
+        }
+
+        let changed = match self.input_changes.last() {
+            Some((_, prev_inputs)) => prev_inputs != inputs,
+            None => true,
+        };
+        if changed {
+            self.input_changes.push((cycle, inputs.clone()));
+        }
+
+        self.num_cycles += 1;
+        Ok(())
+    }
+
+    /// Returns the inputs that were passed to the CPU on the given cycle.
+    pub fn get_inputs(&self, cycle: u64) -> Result<Inputs, String> {
+        if cycle >= self.num_cycles {
+            return Err(format!(
+                "Cycle {} is past the end of the movie ({} cycles)",
+                cycle, self.num_cycles
+            ));
+        }
+
+        // Find the last change that happened at or before the given cycle
+        let index = match self.input_changes.binary_search_by_key(&cycle, |(c, _)| *c) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+
+        Ok(self.input_changes[index].1.clone())
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> Result<(), String> {
+        let settings = &self.settings;
+        let machine = &settings.machine;
+
+        let mut quirks = machine.quirks;
+        let quirks: Vec<String> = get_quirk_flags(&mut quirks)
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, **value as u8))
+            .collect();
+        let timing = match machine.timing {
+            Timing::Fixed => "fixed",
+            Timing::CosmacVip => "vip",
+        };
+        let stack_location = match machine.stack_location {
+            StackLocation::Internal => "internal".to_string(),
+            StackLocation::Ram(address) => format!("0x{:04x}", address),
+        };
+        let font = [settings.font.get_small(), settings.font.get_large()].concat();
+        let patch = match settings.patch.as_ref() {
+            Some(patch) => encode_hex(patch),
+            None => "none".to_string(),
+        };
+
+        let mut lines = vec![
+            MOVIE_HEADER.to_string(),
+            format!("rom_sha1 {}", self.rom_sha1),
+            format!("rng_seed {}", self.rng_seed),
+            format!("quirks {}", quirks.join(" ")),
+            format!("timing {}", timing),
+            format!(
+                "instructions_per_second {}",
+                settings.instructions_per_second
+            ),
+            format!("load_address 0x{:04x}", machine.load_address),
+            format!("initial_pc 0x{:04x}", machine.initial_program_counter),
+            format!("font_address 0x{:04x}", machine.font_address),
+            format!("large_font_address 0x{:04x}", machine.large_font_address),
+            format!("stack_depth {}", machine.stack_depth),
+            format!("stack_location {}", stack_location),
+            format!("font {}", encode_hex(&font)),
+            format!("patch {}", patch),
+            format!("cycles {}", self.num_cycles),
+        ];
+        for (cycle, inputs) in self.input_changes.iter() {
+            lines.push(format!("{} {:04x}", cycle, inputs.to_bits()));
+        }
+
+        for line in lines.iter() {
+            writeln!(output, "{}", line).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read<R: BufRead>(input: R) -> Result<Movie, String> {
+        let mut lines = input.lines();
+        let mut next_line = || -> Result<String, String> {
+            match lines.next() {
+                Some(line) => line.map_err(|e| e.to_string()),
+                None => Err("Unexpected end of movie file".to_string()),
+            }
+        };
+
+        let header = next_line()?;
+        if header != MOVIE_HEADER {
+            return Err(format!("Unrecognized movie file header: {}", header));
+        }
+
+        let rom_sha1 = Movie::parse_field(&next_line()?, "rom_sha1")?;
+        let rng_seed = Movie::parse_field(&next_line()?, "rng_seed")?;
+        let rng_seed = parse_number(&rng_seed, 10)?;
+        let settings = Movie::read_settings(&mut next_line)?;
+        let num_cycles = Movie::parse_field(&next_line()?, "cycles")?;
+        let num_cycles = parse_number(&num_cycles, 10)?;
+
+        let mut input_changes = vec![];
+        for line in lines {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 2 {
+                return Err(format!("Invalid movie input line: {}", line));
+            }
+
+            let cycle = parse_number(parts[0], 10)?;
+            let bits = parse_number(parts[1], 16)? as u16;
+
+            // Inputs are looked up with a binary search, so they have to be in order
+            if let Some((previous_cycle, _)) = input_changes.last() {
+                if cycle <= *previous_cycle {
+                    return Err(format!(
+                        "Movie input for cycle {} comes after cycle {}",
+                        cycle, previous_cycle
+                    ));
+                }
+            }
+            if cycle >= num_cycles {
+                return Err(format!(
+                    "Movie input for cycle {} is past the end of the movie ({} cycles)",
+                    cycle, num_cycles
+                ));
+            }
+
+            input_changes.push((cycle, Inputs::from_bits(bits)));
+        }
+
+        if num_cycles > 0 && input_changes.first().map(|(c, _)| *c) != Some(0) {
+            return Err("Movie file does not give inputs for cycle 0".to_string());
+        }
+
+        Ok(Movie {
+            rom_sha1,
+            rng_seed,
+            settings,
+            num_cycles,
+            input_changes,
+        })
+    }
+
+    fn read_settings<F>(next_line: &mut F) -> Result<MovieSettings, String>
+    where
+        F: FnMut() -> Result<String, String>,
+    {
+        let mut quirks = Quirks::default();
+        let quirk_values = Movie::parse_field(&next_line()?, "quirks")?;
+        for quirk_value in quirk_values.split_whitespace() {
+            let mut parts = quirk_value.splitn(2, '=');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some("0")) => (name, false),
+                (Some(name), Some("1")) => (name, true),
+                _ => return Err(format!("Invalid movie quirk: {}", quirk_value)),
+            };
+
+            let mut flags = get_quirk_flags(&mut quirks);
+            match flags.iter_mut().find(|(flag_name, _)| *flag_name == name) {
+                Some((_, flag)) => **flag = value,
+                None => return Err(format!("Unrecognized movie quirk: {}", name)),
+            }
+        }
+
+        let timing = match Movie::parse_field(&next_line()?, "timing")?.as_str() {
+            "fixed" => Timing::Fixed,
+            "vip" => Timing::CosmacVip,
+            timing => return Err(format!("Unrecognized movie timing: {}", timing)),
+        };
+
+        let instructions_per_second = Movie::parse_field(&next_line()?, "instructions_per_second")?;
+        let instructions_per_second = parse_number(&instructions_per_second, 10)?;
+        if instructions_per_second == 0 {
+            return Err("Movie has 0 instructions per second".to_string());
+        }
+
+        let load_address = parse_address(&Movie::parse_field(&next_line()?, "load_address")?)?;
+        let initial_program_counter =
+            parse_address(&Movie::parse_field(&next_line()?, "initial_pc")?)?;
+        let font_address = parse_address(&Movie::parse_field(&next_line()?, "font_address")?)?;
+        let large_font_address =
+            parse_address(&Movie::parse_field(&next_line()?, "large_font_address")?)?;
+        let stack_depth = Movie::parse_field(&next_line()?, "stack_depth")?;
+        let stack_depth = parse_number(&stack_depth, 10)? as usize;
+        let stack_location = match Movie::parse_field(&next_line()?, "stack_location")?.as_str() {
+            "internal" => StackLocation::Internal,
+            address => StackLocation::Ram(parse_address(address)?),
+        };
+
+        let machine = MachineConfig {
+            load_address,
+            font_address,
+            large_font_address,
+            initial_program_counter,
+            stack_depth,
+            stack_location,
+            timing,
+            quirks,
+        };
+        machine.validate()?;
+
+        let font = decode_hex(&Movie::parse_field(&next_line()?, "font")?)?;
+        let font = Font::from_bytes(&font)?;
+        let patch = match Movie::parse_field(&next_line()?, "patch")?.as_str() {
+            "none" => None,
+            patch => Some(decode_hex(patch)?),
+        };
+
+        Ok(MovieSettings {
+            machine,
+            font,
+            instructions_per_second,
+            patch,
+        })
+    }
+
+    fn parse_field(line: &str, name: &str) -> Result<String, String> {
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(n), Some(value)) if n == name => Ok(value.trim().to_string()),
+            _ => Err(format!(
+                "Expected movie field \"{}\", but got: {}",
+                name, line
+            )),
+        }
+    }
+}
+
+fn parse_number(value: &str, radix: u32) -> Result<u64, String> {
+    u64::from_str_radix(value, radix).map_err(|e| format!("Invalid number \"{}\": {}", value, e))
+}
+
+fn parse_address(value: &str) -> Result<Address, String> {
+    let hex = value
+        .strip_prefix("0x")
+        .ok_or_else(|| format!("Invalid address \"{}\"", value))?;
+    Address::from_str_radix(hex, 16).map_err(|e| format!("Invalid address \"{}\": {}", value, e))
+}
+
+/// Returns each quirk along with its name in the CHIP-8 database.
+fn get_quirk_flags(quirks: &mut Quirks) -> [(&'static str, &mut bool); 8] {
+    [
+        ("shift", &mut quirks.shift),
+        ("memoryIncrementByX", &mut quirks.memory_increment_by_x),
+        (
+            "memoryLeaveIUnchanged",
+            &mut quirks.memory_leave_i_unchanged,
+        ),
+        ("wrap", &mut quirks.wrap),
+        ("jump", &mut quirks.jump),
+        ("vblank", &mut quirks.vblank),
+        ("logic", &mut quirks.logic),
+        ("indexOverflow", &mut quirks.index_overflow),
+    ]
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex \"{}\"", hex));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|e| format!("Invalid hex \"{}\": {}", hex, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn test_settings() -> MovieSettings {
+    MovieSettings {
+        machine: MachineConfig::default(),
+        font: Font::default(),
+        instructions_per_second: 700,
+        patch: None,
+    }
+}
+
+#[test]
+fn movie_record_and_get_inputs() {
+    let mut movie = Movie::new("abc".to_string(), 42, test_settings());
+
+    let none_pressed = Inputs::default();
+    let seven_pressed = Inputs::from_bits(0x0080);
+
+    assert_eq!(Ok(()), movie.record(0, &none_pressed));
+    assert_eq!(Ok(()), movie.record(1, &none_pressed));
+    assert_eq!(Ok(()), movie.record(2, &seven_pressed));
+    assert_eq!(Ok(()), movie.record(3, &none_pressed));
+
+    assert_eq!(4, movie.get_num_cycles());
+    assert_eq!(3, movie.input_changes.len());
+
+    assert_eq!(Ok(none_pressed.clone()), movie.get_inputs(0));
+    assert_eq!(Ok(none_pressed.clone()), movie.get_inputs(1));
+    assert_eq!(Ok(seven_pressed), movie.get_inputs(2));
+    assert_eq!(Ok(none_pressed), movie.get_inputs(3));
+    assert!(movie.get_inputs(4).is_err());
+}
+
+#[test]
+fn movie_record_out_of_order() {
+    let mut movie = Movie::new("abc".to_string(), 42, test_settings());
+
+    let expected = Err("Recorded cycle out of order. Expected cycle 0, but got 1".to_string());
+    assert_eq!(expected, movie.record(1, &Inputs::default()));
+}
+
+#[test]
+fn movie_write_and_read() {
+    let settings = MovieSettings {
+        machine: MachineConfig {
+            timing: Timing::CosmacVip,
+            quirks: Quirks {
+                wrap: true,
+                ..Quirks::default()
+            },
+            ..MachineConfig::default().with_vip_stack()
+        },
+        font: Font::default(),
+        instructions_per_second: 1000,
+        patch: Some(b"PATCH".to_vec()),
+    };
+    let mut movie = Movie::new(
+        "a9993e364706816aba3e25717850c26c9cd0d89d".to_string(),
+        42,
+        settings,
+    );
+    assert_eq!(Ok(()), movie.record(0, &Inputs::default()));
+    assert_eq!(Ok(()), movie.record(1, &Inputs::from_bits(0x0381)));
+    assert_eq!(Ok(()), movie.record(2, &Inputs::default()));
+
+    let mut buffer: Vec<u8> = vec![];
+    assert_eq!(Ok(()), movie.write(&mut buffer));
+
+    let font = [Font::default().get_small(), Font::default().get_large()].concat();
+    let expected_text = format!(
+        "chip8-movie 2\n\
+         rom_sha1 a9993e364706816aba3e25717850c26c9cd0d89d\n\
+         rng_seed 42\n\
+         quirks shift=1 memoryIncrementByX=0 memoryLeaveIUnchanged=1 wrap=1 jump=0 vblank=0 logic=0 indexOverflow=0\n\
+         timing vip\n\
+         instructions_per_second 1000\n\
+         load_address 0x0200\n\
+         initial_pc 0x0200\n\
+         font_address 0x0050\n\
+         large_font_address 0x00a0\n\
+         stack_depth 12\n\
+         stack_location 0x0ea0\n\
+         font {}\n\
+         patch 5041544348\n\
+         cycles 3\n\
+         0 0000\n\
+         1 0381\n\
+         2 0000\n",
+        encode_hex(&font)
+    );
+    assert_eq!(expected_text, String::from_utf8(buffer.clone()).unwrap());
+
+    assert_eq!(Ok(movie), Movie::read(&buffer[..]));
+}
+
+#[test]
+fn movie_read_rejects_invalid_cycles() {
+    let mut movie = Movie::new("abc".to_string(), 42, test_settings());
+    assert_eq!(Ok(()), movie.record(0, &Inputs::default()));
+    assert_eq!(Ok(()), movie.record(1, &Inputs::from_bits(0x0001)));
+
+    let mut buffer: Vec<u8> = vec![];
+    assert_eq!(Ok(()), movie.write(&mut buffer));
+    let text = String::from_utf8(buffer).unwrap();
+
+    let unsorted = format!("{}0 0002\n", text);
+    assert_eq!(
+        Err("Movie input for cycle 0 comes after cycle 1".to_string()),
+        Movie::read(unsorted.as_bytes())
+    );
+
+    let past_end = text.replace("\n1 0001\n", "\n2 0001\n");
+    assert_eq!(
+        Err("Movie input for cycle 2 is past the end of the movie (2 cycles)".to_string()),
+        Movie::read(past_end.as_bytes())
+    );
+}
@@ -0,0 +1,1171 @@
+//! An assembler for Octo (https://github.com/JohnEarnest/Octo), the high level CHIP-8 assembly
+//! language that the programs in Octo cartridges are written in.
+//!
+//! The whole language is supported, including macros, `:calc` expressions, and string modes,
+//! apart from the debugging directives (`:breakpoint`, `:monitor`, and `:assert`), which are
+//! skipped. Like Octo, a jump to `main` is put at the start of the program unless `main` is the
+//! first thing in it.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+
+/// Where Octo programs are assembled to.
+const PROGRAM_START: usize = 0x200;
+const MAX_ADDRESS: usize = 0xFFFF;
+/// Largest address that fits in the 12 bits of a jump, call, or `i := NNN`.
+const MAX_SHORT_ADDRESS: usize = 0xFFF;
+/// Most macros and string modes that can be expanded, to stop recursive macros from expanding
+/// forever.
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// Assembles an Octo program into a ROM that is loaded at 0x200.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler::new(tokenize(source)?);
+    while !assembler.tokens.is_empty() {
+        assembler.statement()?;
+    }
+    assembler.finish()
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Token {
+    text: String,
+    /// Whether the token is a quoted string, as opposed to a name, number, or symbol.
+    is_string: bool,
+    line: usize,
+}
+
+/// Splits a program into tokens, which are separated by whitespace. Comments start with `#` and
+/// run to the end of the line.
+fn tokenize(source: &str) -> Result<VecDeque<Token>, String> {
+    let mut tokens = VecDeque::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '#' => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            }
+            '"' => {
+                let start_line = line;
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('r') => text.push('\r'),
+                            Some('t') => text.push('\t'),
+                            Some('0') => text.push('\0'),
+                            Some(c) => text.push(c),
+                            None => break,
+                        },
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            text.push(c);
+                        }
+                        None => return Err(format!("Line {}: Unterminated string", start_line)),
+                    }
+                }
+                tokens.push_back(Token {
+                    text,
+                    is_string: true,
+                    line: start_line,
+                });
+            }
+            c => {
+                let mut text = c.to_string();
+                while let Some(c) = chars.peek().filter(|c| !c.is_whitespace()) {
+                    text.push(*c);
+                    chars.next();
+                }
+                tokens.push_back(Token {
+                    text,
+                    is_string: false,
+                    line,
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Parses a decimal, hex (0x), or binary (0b) number, which can be negative.
+fn parse_number(text: &str) -> Option<f64> {
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => (-1.0, digits),
+        None => (1.0, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(sign * value)
+}
+
+/// Parses a register name (v0-vF).
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+            digit.to_digit(16).map(|digit| digit as u8)
+        }
+        _ => None,
+    }
+}
+
+fn apply_unary(operator: &str, value: f64) -> Option<f64> {
+    let result = match operator {
+        "-" => -value,
+        "~" => !(value as i64) as f64,
+        "!" => (value == 0.0) as u8 as f64,
+        "sin" => value.sin(),
+        "cos" => value.cos(),
+        "tan" => value.tan(),
+        "exp" => value.exp(),
+        "log" => value.ln(),
+        "abs" => value.abs(),
+        "sqrt" => value.sqrt(),
+        "sign" if value == 0.0 => 0.0,
+        "sign" => value.signum(),
+        "ceil" => value.ceil(),
+        "floor" => value.floor(),
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+fn apply_binary(operator: &str, left: f64, right: f64) -> Option<f64> {
+    let (a, b) = (left as i64, right as i64);
+    let result = match operator {
+        "-" => left - right,
+        "+" => left + right,
+        "*" => left * right,
+        "/" => left / right,
+        "%" => left % right,
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+        ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+        "pow" => left.powf(right),
+        "min" => left.min(right),
+        "max" => left.max(right),
+        "<" => (left < right) as u8 as f64,
+        "<=" => (left <= right) as u8 as f64,
+        ">" => (left > right) as u8 as f64,
+        ">=" => (left >= right) as u8 as f64,
+        "==" => (left == right) as u8 as f64,
+        "!=" => (left != right) as u8 as f64,
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FixupKind {
+    /// The low 12 bits of an instruction, such as a jump.
+    ShortAddress,
+    /// A 16 bit address, as used by `i := long` and `:pointer`.
+    LongAddress,
+    /// The two `vX := NN` instructions of `:unpack`, with the nibble that goes above a 12 bit
+    /// address, or none for `:unpack long`.
+    Unpack(Option<u8>),
+}
+
+/// A reference to a label that is defined later in the program.
+struct Fixup {
+    address: usize,
+    kind: FixupKind,
+    name: String,
+    line: usize,
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    here: usize,
+    /// Whether the program starts with a jump to `main`, which is filled in at the end.
+    jump_to_main: bool,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    /// The body to expand and the index in the alphabet of each character of each string mode.
+    string_modes: HashMap<String, HashMap<char, (usize, Vec<Token>)>>,
+    expansions: usize,
+    fixups: Vec<Fixup>,
+    /// Addresses of the jumps past each `if ... begin` or `else` that hasn't been ended yet.
+    branches: Vec<usize>,
+    /// Start addresses of each loop that hasn't been closed yet, along with the addresses of the
+    /// jumps out of it from `while`.
+    loops: Vec<(usize, Vec<usize>)>,
+    line: usize,
+}
+
+impl Assembler {
+    fn new(tokens: VecDeque<Token>) -> Assembler {
+        let aliases = [
+            ("unpack-hi", 0x0),
+            ("unpack-lo", 0x1),
+            ("compare-temp", 0xF),
+        ]
+        .iter()
+        .map(|(name, register)| (name.to_string(), *register))
+        .collect();
+
+        Assembler {
+            tokens,
+            // Room for the jump to main
+            rom: vec![0x00, 0x00],
+            here: PROGRAM_START + 2,
+            jump_to_main: true,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases,
+            macros: HashMap::new(),
+            string_modes: HashMap::new(),
+            expansions: 0,
+            fixups: vec![],
+            branches: vec![],
+            loops: vec![],
+            line: 1,
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("Line {}: {}", self.line, message)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .pop_front()
+            .ok_or_else(|| self.error("Unexpected end of program"))?;
+        self.line = token.line;
+        Ok(token)
+    }
+
+    fn next_word(&mut self) -> Result<Token, String> {
+        let token = self.next()?;
+        if token.is_string {
+            return Err(self.error(&format!("Unexpected string \"{}\"", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn next_string(&mut self) -> Result<Token, String> {
+        let token = self.next()?;
+        if !token.is_string {
+            return Err(self.error(&format!("Expected a string, got {}", token.text)));
+        }
+        Ok(token)
+    }
+
+    /// Reads the name being defined by a label, constant, alias, or macro.
+    fn next_name(&mut self) -> Result<String, String> {
+        let token = self.next_word()?;
+        if parse_number(&token.text).is_some() || parse_register(&token.text).is_some() {
+            return Err(self.error(&format!("Invalid name: {}", token.text)));
+        }
+        Ok(token.text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), String> {
+        let token = self.next_word()?;
+        if token.text != text {
+            return Err(self.error(&format!("Expected {}, got {}", text, token.text)));
+        }
+        Ok(())
+    }
+
+    fn peek_text(&self, index: usize) -> Option<&str> {
+        self.tokens
+            .get(index)
+            .filter(|token| !token.is_string)
+            .map(|token| token.text.as_str())
+    }
+
+    fn get_register(&self, text: &str) -> Option<u8> {
+        self.aliases
+            .get(text)
+            .copied()
+            .or_else(|| parse_register(text))
+    }
+
+    fn is_register(&self) -> bool {
+        self.peek_text(0)
+            .and_then(|text| self.get_register(text))
+            .is_some()
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let token = self.next_word()?;
+        self.get_register(&token.text)
+            .ok_or_else(|| self.error(&format!("Expected a register, got {}", token.text)))
+    }
+
+    /// Returns the value of a number, or of a constant or label that is already defined.
+    fn lookup_value(&self, text: &str) -> Option<f64> {
+        parse_number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|address| *address as f64))
+    }
+
+    fn value(&mut self) -> Result<f64, String> {
+        let token = self.next_word()?;
+        self.lookup_value(&token.text)
+            .ok_or_else(|| self.error(&format!("Undefined name: {}", token.text)))
+    }
+
+    fn check_range(&self, value: f64, min: i64, max: i64) -> Result<i64, String> {
+        let value = value.floor() as i64;
+        if value < min || value > max {
+            return Err(self.error(&format!(
+                "Value {} is out of range ({} to {})",
+                value, min, max
+            )));
+        }
+        Ok(value)
+    }
+
+    /// Reads a byte, which can be written as a signed or unsigned number.
+    fn byte_value(&mut self) -> Result<u8, String> {
+        let value = self.value()?;
+        Ok(self.check_range(value, -128, 255)? as u8)
+    }
+
+    fn nibble_value(&mut self) -> Result<u8, String> {
+        let value = self.value()?;
+        Ok(self.check_range(value, 0, 15)? as u8)
+    }
+
+    /// Returns the address that the given token refers to, or records a fixup for it if it is a
+    /// label that hasn't been defined yet.
+    fn address_value(
+        &mut self,
+        token: &Token,
+        kind: FixupKind,
+        max: usize,
+    ) -> Result<usize, String> {
+        match self.lookup_value(&token.text) {
+            Some(value) => Ok(self.check_range(value, 0, max as i64)? as usize),
+            None => {
+                if parse_register(&token.text).is_some() {
+                    return Err(self.error(&format!("Expected an address, got {}", token.text)));
+                }
+                self.fixups.push(Fixup {
+                    address: self.here,
+                    kind,
+                    name: token.text.clone(),
+                    line: token.line,
+                });
+                Ok(0)
+            }
+        }
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), String> {
+        if self.here < PROGRAM_START || self.here > MAX_ADDRESS {
+            return Err(self.error(&format!(
+                "Program is outside of memory at 0x{:x}",
+                self.here
+            )));
+        }
+
+        let index = self.here - PROGRAM_START;
+        if index >= self.rom.len() {
+            self.rom.resize(index + 1, 0x00);
+        }
+        self.rom[index] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, first: u8, second: u8) -> Result<(), String> {
+        self.emit_byte(first)?;
+        self.emit_byte(second)
+    }
+
+    /// Emits an instruction made of an opcode nibble and a 12 bit address.
+    fn emit_address_instruction(&mut self, opcode: u8, token: &Token) -> Result<(), String> {
+        let address = self.address_value(token, FixupKind::ShortAddress, MAX_SHORT_ADDRESS)?;
+        self.emit((opcode << 4) | (address >> 8) as u8, address as u8)
+    }
+
+    fn emit_long_address(&mut self) -> Result<(), String> {
+        let token = self.next_word()?;
+        let address = self.address_value(&token, FixupKind::LongAddress, MAX_ADDRESS)?;
+        self.emit((address >> 8) as u8, address as u8)
+    }
+
+    /// Points the jump at the given address to the target.
+    fn patch_jump(&mut self, address: usize, target: usize) -> Result<(), String> {
+        if target > MAX_SHORT_ADDRESS {
+            return Err(self.error(&format!("Can't jump to 0x{:x}", target)));
+        }
+        let index = address - PROGRAM_START;
+        self.rom[index] = 0x10 | (target >> 8) as u8;
+        self.rom[index + 1] = target as u8;
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: String, address: usize) -> Result<(), String> {
+        if self.labels.contains_key(&name) {
+            return Err(self.error(&format!("Label is already defined: {}", name)));
+        }
+
+        // The jump to main isn't needed if the program starts at main
+        if name == "main"
+            && self.jump_to_main
+            && self.here == PROGRAM_START + 2
+            && self.rom.len() == 2
+            && self.labels.is_empty()
+        {
+            self.rom.clear();
+            self.here = PROGRAM_START;
+            self.jump_to_main = false;
+            self.labels.insert(name, PROGRAM_START);
+            return Ok(());
+        }
+
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    /// Reads the tokens up to the `}` that closes a block, whose `{` has already been read.
+    fn block(&mut self) -> Result<Vec<Token>, String> {
+        let mut body = vec![];
+        let mut depth = 0;
+        loop {
+            let token = self.next()?;
+            if !token.is_string {
+                match token.text.as_str() {
+                    "{" => depth += 1,
+                    "}" if depth == 0 => return Ok(body),
+                    "}" => depth -= 1,
+                    _ => {}
+                }
+            }
+            body.push(token);
+        }
+    }
+
+    /// Puts tokens back at the front of the program, to be assembled next.
+    fn expand(&mut self, tokens: Vec<Token>) -> Result<(), String> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error("Too many macro expansions, a macro may be recursive"));
+        }
+
+        for token in tokens.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next_word()?;
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next_name()?;
+                self.define_label(name, self.here)?;
+            }
+            ":next" => {
+                let name = self.next_name()?;
+                self.define_label(name, self.here + 1)?;
+            }
+            ":const" => {
+                let name = self.next_name()?;
+                let value = self.value()?;
+                self.constants.insert(name, value);
+            }
+            ":calc" => {
+                let name = self.next_name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+            }
+            ":alias" => {
+                let name = self.next_name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            }
+            ":unpack" => {
+                let high_nibble = match self.peek_text(0) {
+                    Some("long") => {
+                        self.next()?;
+                        None
+                    }
+                    _ => Some(self.nibble_value()?),
+                };
+                let token = self.next_word()?;
+                let max = match high_nibble {
+                    Some(_) => MAX_SHORT_ADDRESS,
+                    None => MAX_ADDRESS,
+                };
+                let address = self.address_value(&token, FixupKind::Unpack(high_nibble), max)?;
+                let value = get_unpacked_value(high_nibble, address);
+
+                let high = self.get_register("unpack-hi").unwrap_or(0x0);
+                let low = self.get_register("unpack-lo").unwrap_or(0x1);
+                self.emit(0x60 | high, (value >> 8) as u8)?;
+                self.emit(0x60 | low, value as u8)?;
+            }
+            ":org" => {
+                let value = self.value()?;
+                self.here = self.check_range(value, 0, MAX_ADDRESS as i64)? as usize;
+            }
+            ":byte" => {
+                let byte = match self.peek_text(0) {
+                    Some("{") => {
+                        self.next()?;
+                        let value = self.calc()?;
+                        self.check_range(value, -128, 255)? as u8
+                    }
+                    _ => self.byte_value()?,
+                };
+                self.emit_byte(byte)?;
+            }
+            ":pointer" => self.emit_long_address()?,
+            ":call" => {
+                let token = self.next_word()?;
+                self.emit_address_instruction(0x2, &token)?;
+            }
+            ":macro" => {
+                let name = self.next_name()?;
+                let mut parameters = vec![];
+                loop {
+                    let token = self.next_word()?;
+                    if token.text == "{" {
+                        break;
+                    }
+                    parameters.push(token.text);
+                }
+                let body = self.block()?;
+                self.macros.insert(
+                    name,
+                    Macro {
+                        parameters,
+                        body,
+                        calls: 0,
+                    },
+                );
+            }
+            ":stringmode" => {
+                let name = self.next_name()?;
+                let alphabet = self.next_string()?;
+                self.expect("{")?;
+                let body = self.block()?;
+                let mode = self.string_modes.entry(name).or_default();
+                for (index, c) in alphabet.text.chars().enumerate() {
+                    mode.insert(c, (index, body.clone()));
+                }
+            }
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ":assert" => {
+                if self.tokens.front().is_some_and(|token| token.is_string) {
+                    self.next()?;
+                }
+                self.expect("{")?;
+                self.block()?;
+            }
+            ";" | "return" => self.emit(0x00, 0xEE)?,
+            "clear" => self.emit(0x00, 0xE0)?,
+            "hires" => self.emit(0x00, 0xFF)?,
+            "lores" => self.emit(0x00, 0xFE)?,
+            "exit" => self.emit(0x00, 0xFD)?,
+            "scroll-left" => self.emit(0x00, 0xFC)?,
+            "scroll-right" => self.emit(0x00, 0xFB)?,
+            "scroll-down" => {
+                let rows = self.nibble_value()?;
+                self.emit(0x00, 0xC0 | rows)?;
+            }
+            "scroll-up" => {
+                let rows = self.nibble_value()?;
+                self.emit(0x00, 0xD0 | rows)?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(0xF0 | x, 0x33)?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let is_save = token.text == "save";
+                if self.peek_text(0) == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    self.emit(0x50 | x, (y << 4) | if is_save { 0x2 } else { 0x3 })?;
+                } else {
+                    self.emit(0xF0 | x, if is_save { 0x55 } else { 0x65 })?;
+                }
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(0xF0 | x, 0x75)?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(0xF0 | x, 0x85)?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let rows = self.nibble_value()?;
+                self.emit(0xD0 | x, (y << 4) | rows)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let opcode = match token.text.as_str() {
+                    "jump" => 0x1,
+                    "jump0" => 0xB,
+                    _ => 0x0,
+                };
+                let target = self.next_word()?;
+                self.emit_address_instruction(opcode, &target)?;
+            }
+            "plane" => {
+                let planes = self.nibble_value()?;
+                self.emit(0xF0 | planes, 0x01)?;
+            }
+            "audio" => self.emit(0xF0, 0x02)?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let operation = match token.text.as_str() {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.emit(0xF0 | x, operation)?;
+            }
+            "i" => self.index_operation()?,
+            "if" => {
+                // The comparison is read again by `conditional`, so look ahead for how it ends
+                let end_index = match self.peek_text(1) {
+                    Some("key") | Some("-key") => 2,
+                    _ => 3,
+                };
+                match self.peek_text(end_index) {
+                    Some("then") => {
+                        self.conditional(false)?;
+                        self.expect("then")?;
+                    }
+                    Some("begin") => {
+                        self.conditional(true)?;
+                        self.expect("begin")?;
+                        self.branches.push(self.here);
+                        self.emit(0x10, 0x00)?;
+                    }
+                    _ => return Err(self.error("Expected then or begin after if")),
+                }
+            }
+            "else" => {
+                let branch = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| self.error("else without if ... begin"))?;
+                self.branches.push(self.here);
+                self.emit(0x10, 0x00)?;
+                self.patch_jump(branch, self.here)?;
+            }
+            "end" => {
+                let branch = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| self.error("end without if ... begin"))?;
+                self.patch_jump(branch, self.here)?;
+            }
+            "loop" => self.loops.push((self.here, vec![])),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(self.error("while outside of a loop"));
+                }
+                self.conditional(true)?;
+                let here = self.here;
+                if let Some((_, exits)) = self.loops.last_mut() {
+                    exits.push(here);
+                }
+                self.emit(0x10, 0x00)?;
+            }
+            "again" => {
+                let (start, exits) = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| self.error("again without loop"))?;
+                self.emit(0x10 | (start >> 8) as u8, start as u8)?;
+                for exit in exits {
+                    self.patch_jump(exit, self.here)?;
+                }
+            }
+            text => {
+                if let Some(x) = self.get_register(text) {
+                    self.register_operation(x)?;
+                } else if self.macros.contains_key(text) {
+                    self.expand_macro(text)?;
+                } else if self.string_modes.contains_key(text) {
+                    self.expand_string_mode(text)?;
+                } else if self.labels.contains_key(text) {
+                    self.emit_address_instruction(0x2, &token)?;
+                } else if let Some(value) = self.lookup_value(text) {
+                    // Bare numbers are data
+                    let byte = self.check_range(value, -128, 255)? as u8;
+                    self.emit_byte(byte)?;
+                } else {
+                    // Anything else is a call to a label, which can be defined later
+                    self.emit_address_instruction(0x2, &token)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn index_operation(&mut self) -> Result<(), String> {
+        let operator = self.next_word()?;
+        match operator.text.as_str() {
+            ":=" => match self.peek_text(0) {
+                Some("hex") | Some("bighex") => {
+                    let operation = match self.next()?.text.as_str() {
+                        "hex" => 0x29,
+                        _ => 0x30,
+                    };
+                    let x = self.register()?;
+                    self.emit(0xF0 | x, operation)
+                }
+                Some("long") => {
+                    self.next()?;
+                    self.emit(0xF0, 0x00)?;
+                    self.emit_long_address()
+                }
+                _ => {
+                    let target = self.next_word()?;
+                    self.emit_address_instruction(0xA, &target)
+                }
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.emit(0xF0 | x, 0x1E)
+            }
+            other => Err(self.error(&format!("Unknown operator for i: {}", other))),
+        }
+    }
+
+    fn register_operation(&mut self, x: u8) -> Result<(), String> {
+        let operator = self.next_word()?;
+        let register_operation = match operator.text.as_str() {
+            ":=" => 0x0,
+            "|=" => 0x1,
+            "&=" => 0x2,
+            "^=" => 0x3,
+            "+=" => 0x4,
+            "-=" => 0x5,
+            ">>=" => 0x6,
+            "=-" => 0x7,
+            "<<=" => 0xE,
+            other => return Err(self.error(&format!("Unknown operator: {}", other))),
+        };
+
+        if self.is_register() {
+            let y = self.register()?;
+            return self.emit(0x80 | x, (y << 4) | register_operation);
+        }
+
+        match (operator.text.as_str(), self.peek_text(0)) {
+            (":=", Some("random")) => {
+                self.next()?;
+                let mask = self.byte_value()?;
+                self.emit(0xC0 | x, mask)
+            }
+            (":=", Some("key")) => {
+                self.next()?;
+                self.emit(0xF0 | x, 0x0A)
+            }
+            (":=", Some("delay")) => {
+                self.next()?;
+                self.emit(0xF0 | x, 0x07)
+            }
+            (":=", _) => {
+                let value = self.byte_value()?;
+                self.emit(0x60 | x, value)
+            }
+            ("+=", _) => {
+                let value = self.byte_value()?;
+                self.emit(0x70 | x, value)
+            }
+            ("-=", _) => {
+                let value = self.byte_value()?;
+                self.emit(0x70 | x, value.wrapping_neg())
+            }
+            (other, _) => Err(self.error(&format!("{} needs a register", other))),
+        }
+    }
+
+    /// Emits a comparison that skips the instruction after it unless the comparison is true, or
+    /// unless it is false if `negated` is set.
+    fn conditional(&mut self, negated: bool) -> Result<(), String> {
+        let x = self.register()?;
+        let comparison = self.next_word()?.text;
+        let comparison = match (negated, comparison.as_str()) {
+            (false, comparison) => comparison,
+            (true, "==") => "!=",
+            (true, "!=") => "==",
+            (true, "key") => "-key",
+            (true, "-key") => "key",
+            (true, "<") => ">=",
+            (true, ">") => "<=",
+            (true, ">=") => "<",
+            (true, "<=") => ">",
+            (true, comparison) => comparison,
+        };
+
+        match comparison {
+            "==" | "!=" => {
+                let is_equal = comparison == "==";
+                if self.is_register() {
+                    let y = self.register()?;
+                    self.emit(if is_equal { 0x90 } else { 0x50 } | x, y << 4)
+                } else {
+                    let value = self.byte_value()?;
+                    self.emit(if is_equal { 0x40 } else { 0x30 } | x, value)
+                }
+            }
+            "key" => self.emit(0xE0 | x, 0xA1),
+            "-key" => self.emit(0xE0 | x, 0x9E),
+            "<" | ">" | "<=" | ">=" => {
+                // Subtract in the temporary register and check whether it borrowed
+                let temp = self.get_register("compare-temp").unwrap_or(0xF);
+                if self.is_register() {
+                    let y = self.register()?;
+                    self.emit(0x80 | temp, y << 4)?;
+                } else {
+                    let value = self.byte_value()?;
+                    self.emit(0x60 | temp, value)?;
+                }
+                let (operation, skip) = match comparison {
+                    ">" => (0x5, 0x3F),
+                    "<" => (0x7, 0x3F),
+                    ">=" => (0x7, 0x4F),
+                    _ => (0x5, 0x4F),
+                };
+                self.emit(0x80 | temp, (x << 4) | operation)?;
+                self.emit(skip, 0x01)
+            }
+            other => Err(self.error(&format!("Unknown comparison: {}", other))),
+        }
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), String> {
+        let (parameters, body, calls) = match self.macros.get_mut(name) {
+            Some(definition) => {
+                definition.calls += 1;
+                (
+                    definition.parameters.clone(),
+                    definition.body.clone(),
+                    definition.calls - 1,
+                )
+            }
+            None => return Err(self.error(&format!("Undefined macro: {}", name))),
+        };
+
+        let mut arguments = HashMap::new();
+        for parameter in parameters {
+            let argument = self.next()?;
+            arguments.insert(parameter, argument);
+        }
+
+        let expanded = body
+            .into_iter()
+            .map(|token| match (token.is_string, token.text.as_str()) {
+                (false, "CALLS") => Token {
+                    text: calls.to_string(),
+                    ..token
+                },
+                (false, text) => arguments.get(text).cloned().unwrap_or(token),
+                _ => token,
+            })
+            .collect();
+        self.expand(expanded)
+    }
+
+    fn expand_string_mode(&mut self, name: &str) -> Result<(), String> {
+        let text = self.next_string()?.text;
+        let mut expanded = vec![];
+        for (index, c) in text.chars().enumerate() {
+            let (value, body) = self
+                .string_modes
+                .get(name)
+                .and_then(|mode| mode.get(&c))
+                .ok_or_else(|| {
+                    self.error(&format!("String mode {} has no character {:?}", name, c))
+                })?;
+
+            expanded.extend(body.iter().map(|token| {
+                let text = match (token.is_string, token.text.as_str()) {
+                    (false, "CHAR") => (c as u32).to_string(),
+                    (false, "INDEX") => index.to_string(),
+                    (false, "VALUE") => value.to_string(),
+                    _ => token.text.clone(),
+                };
+                Token {
+                    text,
+                    ..token.clone()
+                }
+            }));
+        }
+        self.expand(expanded)
+    }
+
+    /// Evaluates a `:calc` expression, whose `{` has already been read. Like Octo, operators have
+    /// no precedence and are evaluated right to left.
+    fn calc(&mut self) -> Result<f64, String> {
+        let value = self.calc_expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn calc_expression(&mut self) -> Result<f64, String> {
+        let left = self.calc_term()?;
+        match self.peek_text(0) {
+            Some("}") | Some(")") => return Ok(left),
+            _ => {}
+        }
+
+        let operator = self.next_word()?.text;
+        let right = self.calc_expression()?;
+        apply_binary(&operator, left, right)
+            .ok_or_else(|| self.error(&format!("Unknown operator: {}", operator)))
+    }
+
+    fn calc_term(&mut self) -> Result<f64, String> {
+        let token = self.next_word()?;
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expression()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            "strlen" => Ok(self.next_string()?.text.chars().count() as f64),
+            "@" => {
+                let address = self.calc_term()?.floor() as i64 - PROGRAM_START as i64;
+                let byte = usize::try_from(address)
+                    .ok()
+                    .and_then(|index| self.rom.get(index))
+                    .copied()
+                    .unwrap_or(0);
+                Ok(byte as f64)
+            }
+            text => match self.lookup_value(text) {
+                Some(value) => Ok(value),
+                None => {
+                    let operand = self.calc_term()?;
+                    apply_unary(text, operand)
+                        .ok_or_else(|| self.error(&format!("Undefined name: {}", text)))
+                }
+            },
+        }
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, String> {
+        if !self.branches.is_empty() {
+            return Err(self.error("if ... begin without end"));
+        }
+        if !self.loops.is_empty() {
+            return Err(self.error("loop without again"));
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            let value = self
+                .lookup_value(&fixup.name)
+                .ok_or_else(|| self.error(&format!("Undefined name: {}", fixup.name)))?;
+            let index = fixup.address - PROGRAM_START;
+
+            match fixup.kind {
+                FixupKind::ShortAddress => {
+                    let address = self.check_range(value, 0, MAX_SHORT_ADDRESS as i64)?;
+                    self.rom[index] |= (address >> 8) as u8;
+                    self.rom[index + 1] = address as u8;
+                }
+                FixupKind::LongAddress => {
+                    let address = self.check_range(value, 0, MAX_ADDRESS as i64)?;
+                    self.rom[index] = (address >> 8) as u8;
+                    self.rom[index + 1] = address as u8;
+                }
+                FixupKind::Unpack(high_nibble) => {
+                    let max = match high_nibble {
+                        Some(_) => MAX_SHORT_ADDRESS,
+                        None => MAX_ADDRESS,
+                    };
+                    let address = self.check_range(value, 0, max as i64)? as usize;
+                    let value = get_unpacked_value(high_nibble, address);
+                    self.rom[index + 1] = (value >> 8) as u8;
+                    self.rom[index + 3] = value as u8;
+                }
+            }
+        }
+
+        if self.jump_to_main {
+            let main = *self.labels.get("main").ok_or("Program has no main label")?;
+            self.patch_jump(PROGRAM_START, main)?;
+        }
+
+        Ok(self.rom)
+    }
+}
+
+/// Returns the 16 bit value that `:unpack` splits across two registers.
+fn get_unpacked_value(high_nibble: Option<u8>, address: usize) -> u16 {
+    match high_nibble {
+        Some(nibble) => ((nibble as u16) << 12) | address as u16,
+        None => address as u16,
+    }
+}
+
+#[test]
+fn octo_assemble_instructions() {
+    let source = r#"
+        : main
+            clear
+            v1 := 0x20  va += 1  v2 -= 1  v3 := v4  v3 <<= v4  v5 =- v6
+            v0 := random 0xF0  v1 := key  v2 := delay
+            delay := v3  buzzer := v4
+            i := 0x300  i := hex v5  i += v6
+            sprite v1 v2 5
+            bcd v7  save v8  load v9  save v1 - v3
+            jump 0x400
+            ;
+    "#;
+
+    assert_eq!(
+        Ok(vec![
+            0x00, 0xE0, //
+            0x61, 0x20, 0x7A, 0x01, 0x72, 0xFF, 0x83, 0x40, 0x83, 0x4E, 0x85, 0x67, //
+            0xC0, 0xF0, 0xF1, 0x0A, 0xF2, 0x07, //
+            0xF3, 0x15, 0xF4, 0x18, //
+            0xA3, 0x00, 0xF5, 0x29, 0xF6, 0x1E, //
+            0xD1, 0x25, //
+            0xF7, 0x33, 0xF8, 0x55, 0xF9, 0x65, 0x51, 0x32, //
+            0x14, 0x00, //
+            0x00, 0xEE,
+        ]),
+        assemble(source)
+    );
+}
+
+#[test]
+fn octo_assemble_labels_and_control_flow() {
+    let source = r#"
+        : draw   # called before it is defined below main
+            return
+        : main
+            draw
+            loop
+                v0 += 1
+                if v0 == 3 then v1 := 1
+                if v0 > 5 begin
+                    v2 := 1
+                else
+                    v2 := 2
+                end
+                while v0 != 10
+            again
+            :unpack 0xA data
+            i := data
+        : data
+            0xFF 0x81
+    "#;
+
+    assert_eq!(
+        Ok(vec![
+            0x12, 0x04, // 0x200: Jump to main
+            0x00, 0xEE, // 0x202: draw
+            0x22, 0x02, // 0x204: Call draw
+            0x70, 0x01, // 0x206: Loop
+            0x40, 0x03, 0x61, 0x01, // 0x208
+            0x6F, 0x05, 0x8F, 0x05, 0x4F, 0x01, 0x12, 0x18, // 0x20C: if v0 > 5 begin
+            0x62, 0x01, 0x12, 0x1A, // 0x214: else
+            0x62, 0x02, // 0x218
+            0x40, 0x0A, 0x12, 0x20, // 0x21A: while
+            0x12, 0x06, // 0x21E: again
+            0x60, 0xA2, 0x61, 0x26, // 0x220: :unpack
+            0xA2, 0x26, // 0x224
+            0xFF, 0x81, // 0x226: data
+        ]),
+        assemble(source)
+    );
+}
+
+#[test]
+fn octo_assemble_macros_and_calc() {
+    let source = r#"
+        :const SPEED 2
+        :alias x v3
+        :macro move register amount { register += amount }
+        :calc DOUBLE { SPEED * 2 + 1 }
+        :stringmode text "AB" { :byte { VALUE + 0x10 } }
+        : main
+            move x SPEED
+            move v4 DOUBLE
+            :byte { 1 << 4 }
+            text "BA"
+    "#;
+
+    assert_eq!(
+        Ok(vec![0x73, 0x02, 0x74, 0x06, 0x10, 0x11, 0x10]),
+        assemble(source)
+    );
+}
+
+#[test]
+fn octo_assemble_errors() {
+    assert_eq!(
+        Err("Program has no main label".to_string()),
+        assemble(": start clear")
+    );
+    assert_eq!(
+        Err("Line 3: Undefined name: missing".to_string()),
+        assemble(": main\n  clear\n  jump missing")
+    );
+    assert_eq!(
+        Err("Line 1: Value 300 is out of range (-128 to 255)".to_string()),
+        assemble(": main v0 := 300")
+    );
+    assert_eq!(
+        Err("Line 1: loop without again".to_string()),
+        assemble(": main loop v0 += 1")
+    );
+    assert!(assemble(":macro forever { forever } : main forever").is_err());
+}
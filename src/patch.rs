@@ -0,0 +1,411 @@
+//! ROM patches in the IPS and BPS formats, which are commonly used to share translations and
+//! fixes of ROMs without sharing the ROMs themselves.
+//!
+//! BPS patches store checksums of the ROM they apply to and the ROM they produce, and these are
+//! checked when applying them. IPS patches have no checksums, so they are applied to any ROM.
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_FOOTER: &[u8] = b"EOF";
+/// Largest number of bytes in one IPS record.
+const IPS_MAX_RECORD_SIZE: usize = 0xFFFF;
+/// IPS offsets are 24 bits.
+const IPS_MAX_SIZE: usize = 0x100_0000;
+/// An offset that can't be used by an IPS record, since it would be read as the footer.
+const IPS_FOOTER_OFFSET: usize = 0x454F46;
+
+const BPS_HEADER: &[u8] = b"BPS1";
+/// Size of the checksums at the end of a BPS patch.
+const BPS_FOOTER_SIZE: usize = 12;
+/// Most bytes to reserve for the output of a BPS patch up front, since its size comes from the
+/// patch and can't be trusted.
+const BPS_MAX_PREALLOCATED_SIZE: usize = 0x10000;
+
+/// Applies the given IPS or BPS patch to the ROM, detecting the format from the patch's header.
+pub fn apply(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_HEADER) {
+        apply_ips(patch, rom)
+    } else if patch.starts_with(BPS_HEADER) {
+        apply_bps(patch, rom)
+    } else {
+        Err("Unrecognized patch format, expected an IPS or BPS patch".to_string())
+    }
+}
+
+pub fn apply_ips(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = PatchReader::new(patch);
+    if reader.read_bytes(IPS_HEADER.len())? != IPS_HEADER {
+        return Err("Missing IPS header".to_string());
+    }
+
+    let mut patched = rom.to_vec();
+    loop {
+        let offset_bytes = reader.read_bytes(3)?;
+        if offset_bytes == IPS_FOOTER {
+            break;
+        }
+        let offset = read_u24(offset_bytes);
+
+        let bytes = match reader.read_u16()? {
+            // Run-length encoded record
+            0 => {
+                let count = reader.read_u16()? as usize;
+                vec![reader.read_byte()?; count]
+            }
+            size => reader.read_bytes(size as usize)?.to_vec(),
+        };
+
+        if patched.len() < offset + bytes.len() {
+            patched.resize(offset + bytes.len(), 0);
+        }
+        patched[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    // Some patches end with the size to truncate the ROM to
+    if reader.remaining() >= 3 {
+        patched.truncate(read_u24(reader.read_bytes(3)?));
+    }
+
+    Ok(patched)
+}
+
+/// Creates an IPS patch that turns the original ROM into the modified one.
+pub fn create_ips(original: &[u8], modified: &[u8]) -> Result<Vec<u8>, String> {
+    if modified.len() > IPS_MAX_SIZE {
+        return Err(format!(
+            "ROM is too large for an IPS patch: {} bytes",
+            modified.len()
+        ));
+    }
+
+    let mut patch = IPS_HEADER.to_vec();
+    let mut offset = 0;
+    while offset < modified.len() {
+        if original.get(offset) == Some(&modified[offset]) {
+            offset += 1;
+            continue;
+        }
+
+        // Records can't start at the offset that reads as the footer, so start one byte earlier
+        let start = match offset {
+            IPS_FOOTER_OFFSET => offset - 1,
+            _ => offset,
+        };
+        let mut end = offset;
+        while end < modified.len()
+            && end - start < IPS_MAX_RECORD_SIZE
+            && original.get(end) != Some(&modified[end])
+        {
+            end += 1;
+        }
+
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[start..end]);
+        offset = end;
+    }
+    patch.extend_from_slice(IPS_FOOTER);
+
+    if modified.len() < original.len() {
+        patch.extend_from_slice(&(modified.len() as u32).to_be_bytes()[1..]);
+    }
+
+    Ok(patch)
+}
+
+pub fn apply_bps(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < BPS_HEADER.len() + BPS_FOOTER_SIZE {
+        return Err("BPS patch is too short".to_string());
+    }
+
+    let footer = &patch[patch.len() - BPS_FOOTER_SIZE..];
+    let source_checksum = read_u32_le(&footer[0..4]);
+    let target_checksum = read_u32_le(&footer[4..8]);
+    let patch_checksum = read_u32_le(&footer[8..12]);
+    if crc32(&patch[..patch.len() - 4]) != patch_checksum {
+        return Err("BPS patch is corrupted, its checksum doesn't match".to_string());
+    }
+    if crc32(rom) != source_checksum {
+        return Err(
+            "BPS patch is for a different ROM, the ROM's checksum doesn't match".to_string(),
+        );
+    }
+
+    let mut reader = PatchReader::new(&patch[..patch.len() - BPS_FOOTER_SIZE]);
+    if reader.read_bytes(BPS_HEADER.len())? != BPS_HEADER {
+        return Err("Missing BPS header".to_string());
+    }
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    let metadata_size = reader.read_varint()?;
+    reader.read_bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(format!(
+            "BPS patch is for a {} byte ROM, but the ROM is {} bytes",
+            source_size,
+            rom.len()
+        ));
+    }
+
+    let mut target = Vec::with_capacity(target_size.min(BPS_MAX_PREALLOCATED_SIZE));
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while reader.remaining() > 0 {
+        let data = reader.read_varint()?;
+        let length = (data >> 2) + 1;
+        if length > target_size - target.len() {
+            return Err(format!(
+                "BPS patch writes past the end of its {} byte output",
+                target_size
+            ));
+        }
+
+        match data & 0b11 {
+            // Source read
+            0 => {
+                let start = target.len();
+                target.extend_from_slice(get_range(rom, start, length)?);
+            }
+            // Target read
+            1 => target.extend_from_slice(reader.read_bytes(length)?),
+            // Source copy
+            2 => {
+                source_offset = offset_by(source_offset, reader.read_varint()?)?;
+                target.extend_from_slice(get_range(rom, source_offset, length)?);
+                source_offset += length;
+            }
+            // Target copy, which can overlap the bytes it writes to repeat a pattern
+            _ => {
+                target_offset = offset_by(target_offset, reader.read_varint()?)?;
+                for _ in 0..length {
+                    let byte = *target
+                        .get(target_offset)
+                        .ok_or("BPS patch copies from past the end of the output")?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(format!(
+            "BPS patch produced {} bytes, but should have produced {}",
+            target.len(),
+            target_size
+        ));
+    }
+    if crc32(&target) != target_checksum {
+        return Err("BPS patch produced a ROM whose checksum doesn't match".to_string());
+    }
+
+    Ok(target)
+}
+
+/// Moves an offset by a BPS relative offset, whose lowest bit is the sign.
+fn offset_by(offset: usize, relative: usize) -> Result<usize, String> {
+    let distance = relative >> 1;
+    let result = match relative & 1 {
+        0 => offset.checked_add(distance),
+        _ => offset.checked_sub(distance),
+    };
+
+    result.ok_or_else(|| "BPS patch copies from before the start of the ROM".to_string())
+}
+
+fn get_range(bytes: &[u8], start: usize, length: usize) -> Result<&[u8], String> {
+    start
+        .checked_add(length)
+        .and_then(|end| bytes.get(start..end))
+        .ok_or_else(|| "BPS patch reads past the end of the ROM".to_string())
+}
+
+fn read_u24(bytes: &[u8]) -> usize {
+    ((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Returns the CRC-32 checksum of the given bytes, as used by BPS and zip files.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF;
+    for byte in bytes.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0xEDB8_8320,
+            };
+        }
+    }
+
+    !crc
+}
+
+struct PatchReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(bytes: &'a [u8]) -> PatchReader<'a> {
+        PatchReader { bytes, position: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.remaining() < length {
+            return Err("Patch ended unexpectedly".to_string());
+        }
+
+        let bytes = &self.bytes[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, String> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Reads a BPS variable length number.
+    fn read_varint(&mut self) -> Result<usize, String> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.read_byte()?;
+            value = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|n| n.checked_add(value))
+                .ok_or("BPS patch has a number that is too large")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+
+            shift = shift
+                .checked_shl(7)
+                .filter(|shift| *shift != 0)
+                .ok_or("BPS patch has a number that is too large")?;
+            value = value
+                .checked_add(shift)
+                .ok_or("BPS patch has a number that is too large")?;
+        }
+    }
+}
+
+#[cfg(test)]
+fn create_test_bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_HEADER.to_vec();
+    patch.extend_from_slice(&[0x80 | source.len() as u8, 0x80 | target.len() as u8, 0x80]);
+    patch.extend_from_slice(actions);
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let patch_checksum = crc32(&patch);
+    patch.extend_from_slice(&patch_checksum.to_le_bytes());
+    patch
+}
+
+#[test]
+fn patch_crc32() {
+    assert_eq!(0xCBF43926, crc32(b"123456789"));
+    assert_eq!(0, crc32(b""));
+}
+
+#[test]
+fn patch_apply_ips() {
+    let rom = [0x00, 0xE0, 0x12, 0x00];
+    let patch = [
+        b'P', b'A', b'T', b'C', b'H', //
+        0x00, 0x00, 0x02, 0x00, 0x01, 0x13, // Change 0x12 to 0x13
+        0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0xFF, // Append three 0xFF
+        b'E', b'O', b'F',
+    ];
+    assert_eq!(
+        Ok(vec![0x00, 0xE0, 0x13, 0x00, 0xFF, 0xFF, 0xFF]),
+        apply(&patch, &rom)
+    );
+
+    let truncating_patch = [
+        b'P', b'A', b'T', b'C', b'H', b'E', b'O', b'F', 0x00, 0x00, 0x02,
+    ];
+    assert_eq!(Ok(vec![0x00, 0xE0]), apply(&truncating_patch, &rom));
+
+    assert_eq!(
+        Err("Patch ended unexpectedly".to_string()),
+        apply(&patch[..10], &rom)
+    );
+}
+
+#[test]
+fn patch_create_ips() {
+    let original = [0x00, 0xE0, 0x12, 0x00, 0x60, 0x01];
+    for modified in [
+        vec![0x00, 0xE0, 0x13, 0x02, 0x60, 0x01, 0x70, 0x02],
+        vec![0x01, 0xE0, 0x12],
+        original.to_vec(),
+    ]
+    .iter()
+    {
+        let patch = create_ips(&original, modified).unwrap();
+        assert_eq!(Ok(modified.clone()), apply(&patch, &original));
+    }
+
+    assert_eq!(
+        b"PATCH\x00\x00\x02\x00\x02\x13\x02EOF".to_vec(),
+        create_ips(&original, &[0x00, 0xE0, 0x13, 0x02, 0x60, 0x01]).unwrap()
+    );
+}
+
+#[test]
+fn patch_apply_bps() {
+    let source = [0x00, 0xE0, 0x12, 0x00];
+    let target = [0x00, 0xE0, 0x60, 0x05, 0x60, 0x05, 0x00, 0xE0];
+    let actions = [
+        0x84, // Source read 2 bytes
+        0x85, 0x60, 0x05, // Target read 2 bytes
+        0x87, 0x84, // Target copy 2 bytes from 2
+        0x86, 0x80, // Source copy 2 bytes from 0
+    ];
+    let patch = create_test_bps(&source, &target, &actions);
+    assert_eq!(Ok(target.to_vec()), apply(&patch, &source));
+
+    assert_eq!(
+        Err("BPS patch is for a different ROM, the ROM's checksum doesn't match".to_string()),
+        apply(&patch, &[0x00, 0xE0, 0x12, 0x01])
+    );
+
+    let mut corrupted = patch.clone();
+    corrupted[6] ^= 0xFF;
+    assert_eq!(
+        Err("BPS patch is corrupted, its checksum doesn't match".to_string()),
+        apply(&corrupted, &source)
+    );
+
+    // A target copy that keeps reading back the bytes it writes stops at the output size
+    let actions = [
+        0x84, // Source read 2 bytes
+        0x7F, 0x7F, 0x7F, 0xFF, 0x80, // Target copy a huge number of bytes from 0
+    ];
+    let patch = create_test_bps(&source, &target, &actions);
+    assert_eq!(
+        Err("BPS patch writes past the end of its 8 byte output".to_string()),
+        apply(&patch, &source)
+    );
+
+    // Huge output sizes aren't allocated up front
+    let mut patch = BPS_HEADER.to_vec();
+    patch.extend_from_slice(&[0x84, 0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0xFF, 0x80]);
+    patch.extend_from_slice(&crc32(&source).to_le_bytes());
+    patch.extend_from_slice(&crc32(&[]).to_le_bytes());
+    let patch_checksum = crc32(&patch);
+    patch.extend_from_slice(&patch_checksum.to_le_bytes());
+    assert!(apply(&patch, &source).is_err());
+}
@@ -0,0 +1,284 @@
+//! Profiling of where a program spends its time.
+//!
+//! Time is measured in cycles as counted by `CPU::get_cycle_count`, so with `Timing::CosmacVip` it
+//! is the number of machine cycles that the COSMAC VIP would take, and otherwise it is the number
+//! of instructions run. Subroutines are tracked by pairing up 2NNN calls with 00EE returns.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::instruction::Instruction;
+use crate::ram::Address;
+use crate::symbols::SymbolMap;
+
+/// Number of addresses listed in the report.
+const REPORT_TOP_ADDRESSES: usize = 20;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ExecutionStats {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+impl ExecutionStats {
+    fn add(&mut self, cycles: u64) {
+        self.count += 1;
+        self.cycles += cycles;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SubroutineStats {
+    pub calls: u64,
+    /// Cycles spent in the subroutine, including in the subroutines it called.
+    pub total_cycles: u64,
+    /// Cycles spent in the subroutine itself.
+    pub self_cycles: u64,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Profiler {
+    addresses: BTreeMap<Address, ExecutionStats>,
+    instructions: BTreeMap<&'static str, ExecutionStats>,
+    subroutines: BTreeMap<Address, SubroutineStats>,
+    /// Cycles spent with each chain of subroutine calls on the stack, outermost first.
+    stacks: BTreeMap<Vec<Address>, u64>,
+    /// Entry addresses of the subroutines that are currently running, starting with the address
+    /// the program started at.
+    call_stack: Vec<Address>,
+}
+
+impl Profiler {
+    /// Records that the instruction at the given address ran and took the given number of cycles.
+    pub fn record(&mut self, address: Address, instruction: &Instruction, cycles: u64) {
+        if self.call_stack.is_empty() {
+            self.call_stack.push(address);
+        }
+
+        self.addresses.entry(address).or_default().add(cycles);
+        self.instructions
+            .entry(get_instruction_name(instruction))
+            .or_default()
+            .add(cycles);
+        *self.stacks.entry(self.call_stack.clone()).or_default() += cycles;
+
+        // Recursive calls are only counted once towards the total
+        let mut counted = vec![];
+        for entry in self.call_stack.iter().skip(1) {
+            if !counted.contains(entry) {
+                self.subroutines.entry(*entry).or_default().total_cycles += cycles;
+                counted.push(*entry);
+            }
+        }
+        if self.call_stack.len() > 1 {
+            let current = self.call_stack[self.call_stack.len() - 1];
+            self.subroutines.entry(current).or_default().self_cycles += cycles;
+        }
+
+        match instruction {
+            Instruction::Call(target) => {
+                self.subroutines.entry(*target).or_default().calls += 1;
+                self.call_stack.push(*target);
+            }
+            // Returning from the outermost frame is an error that stops the program, so there is
+            // always a frame left to return to
+            Instruction::Return() if self.call_stack.len() > 1 => {
+                self.call_stack.pop();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn get_address_stats(&self) -> &BTreeMap<Address, ExecutionStats> {
+        &self.addresses
+    }
+
+    pub fn get_instruction_stats(&self) -> &BTreeMap<&'static str, ExecutionStats> {
+        &self.instructions
+    }
+
+    pub fn get_subroutine_stats(&self) -> &BTreeMap<Address, SubroutineStats> {
+        &self.subroutines
+    }
+
+    /// Returns a report of the addresses, instructions, and subroutines that took the most cycles.
+    pub fn report(&self, symbols: &SymbolMap) -> String {
+        let mut report = String::new();
+        let total_cycles: u64 = self.stacks.values().sum();
+        writeln!(report, "Total cycles: {}", total_cycles).unwrap();
+
+        writeln!(report, "Hottest addresses:").unwrap();
+        writeln!(report, "  {:>12} {:>10}  address", "cycles", "count").unwrap();
+        for (address, stats) in sort_by_cycles(&self.addresses, |stats| stats.cycles)
+            .iter()
+            .take(REPORT_TOP_ADDRESSES)
+        {
+            writeln!(
+                report,
+                "  {:>12} {:>10}  {}",
+                stats.cycles,
+                stats.count,
+                symbols.format_address(*address)
+            )
+            .unwrap();
+        }
+
+        writeln!(report, "Instructions:").unwrap();
+        writeln!(report, "  {:>12} {:>10}  instruction", "cycles", "count").unwrap();
+        for (name, stats) in sort_by_cycles(&self.instructions, |stats| stats.cycles).iter() {
+            writeln!(
+                report,
+                "  {:>12} {:>10}  {}",
+                stats.cycles, stats.count, name
+            )
+            .unwrap();
+        }
+
+        writeln!(report, "Subroutines:").unwrap();
+        writeln!(
+            report,
+            "  {:>12} {:>12} {:>10}  subroutine",
+            "total cycles", "self cycles", "calls"
+        )
+        .unwrap();
+        for (address, stats) in sort_by_cycles(&self.subroutines, |stats| stats.total_cycles).iter()
+        {
+            writeln!(
+                report,
+                "  {:>12} {:>12} {:>10}  {}",
+                stats.total_cycles,
+                stats.self_cycles,
+                stats.calls,
+                symbols.format_address(*address)
+            )
+            .unwrap();
+        }
+
+        report
+    }
+
+    /// Returns the cycles spent in each chain of subroutine calls in the folded stack format used
+    /// by flame graph tools, with one `outer;inner cycles` line per chain.
+    pub fn to_folded_stacks(&self, symbols: &SymbolMap) -> String {
+        let mut folded = String::new();
+        for (stack, cycles) in self.stacks.iter() {
+            let frames: Vec<String> = stack
+                .iter()
+                .map(|address| symbols.format_address(*address))
+                .collect();
+            writeln!(folded, "{} {}", frames.join(";"), cycles).unwrap();
+        }
+
+        folded
+    }
+}
+
+/// Returns the name of the kind of instruction, such as `DrawSprite`.
+fn get_instruction_name(instruction: &Instruction) -> &'static str {
+    use Instruction::*;
+
+    match instruction {
+        ClearDisplay() => "ClearDisplay",
+        Return() => "Return",
+        Jump(_) => "Jump",
+        Call(_) => "Call",
+        JumpIfEqValue(_, _) => "JumpIfEqValue",
+        JumpIfNotEqValue(_, _) => "JumpIfNotEqValue",
+        JumpIfRegistersEq(_, _) => "JumpIfRegistersEq",
+        SetRegister(_, _) => "SetRegister",
+        IncrementRegister(_, _) => "IncrementRegister",
+        CopyRegister(_, _) => "CopyRegister",
+        BitwiseOr(_, _) => "BitwiseOr",
+        BitwiseAnd(_, _) => "BitwiseAnd",
+        BitwiseXor(_, _) => "BitwiseXor",
+        IncrementByRegister(_, _) => "IncrementByRegister",
+        DecrementByRegister(_, _) => "DecrementByRegister",
+        RightShift(_, _) => "RightShift",
+        DecrementByRegisterRev(_, _) => "DecrementByRegisterRev",
+        LeftShift(_, _) => "LeftShift",
+        JumpIfRegistersNotEq(_, _) => "JumpIfRegistersNotEq",
+        SetIndexRegister(_) => "SetIndexRegister",
+        JumpWithOffset(_) => "JumpWithOffset",
+        SetRandomAnd(_, _) => "SetRandomAnd",
+        DrawSprite(_, _, _) => "DrawSprite",
+        SkipIfNotPressed(_) => "SkipIfNotPressed",
+        GetDelayTimer(_) => "GetDelayTimer",
+        SetDelayTimer(_) => "SetDelayTimer",
+        SetSoundTimer(_) => "SetSoundTimer",
+        IncrementIndexByRegister(_) => "IncrementIndexByRegister",
+        GetFontCharacter(_) => "GetFontCharacter",
+        GetLargeFontCharacter(_) => "GetLargeFontCharacter",
+        StoreBinCodedDec(_) => "StoreBinCodedDec",
+        DumpRegisters(_) => "DumpRegisters",
+        LoadRegisters(_) => "LoadRegisters",
+    }
+}
+
+/// Returns the entries of the map with the most cycles first.
+fn sort_by_cycles<K: Copy + Ord, V: Copy, F: Fn(&V) -> u64>(
+    map: &BTreeMap<K, V>,
+    get_cycles: F,
+) -> Vec<(K, V)> {
+    let mut entries: Vec<(K, V)> = map.iter().map(|(k, v)| (*k, *v)).collect();
+    entries.sort_by_key(|(key, value)| (std::cmp::Reverse(get_cycles(value)), *key));
+    entries
+}
+
+#[cfg(test)]
+fn create_test_profile() -> Profiler {
+    use crate::instruction::Register;
+
+    let mut profiler = Profiler::default();
+    profiler.record(0x0200, &Instruction::Call(0x0300), 10);
+    profiler.record(0x0300, &Instruction::SetRegister(Register::V0, 1), 5);
+    profiler.record(0x0302, &Instruction::Call(0x0400), 10);
+    profiler.record(0x0400, &Instruction::Return(), 8);
+    profiler.record(0x0304, &Instruction::Return(), 8);
+    profiler.record(0x0202, &Instruction::Jump(0x0200), 12);
+    profiler
+}
+
+#[test]
+fn profiler_record() {
+    let profiler = create_test_profile();
+
+    assert_eq!(
+        Some(&ExecutionStats {
+            count: 1,
+            cycles: 10
+        }),
+        profiler.get_address_stats().get(&0x0200)
+    );
+    assert_eq!(
+        Some(&ExecutionStats {
+            count: 2,
+            cycles: 16
+        }),
+        profiler.get_instruction_stats().get("Return")
+    );
+    assert_eq!(
+        Some(&SubroutineStats {
+            calls: 1,
+            total_cycles: 31,
+            self_cycles: 23
+        }),
+        profiler.get_subroutine_stats().get(&0x0300)
+    );
+    assert_eq!(
+        Some(&SubroutineStats {
+            calls: 1,
+            total_cycles: 8,
+            self_cycles: 8
+        }),
+        profiler.get_subroutine_stats().get(&0x0400)
+    );
+}
+
+#[test]
+fn profiler_to_folded_stacks() {
+    let profiler = create_test_profile();
+    let symbols = SymbolMap::parse("label 0x0200 main\nlabel 0x0300 update").unwrap();
+
+    let expected = "main 22\nmain;update 23\nmain;update;update+0x100 8\n";
+    assert_eq!(expected, profiler.to_folded_stacks(&symbols));
+}
@@ -0,0 +1,95 @@
+//! Behaviors of instructions that differ between the platforms that CHIP-8 programs were written
+//! for.
+//!
+//! The names of the quirks follow the ones used by the CHIP-8 database
+//! (https://github.com/chip-8/chip-8-database).
+
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VX in place instead of shifting VY and storing the result in VX.
+    pub shift: bool,
+    /// FX55 and FX65 increment I by X instead of X + 1.
+    pub memory_increment_by_x: bool,
+    /// FX55 and FX65 leave I unchanged.
+    pub memory_leave_i_unchanged: bool,
+    /// Sprites drawn past the edge of the screen wrap around to the other side instead of being
+    /// clipped.
+    pub wrap: bool,
+    /// BNNN jumps to XNN + VX instead of NNN + V0.
+    pub jump: bool,
+    /// DXYN waits for the vertical blank interrupt before drawing.
+    pub vblank: bool,
+    /// 8XY1, 8XY2, and 8XY3 reset VF to 0.
+    pub logic: bool,
+    /// FX1E sets VF to 1 when I goes past the end of memory and to 0 otherwise, as the Amiga
+    /// interpreter did. Not part of the CHIP-8 database, so it is off unless given.
+    #[serde(default)]
+    pub index_overflow: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+            index_overflow: false,
+        }
+    }
+}
+
+/// A partial set of quirks, used to adjust the default quirks of a platform for a specific ROM.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuirkOverrides {
+    pub shift: Option<bool>,
+    pub memory_increment_by_x: Option<bool>,
+    pub memory_leave_i_unchanged: Option<bool>,
+    pub wrap: Option<bool>,
+    pub jump: Option<bool>,
+    pub vblank: Option<bool>,
+    pub logic: Option<bool>,
+    pub index_overflow: Option<bool>,
+}
+
+impl Quirks {
+    pub fn with_overrides(&self, overrides: &QuirkOverrides) -> Quirks {
+        Quirks {
+            shift: overrides.shift.unwrap_or(self.shift),
+            memory_increment_by_x: overrides
+                .memory_increment_by_x
+                .unwrap_or(self.memory_increment_by_x),
+            memory_leave_i_unchanged: overrides
+                .memory_leave_i_unchanged
+                .unwrap_or(self.memory_leave_i_unchanged),
+            wrap: overrides.wrap.unwrap_or(self.wrap),
+            jump: overrides.jump.unwrap_or(self.jump),
+            vblank: overrides.vblank.unwrap_or(self.vblank),
+            logic: overrides.logic.unwrap_or(self.logic),
+            index_overflow: overrides.index_overflow.unwrap_or(self.index_overflow),
+        }
+    }
+}
+
+#[test]
+fn quirks_with_overrides() {
+    let overrides = QuirkOverrides {
+        shift: Some(false),
+        logic: Some(true),
+        ..QuirkOverrides::default()
+    };
+
+    let expected = Quirks {
+        shift: false,
+        logic: true,
+        ..Quirks::default()
+    };
+    assert_eq!(expected, Quirks::default().with_overrides(&overrides));
+}
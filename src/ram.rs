@@ -1,18 +1,49 @@
+use crate::instruction::Instruction;
+
 pub type Address = u16;
 
-#[derive(Debug, Eq, PartialEq)]
+pub const MEMORY_SIZE: usize = 4096;
+
+#[derive(Debug)]
 pub struct RAM {
-    memory: [u8; 4096],
+    memory: [u8; MEMORY_SIZE],
+    /// Instructions that have already been decoded, indexed by the address they start at. Entries
+    /// are cleared whenever either of their bytes is written to.
+    instruction_cache: Vec<Option<Instruction>>,
 }
 
 impl Default for RAM {
     fn default() -> Self {
-        RAM { memory: [0; 4096] }
+        RAM {
+            memory: [0; MEMORY_SIZE],
+            instruction_cache: vec![None; MEMORY_SIZE],
+        }
+    }
+}
+
+// The instruction cache is left out so that RAMs with the same contents compare as equal
+// regardless of which instructions have been run.
+impl PartialEq for RAM {
+    fn eq(&self, other: &Self) -> bool {
+        self.memory[..] == other.memory[..]
     }
 }
 
+impl Eq for RAM {}
+
 impl RAM {
     pub fn write_bytes(&mut self, address: Address, bytes: &[u8]) -> Result<(), String> {
+        // Check the whole range up front so that a failed write leaves memory untouched
+        let end_address = address as usize + bytes.len();
+        if end_address > MEMORY_SIZE {
+            return Err(format!(
+                "Write of {} bytes at 0x{:x} goes past the end of memory (0x{:x})",
+                bytes.len(),
+                address,
+                MEMORY_SIZE
+            ));
+        }
+
         for (i, byte) in bytes.iter().enumerate() {
             let cur_address = address + (i as u16);
             self.write_byte(cur_address, *byte)?
@@ -22,16 +53,23 @@ impl RAM {
     }
 
     pub fn write_byte(&mut self, address: Address, byte: u8) -> Result<(), String> {
-        if address >= 4096 {
+        if address as usize >= MEMORY_SIZE {
             return Err(format!("Write at invalid memory address: 0x{:x}", address));
         }
 
         self.memory[address as usize] = byte;
+
+        // Any instruction that includes this byte needs to be decoded again
+        self.instruction_cache[address as usize] = None;
+        if address > 0 {
+            self.instruction_cache[address as usize - 1] = None;
+        }
+
         Ok(())
     }
 
     pub fn read_byte(&self, address: Address) -> Result<u8, String> {
-        if address >= 4096 {
+        if address as usize >= MEMORY_SIZE {
             return Err(format!("Read at invalid memory address: 0x{:x}", address));
         }
 
@@ -40,17 +78,34 @@ impl RAM {
 
     pub fn read_u16(&self, address: Address) -> Result<u16, String> {
         let first_byte = self.read_byte(address)? as u16;
-        let second_byte = self.read_byte(address + 1)? as u16;
+        let second_byte = self.read_byte(address.wrapping_add(1))? as u16;
 
         Ok((first_byte << 8) | second_byte)
     }
 
+    /// Returns the instruction starting at the given address, only decoding it if it has not been
+    /// decoded since the last write to its bytes.
+    pub fn read_instruction(&mut self, address: Address) -> Result<Instruction, String> {
+        if let Some(Some(instruction)) = self.instruction_cache.get(address as usize) {
+            return Ok(instruction.clone());
+        }
+
+        let instruction = Instruction::from_u16(self.read_u16(address)?)?;
+        self.instruction_cache[address as usize] = Some(instruction.clone());
+
+        Ok(instruction)
+    }
+
+    pub fn write_u16(&mut self, address: Address, value: u16) -> Result<(), String> {
+        self.write_bytes(address, &value.to_be_bytes())
+    }
+
     pub fn read_sprite(&self, address: Address, height: u8) -> Result<Vec<u8>, String> {
         // Note: Sprite width is always 8 pixels and data is encoded as each byte is a row of the
         // sprite with 0=transparent and 1=filled.
         let mut sprite_bytes = vec![];
         for i in 0..height {
-            sprite_bytes.push(self.read_byte(address + i as u16)?);
+            sprite_bytes.push(self.read_byte(address.wrapping_add(i as u16))?);
         }
 
         Ok(sprite_bytes)
@@ -92,6 +147,18 @@ fn ram_write_byte_invalid_memory_address() {
     assert_eq!(expected, ram.write_byte(0x1000, 0xFF));
 }
 
+#[test]
+fn ram_write_bytes_past_end_leaves_memory_unchanged() {
+    let mut ram = RAM::default();
+
+    let expected =
+        Err("Write of 3 bytes at 0xffe goes past the end of memory (0x1000)".to_string());
+    assert_eq!(expected, ram.write_bytes(0x0FFE, &[0x01, 0x02, 0x03]));
+
+    assert_eq!(Ok(0x00), ram.read_byte(0x0FFE));
+    assert_eq!(Ok(0x00), ram.read_byte(0x0FFF));
+}
+
 #[test]
 fn ram_read_u16() {
     let mut ram = RAM::default();
@@ -101,3 +168,18 @@ fn ram_read_u16() {
 
     assert_eq!(Ok(0x1234), ram.read_u16(0x0000));
 }
+
+#[test]
+fn ram_read_instruction_after_write() {
+    let mut ram = RAM::default();
+
+    assert_eq!(Ok(()), ram.write_bytes(0x0200, &[0x00, 0xE0]));
+    assert_eq!(
+        Ok(Instruction::ClearDisplay()),
+        ram.read_instruction(0x0200)
+    );
+
+    // Overwriting the second byte of a cached instruction should cause it to be decoded again
+    assert_eq!(Ok(()), ram.write_byte(0x0201, 0xEE));
+    assert_eq!(Ok(Instruction::Return()), ram.read_instruction(0x0200));
+}
@@ -0,0 +1,236 @@
+//! Helpers for working with ROM files.
+
+extern crate sha1;
+
+use std::fmt;
+
+use crate::bit_operations;
+use crate::instruction::{Instruction, INSTRUCTION_SIZE_BYTES};
+use crate::ram::{Address, MEMORY_SIZE};
+
+/// Returns the SHA-1 hash of the given ROM as a lowercase hex string.
+///
+/// ```rust
+/// # use chip8_interpreter::rom::sha1_hex;
+/// assert_eq!("a9993e364706816aba3e25717850c26c9cd0d89d", sha1_hex(b"abc"));
+/// ```
+pub fn sha1_hex(rom: &[u8]) -> String {
+    sha1::Sha1::from(rom).digest().to_string()
+}
+
+/// Returns the size of the largest ROM that the given platform (as named in the ROM database)
+/// has room in memory for when loaded at the given address.
+pub fn max_rom_size(platform_id: &str, load_address: Address) -> usize {
+    let end_address = match platform_id {
+        // The COSMAC VIP reserves 0xEA0-0xFFF for the stack, interpreter work area, and display
+        "originalChip8" | "hybridVIP" => 0x0EA0,
+        // XO-CHIP has 64KB of memory, but only the first 4KB are emulated
+        _ => MEMORY_SIZE,
+    };
+
+    end_address.saturating_sub(load_address as usize)
+}
+
+/// Returns the address of the byte at the given offset into a ROM loaded at the given address, or
+/// `None` if it falls past the end of the address space.
+pub fn get_rom_address(load_address: Address, offset: usize) -> Option<Address> {
+    let address = load_address as usize + offset;
+    if address <= Address::MAX as usize {
+        Some(address as Address)
+    } else {
+        None
+    }
+}
+
+/// Checks that the given ROM can be loaded, returning warnings about any suspicious properties of
+/// it.
+pub fn validate(rom: &[u8], max_size: usize) -> Result<Vec<String>, String> {
+    if rom.is_empty() {
+        return Err("ROM is empty".to_string());
+    }
+
+    if rom.len() > max_size {
+        return Err(format!(
+            "ROM is too large: {} bytes (max {} bytes)",
+            rom.len(),
+            max_size
+        ));
+    }
+
+    let mut warnings = vec![];
+    if !rom.len().is_multiple_of(2) {
+        warnings.push(format!(
+            "ROM has an odd size ({} bytes), so its last byte is not a full instruction",
+            rom.len()
+        ));
+    }
+
+    Ok(warnings)
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum DetectedPlatform {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl fmt::Display for DetectedPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DetectedPlatform::Chip8 => "CHIP-8",
+            DetectedPlatform::SuperChip => "SUPER-CHIP",
+            DetectedPlatform::XoChip => "XO-CHIP",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// A summary of the contents of a ROM, found by doing a linear sweep over its instructions.
+///
+/// Since the sweep can't tell code apart from data, sprite data and other non-instruction bytes
+/// will show up as undecodable words or even as platform specific instructions.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RomInfo {
+    pub size: usize,
+    pub sha1: String,
+    pub detected_platform: DetectedPlatform,
+    /// Instructions that are only available on SUPER-CHIP or XO-CHIP.
+    pub platform_instructions: Vec<(Address, u16, DetectedPlatform)>,
+    /// Words that are not recognized as instructions by this interpreter.
+    pub undecodable_words: Vec<(Address, u16, String)>,
+}
+
+impl RomInfo {
+    pub fn analyze(rom: &[u8], load_address: Address) -> RomInfo {
+        let mut platform_instructions = vec![];
+        let mut undecodable_words = vec![];
+
+        for (i, word) in rom
+            .chunks_exact(INSTRUCTION_SIZE_BYTES as usize)
+            .enumerate()
+        {
+            let address = match get_rom_address(load_address, i * word.len()) {
+                Some(address) => address,
+                None => break,
+            };
+            let bytes = ((word[0] as u16) << 8) | word[1] as u16;
+
+            if let Some(platform) = get_instruction_platform(bytes) {
+                platform_instructions.push((address, bytes, platform));
+            }
+
+            if let Err(message) = Instruction::from_u16(bytes) {
+                undecodable_words.push((address, bytes, message));
+            }
+        }
+
+        let detected_platform = platform_instructions
+            .iter()
+            .map(|(_, _, platform)| *platform)
+            .max()
+            .unwrap_or(DetectedPlatform::Chip8);
+
+        RomInfo {
+            size: rom.len(),
+            sha1: sha1_hex(rom),
+            detected_platform,
+            platform_instructions,
+            undecodable_words,
+        }
+    }
+}
+
+/// Returns the platform that introduced the given instruction, if it is not part of the original
+/// CHIP-8 instruction set.
+fn get_instruction_platform(bytes: u16) -> Option<DetectedPlatform> {
+    use DetectedPlatform::*;
+
+    match bit_operations::break_into_nibbles(bytes) {
+        (0x0, 0x0, 0xC, _) => Some(SuperChip),   // 00CN scroll down
+        (0x0, 0x0, 0xF, 0xB) => Some(SuperChip), // 00FB scroll right
+        (0x0, 0x0, 0xF, 0xC) => Some(SuperChip), // 00FC scroll left
+        (0x0, 0x0, 0xF, 0xD) => Some(SuperChip), // 00FD exit
+        (0x0, 0x0, 0xF, 0xE) => Some(SuperChip), // 00FE low resolution
+        (0x0, 0x0, 0xF, 0xF) => Some(SuperChip), // 00FF high resolution
+        (0xD, _, _, 0x0) => Some(SuperChip),     // DXY0 16x16 sprite
+        (0xF, _, 0x3, 0x0) => Some(SuperChip),   // FX30 large font character
+        (0xF, _, 0x7, 0x5) => Some(SuperChip),   // FX75 save flags
+        (0xF, _, 0x8, 0x5) => Some(SuperChip),   // FX85 load flags
+        (0x0, 0x0, 0xD, _) => Some(XoChip),      // 00DN scroll up
+        (0x5, _, _, 0x2) => Some(XoChip),        // 5XY2 save register range
+        (0x5, _, _, 0x3) => Some(XoChip),        // 5XY3 load register range
+        (0xF, 0x0, 0x0, 0x0) => Some(XoChip),    // F000 NNNN long index
+        (0xF, _, 0x0, 0x1) => Some(XoChip),      // FN01 select plane
+        (0xF, 0x0, 0x0, 0x2) => Some(XoChip),    // F002 load audio pattern
+        (0xF, _, 0x3, 0xA) => Some(XoChip),      // FX3A set pitch
+        _ => None,
+    }
+}
+
+#[test]
+fn rom_max_rom_size() {
+    assert_eq!(3232, max_rom_size("originalChip8", 0x0200));
+    assert_eq!(3584, max_rom_size("superchip", 0x0200));
+    assert_eq!(2560, max_rom_size("superchip", 0x0600));
+    assert_eq!(3584, max_rom_size("xochip", 0x0200));
+}
+
+#[test]
+fn rom_get_rom_address() {
+    assert_eq!(Some(0x0202), get_rom_address(0x0200, 2));
+    assert_eq!(Some(0xFFFF), get_rom_address(0xFFFE, 1));
+    assert_eq!(None, get_rom_address(0xFFFE, 2));
+}
+
+#[test]
+fn rom_validate() {
+    assert_eq!(Err("ROM is empty".to_string()), validate(&[], 10));
+    assert_eq!(
+        Err("ROM is too large: 4 bytes (max 2 bytes)".to_string()),
+        validate(&[0x00, 0xE0, 0x00, 0xE0], 2)
+    );
+    assert_eq!(Ok(vec![]), validate(&[0x00, 0xE0], 2));
+    assert_eq!(1, validate(&[0x00, 0xE0, 0x00], 10).unwrap().len());
+}
+
+#[test]
+fn rom_info_analyze() {
+    let rom = [
+        0x00, 0xE0, // Clear display
+        0x00, 0xFF, // SUPER-CHIP high resolution
+        0xF1, 0x01, // XO-CHIP select plane
+        0x12, 0x00, // Jump
+    ];
+
+    let info = RomInfo::analyze(&rom, 0x0200);
+
+    assert_eq!(8, info.size);
+    assert_eq!(DetectedPlatform::XoChip, info.detected_platform);
+    assert_eq!(
+        vec![
+            (0x0202, 0x00FF, DetectedPlatform::SuperChip),
+            (0x0204, 0xF101, DetectedPlatform::XoChip),
+        ],
+        info.platform_instructions
+    );
+    assert_eq!(
+        vec![0x0202, 0x0204],
+        info.undecodable_words
+            .iter()
+            .map(|(address, _, _)| *address)
+            .collect::<Vec<Address>>()
+    );
+
+    // ROMs too large to fit in the address space are analyzed up to its end
+    let rom: Vec<u8> = [0xF1, 0x01].iter().cycle().take(0x10000).cloned().collect();
+    let info = RomInfo::analyze(&rom, 0x0200);
+    assert_eq!(0x10000, info.size);
+    assert_eq!(
+        Some(0xFFFE),
+        info.platform_instructions
+            .last()
+            .map(|(address, _, _)| *address)
+    );
+}
@@ -1,7 +1,15 @@
-use crate::bit_operations;
+use crate::views::Palette;
 
 const SPRITE_WIDTH: usize = 8;
 
+/// The widest and tallest screen supported, which is the SUPER-CHIP high resolution mode.
+const MAX_WIDTH: usize = 128;
+const MAX_HEIGHT: usize = 64;
+
+/// A row of pixels packed into bits, with the leftmost pixel in the most significant bit. Bits
+/// past the width of the screen are always unset.
+type Row = u128;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Position {
     x: u8,
@@ -13,12 +21,12 @@ impl Position {
         Position { x, y }
     }
 
-    fn shifted(&self, x_delta: u8, y_delta: u8) -> Position {
-        // TODO: do we wrap if we go over the edge?
-        let new_x = self.x + x_delta;
-        let new_y = self.y + y_delta;
+    pub fn x(&self) -> u8 {
+        self.x
+    }
 
-        Position::new(new_x, new_y)
+    pub fn y(&self) -> u8 {
+        self.y
     }
 }
 
@@ -34,87 +42,101 @@ impl Default for Pixel {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// What happens to the parts of a sprite that go past the edge of the screen.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpriteEdge {
+    /// The parts past the edge are not drawn.
+    Clip,
+    /// The parts past the edge are drawn on the opposite side of the screen.
+    Wrap,
+}
+
+#[derive(Debug)]
 pub struct Screen {
-    // TODO: Make this private and require accessor methods to view its contents
-    pub pixels: [[Pixel; 64]; 32],
+    width: u8,
+    height: u8,
+    rows: [Row; MAX_HEIGHT],
+    /// Bitmask of the rows that have changed since the last call to `clear_dirty_rows`, with row
+    /// y in bit y.
+    dirty_rows: u64,
 }
 
+// Dirty rows are left out since they only track what views have already drawn.
+impl PartialEq for Screen {
+    fn eq(&self, other: &Self) -> bool {
+        self.width == other.width && self.height == other.height && self.rows == other.rows
+    }
+}
+
+impl Eq for Screen {}
+
 impl Screen {
     pub fn clear(&mut self) {
-        for row in self.pixels.iter_mut() {
-            row.fill(Pixel::Off);
-        }
+        self.rows.fill(0);
+        self.mark_all_rows_dirty();
     }
 
+    /// Draws the given sprite by XORing it onto the screen.
+    ///
+    /// The starting position always wraps around the screen, so (66, 1) draws at the same place
+    /// as (2, 1). Only the parts of the sprite that then go past the edge are affected by `edge`.
     pub fn draw_sprite(
         &mut self,
         position: &Position,
         bytes: &[u8],
+        edge: SpriteEdge,
     ) -> Result<AnyPixelsUnset, String> {
-        let height = bytes.len() as u8;
+        let row_mask = self.get_row_mask();
+        let start_x = (position.x % self.width) as usize;
+        let start_y = (position.y % self.height) as usize;
 
         let mut any_unset = AnyPixelsUnset::No;
-        for yi in 0..height {
-            for xi in 0..8 {
-                let offset = Position::new(xi, yi);
-                let new_position = position.shifted(xi, yi);
-
-                if self.validate_position(&new_position).is_ok() {
-                    let sprite_pixel = self.get_sprite_pixel(bytes, &offset)?;
-
-                    if sprite_pixel == Pixel::On {
-                        let old_value = self.get_value(&new_position)?;
-
-                        match old_value {
-                            Pixel::Off => self.set_value(&new_position, Pixel::On)?,
-                            Pixel::On => {
-                                self.set_value(&new_position, Pixel::Off)?;
-                                any_unset = AnyPixelsUnset::Yes;
-                            }
-                        }
-                    }
+        for (yi, byte) in bytes.iter().enumerate() {
+            let mut y = start_y + yi;
+            if y >= self.height as usize {
+                match edge {
+                    SpriteEdge::Clip => break,
+                    SpriteEdge::Wrap => y %= self.height as usize,
                 }
             }
-        }
 
-        Ok(any_unset)
-    }
+            // Line the sprite row up with the leftmost pixel, then move it over to its x position.
+            // Anything that ends up past the right edge of the screen is clipped off by the mask.
+            let aligned_row = (*byte as Row) << (MAX_WIDTH - SPRITE_WIDTH);
+            let mut sprite_row = (aligned_row >> start_x) & row_mask;
 
-    fn get_sprite_pixel(&self, bytes: &[u8], position: &Position) -> Result<Pixel, String> {
-        if position.x >= SPRITE_WIDTH as u8 {
-            return Err(format!(
-                "x offset into sprite is too large: {:?} (Sprite width: {})",
-                position, SPRITE_WIDTH,
-            ));
-        }
-        if position.y >= bytes.len() as u8 {
-            return Err(format!(
-                "y offset into sprite is too large: {:?} (Sprite height: {})",
-                position,
-                bytes.len(),
-            ));
-        }
+            if edge == SpriteEdge::Wrap {
+                // Shifting the other way moves the pixels that went past the right edge over to
+                // the left edge, while the ones that were on screen get shifted out
+                let wrapped_row = aligned_row
+                    .checked_shl((self.width as usize - start_x) as u32)
+                    .unwrap_or(0);
+                sprite_row |= wrapped_row & row_mask;
+            }
 
-        // Convert from a left=0 index to a right=0 index so we can use a classic mask+shift
-        // algorithm to get the correct bit
-        let bit_reverse_index = 7 - position.x;
+            let row = &mut self.rows[y];
+            if *row & sprite_row != 0 {
+                any_unset = AnyPixelsUnset::Yes;
+            }
+            *row ^= sprite_row;
 
-        match bit_operations::get_nth_bit(bit_reverse_index, bytes[position.y as usize])? {
-            true => Ok(Pixel::On),
-            false => Ok(Pixel::Off),
+            if sprite_row != 0 {
+                self.dirty_rows |= 1 << y;
+            }
         }
+
+        Ok(any_unset)
     }
 
     fn validate_position(&self, position: &Position) -> Result<(), String> {
-        if position.x > (self.get_width() - 1) {
+        if position.x > (self.width() - 1) {
             return Err(format!(
                 "Screen position x value is too large: {:?}",
                 position
             ));
         }
 
-        if position.y > (self.get_height() - 1) {
+        if position.y > (self.height() - 1) {
             return Err(format!(
                 "Screen position y value is too large: {:?}",
                 position
@@ -126,30 +148,111 @@ impl Screen {
     pub fn get_value(&self, position: &Position) -> Result<Pixel, String> {
         self.validate_position(position)?;
 
-        Ok(self.pixels[position.y as usize][position.x as usize])
+        let row = self.rows[position.y as usize];
+        match row & Screen::get_pixel_bit(position.x) {
+            0 => Ok(Pixel::Off),
+            _ => Ok(Pixel::On),
+        }
     }
 
     pub fn set_value(&mut self, position: &Position, value: Pixel) -> Result<(), String> {
         self.validate_position(position)?;
 
-        self.pixels[position.y as usize][position.x as usize] = value;
+        let row = &mut self.rows[position.y as usize];
+        match value {
+            Pixel::On => *row |= Screen::get_pixel_bit(position.x),
+            Pixel::Off => *row &= !Screen::get_pixel_bit(position.x),
+        }
+        self.dirty_rows |= 1 << position.y;
 
         Ok(())
     }
 
-    pub fn get_width(&self) -> u8 {
-        64
+    /// Returns the width of the screen in the current display mode.
+    pub fn width(&self) -> u8 {
+        self.width
+    }
+
+    /// Returns the height of the screen in the current display mode.
+    pub fn height(&self) -> u8 {
+        self.height
+    }
+
+    /// Returns the pixels of the given row, from left to right.
+    pub fn row(&self, y: u8) -> impl Iterator<Item = Pixel> + '_ {
+        let row = self.rows[y as usize];
+
+        (0..self.width).map(move |x| match row & Screen::get_pixel_bit(x) {
+            0 => Pixel::Off,
+            _ => Pixel::On,
+        })
+    }
+
+    /// Returns the pixels of each row, from top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = impl Iterator<Item = Pixel> + '_> + '_ {
+        (0..self.height).map(move |y| self.row(y))
+    }
+
+    /// Returns every pixel along with its position, going left to right and then top to bottom.
+    pub fn pixels(&self) -> impl Iterator<Item = (Position, Pixel)> + '_ {
+        (0..self.height).flat_map(move |y| {
+            self.row(y)
+                .enumerate()
+                .map(move |(x, pixel)| (Position::new(x as u8, y), pixel))
+        })
+    }
+
+    /// Returns the screen as RGBA bytes, going left to right and then top to bottom.
+    pub fn to_rgba(&self, palette: &Palette) -> Vec<u8> {
+        let to_bytes = |color: u32| {
+            let [_, r, g, b] = color.to_be_bytes();
+            [r, g, b, 0xFF]
+        };
+        let on = to_bytes(palette.on);
+        let off = to_bytes(palette.off);
+
+        let mut bytes = Vec::with_capacity(self.width as usize * self.height as usize * 4);
+        for (_, pixel) in self.pixels() {
+            match pixel {
+                Pixel::On => bytes.extend_from_slice(&on),
+                Pixel::Off => bytes.extend_from_slice(&off),
+            }
+        }
+
+        bytes
+    }
+
+    /// Returns the rows that have changed since dirty rows were last cleared, from top to bottom.
+    pub fn dirty_rows(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.height).filter(move |y| self.dirty_rows & (1 << y) != 0)
+    }
+
+    /// Marks all rows as up to date. Should be called once the changes have been displayed.
+    pub fn clear_dirty_rows(&mut self) {
+        self.dirty_rows = 0;
     }
 
-    pub fn get_height(&self) -> u8 {
-        32
+    pub fn mark_all_rows_dirty(&mut self) {
+        self.dirty_rows = !0;
+    }
+
+    fn get_pixel_bit(x: u8) -> Row {
+        1 << (MAX_WIDTH - 1 - x as usize)
+    }
+
+    /// Returns a row with all of the bits that are on the screen set.
+    fn get_row_mask(&self) -> Row {
+        !0 << (MAX_WIDTH - self.width as usize)
     }
 }
 
 impl Default for Screen {
     fn default() -> Self {
         Screen {
-            pixels: [[Pixel::default(); 64]; 32],
+            width: 64,
+            height: 32,
+            rows: [0; MAX_HEIGHT],
+            dirty_rows: !0,
         }
     }
 }
@@ -158,3 +261,134 @@ pub enum AnyPixelsUnset {
     Yes,
     No,
 }
+
+#[test]
+fn screen_set_and_get_value() {
+    let mut screen = Screen::default();
+
+    assert_eq!(Ok(Pixel::Off), screen.get_value(&Position::new(63, 31)));
+    assert_eq!(Ok(()), screen.set_value(&Position::new(63, 31), Pixel::On));
+    assert_eq!(Ok(Pixel::On), screen.get_value(&Position::new(63, 31)));
+    assert_eq!(Ok(Pixel::Off), screen.get_value(&Position::new(62, 31)));
+
+    assert!(screen.get_value(&Position::new(64, 0)).is_err());
+    assert!(screen.set_value(&Position::new(0, 32), Pixel::On).is_err());
+}
+
+#[test]
+fn screen_draw_sprite_collision() {
+    let mut screen = Screen::default();
+    let position = Position::new(2, 1);
+
+    let result = screen.draw_sprite(&position, &[0b10000001], SpriteEdge::Clip);
+    assert!(matches!(result, Ok(AnyPixelsUnset::No)));
+    assert_eq!(Ok(Pixel::On), screen.get_value(&Position::new(2, 1)));
+    assert_eq!(Ok(Pixel::On), screen.get_value(&Position::new(9, 1)));
+
+    // Drawing the same sprite again erases it
+    let result = screen.draw_sprite(&position, &[0b10000001], SpriteEdge::Clip);
+    assert!(matches!(result, Ok(AnyPixelsUnset::Yes)));
+    assert_eq!(Ok(Pixel::Off), screen.get_value(&Position::new(2, 1)));
+    assert_eq!(Ok(Pixel::Off), screen.get_value(&Position::new(9, 1)));
+}
+
+#[test]
+fn screen_draw_sprite_clips_at_edges() {
+    let mut screen = Screen::default();
+
+    let result = screen.draw_sprite(&Position::new(60, 31), &[0xFF, 0xFF], SpriteEdge::Clip);
+    assert!(matches!(result, Ok(AnyPixelsUnset::No)));
+
+    for x in 0..64 {
+        let expected = if x >= 60 { Pixel::On } else { Pixel::Off };
+        assert_eq!(Ok(expected), screen.get_value(&Position::new(x, 31)));
+        assert_eq!(Ok(Pixel::Off), screen.get_value(&Position::new(x, 0)));
+    }
+}
+
+#[test]
+fn screen_draw_sprite_wraps_at_edges() {
+    let mut screen = Screen::default();
+
+    let result = screen.draw_sprite(&Position::new(60, 31), &[0xFF, 0xFF], SpriteEdge::Wrap);
+    assert!(matches!(result, Ok(AnyPixelsUnset::No)));
+
+    for x in 0..64 {
+        let expected = if !(4..60).contains(&x) {
+            Pixel::On
+        } else {
+            Pixel::Off
+        };
+        assert_eq!(Ok(expected), screen.get_value(&Position::new(x, 31)));
+        assert_eq!(Ok(expected), screen.get_value(&Position::new(x, 0)));
+        assert_eq!(Ok(Pixel::Off), screen.get_value(&Position::new(x, 1)));
+    }
+}
+
+#[test]
+fn screen_draw_sprite_start_position_wraps() {
+    for edge in [SpriteEdge::Clip, SpriteEdge::Wrap].iter() {
+        let mut screen = Screen::default();
+
+        // 255 and 66 are past the edges, so the sprite starts at (63, 2)
+        let result = screen.draw_sprite(&Position::new(255, 66), &[0xC0], *edge);
+        assert!(result.is_ok());
+        assert_eq!(Ok(Pixel::On), screen.get_value(&Position::new(63, 2)));
+
+        let wrapped_pixel = screen.get_value(&Position::new(0, 2));
+        match edge {
+            SpriteEdge::Clip => assert_eq!(Ok(Pixel::Off), wrapped_pixel),
+            SpriteEdge::Wrap => assert_eq!(Ok(Pixel::On), wrapped_pixel),
+        }
+    }
+}
+
+#[test]
+fn screen_pixel_iterators() {
+    let mut screen = Screen::default();
+    assert_eq!(Ok(()), screen.set_value(&Position::new(1, 2), Pixel::On));
+
+    assert_eq!(32, screen.rows().count());
+    assert_eq!(
+        vec![Pixel::Off, Pixel::On, Pixel::Off],
+        screen.row(2).take(3).collect::<Vec<Pixel>>()
+    );
+
+    let on_positions: Vec<Position> = screen
+        .pixels()
+        .filter(|(_, pixel)| *pixel == Pixel::On)
+        .map(|(position, _)| position)
+        .collect();
+    assert_eq!(vec![Position::new(1, 2)], on_positions);
+}
+
+#[test]
+fn screen_to_rgba() {
+    let mut screen = Screen::default();
+    assert_eq!(Ok(()), screen.set_value(&Position::new(1, 0), Pixel::On));
+
+    let palette = Palette {
+        on: 0x00FF8000,
+        off: 0x00000000,
+    };
+    let rgba = screen.to_rgba(&palette);
+
+    assert_eq!(64 * 32 * 4, rgba.len());
+    assert_eq!(
+        &[0x00, 0x00, 0x00, 0xFF, 0xFF, 0x80, 0x00, 0xFF],
+        &rgba[0..8]
+    );
+}
+
+#[test]
+fn screen_dirty_rows() {
+    let mut screen = Screen::default();
+    assert_eq!(32, screen.dirty_rows().count());
+
+    screen.clear_dirty_rows();
+    assert_eq!(0, screen.dirty_rows().count());
+
+    let result = screen.draw_sprite(&Position::new(0, 3), &[0xFF, 0x00, 0x81], SpriteEdge::Clip);
+    assert!(result.is_ok());
+    assert_eq!(vec![3, 5], screen.dirty_rows().collect::<Vec<u8>>());
+}
@@ -0,0 +1,204 @@
+//! Symbol files, which map the addresses in an assembled ROM back to the source it was assembled
+//! from.
+//!
+//! A symbol file is a plain text file that looks like the following:
+//!
+//! ```text
+//! label 0x0200 main
+//! line 0x0200 game.8o:12
+//! line 0x0202 game.8o:13
+//! label 0x02a2 draw_player
+//! ```
+//!
+//! Each `label` entry gives the address that a label in the source (such as `: draw_player` in
+//! Octo) refers to. Each `line` entry gives the address of an instruction and the file and line
+//! number of the source it was assembled from. Blank lines and lines starting with `#` are
+//! ignored.
+//!
+//! Addresses are shown relative to the closest label before them, such as `draw_player+0x4`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::ram::Address;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SymbolMap {
+    labels: BTreeMap<Address, String>,
+    source_locations: BTreeMap<Address, SourceLocation>,
+}
+
+impl SymbolMap {
+    pub fn parse(text: &str) -> Result<SymbolMap, String> {
+        let mut symbols = SymbolMap::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            symbols
+                .parse_entry(line)
+                .map_err(|e| format!("Invalid symbol file line {}: {}", i + 1, e))?;
+        }
+
+        Ok(symbols)
+    }
+
+    fn parse_entry(&mut self, entry: &str) -> Result<(), String> {
+        let parts: Vec<&str> = entry.split_whitespace().collect();
+        match parts.as_slice() {
+            ["label", address, name] => {
+                self.labels
+                    .insert(parse_address(address)?, name.to_string());
+                Ok(())
+            }
+            ["line", address, location] => {
+                let address = parse_address(address)?;
+                let (file, line) = match location.rfind(':') {
+                    Some(i) => (&location[..i], &location[i + 1..]),
+                    None => return Err(format!("Missing line number: {}", location)),
+                };
+                let line = line
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid line number: {}", line))?;
+
+                self.source_locations.insert(
+                    address,
+                    SourceLocation {
+                        file: file.to_string(),
+                        line,
+                    },
+                );
+                Ok(())
+            }
+            _ => Err(format!("Unrecognized entry: {}", entry)),
+        }
+    }
+
+    /// Returns the address of the label with the given name.
+    pub fn get_label_address(&self, name: &str) -> Option<Address> {
+        self.labels
+            .iter()
+            .find(|(_, label)| *label == name)
+            .map(|(address, _)| *address)
+    }
+
+    /// Returns the name of the label at exactly the given address.
+    pub fn get_label(&self, address: Address) -> Option<&str> {
+        self.labels.get(&address).map(|label| label.as_str())
+    }
+
+    /// Formats the given address relative to the closest label at or before it, falling back to
+    /// the plain address if there is no such label.
+    ///
+    /// ```rust
+    /// # use chip8_interpreter::symbols::SymbolMap;
+    /// let symbols = SymbolMap::parse("label 0x02a2 draw_player").unwrap();
+    ///
+    /// assert_eq!("draw_player", symbols.format_address(0x02A2));
+    /// assert_eq!("draw_player+0x4", symbols.format_address(0x02A6));
+    /// assert_eq!("0x0200", symbols.format_address(0x0200));
+    /// ```
+    pub fn format_address(&self, address: Address) -> String {
+        match self.labels.range(..=address).next_back() {
+            Some((label_address, label)) if *label_address == address => label.clone(),
+            Some((label_address, label)) => format!("{}+0x{:x}", label, address - label_address),
+            None => format!("0x{:04x}", address),
+        }
+    }
+
+    /// Returns the source location that the instruction at the given address was assembled from.
+    pub fn get_source_location(&self, address: Address) -> Option<&SourceLocation> {
+        self.source_locations.get(&address)
+    }
+
+    pub fn get_source_locations(&self) -> impl Iterator<Item = (&Address, &SourceLocation)> {
+        self.source_locations.iter()
+    }
+
+    /// Returns the address of the first instruction assembled from the given source line, or
+    /// from the closest line after it that has an instruction, along with that line.
+    ///
+    /// The given path only needs to end with the file name used in the symbol file, so absolute
+    /// paths from an editor match relative paths written by the assembler.
+    pub fn find_address(&self, path: &str, line: u32) -> Option<(Address, u32)> {
+        self.source_locations
+            .iter()
+            .filter(|(_, location)| Path::new(path).ends_with(&location.file))
+            .filter(|(_, location)| location.line >= line)
+            .min_by_key(|(address, location)| (location.line, **address))
+            .map(|(address, location)| (*address, location.line))
+    }
+}
+
+fn parse_address(value: &str) -> Result<Address, String> {
+    let digits = value.trim_start_matches("0x");
+    Address::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", value))
+}
+
+#[test]
+fn symbol_map_parse() {
+    let text = "# Symbols for game.8o\nline 0x0200 game.8o:12\n\nline 0x0202 src/game.8o:14\n";
+    let symbols = SymbolMap::parse(text).unwrap();
+
+    assert_eq!(
+        Some(&SourceLocation {
+            file: "game.8o".to_string(),
+            line: 12
+        }),
+        symbols.get_source_location(0x0200)
+    );
+    assert_eq!(None, symbols.get_source_location(0x0204));
+
+    assert_eq!(
+        Err("Invalid symbol file line 1: Invalid address: 0xZZ".to_string()),
+        SymbolMap::parse("line 0xZZ game.8o:1")
+    );
+    assert_eq!(
+        Err("Invalid symbol file line 1: Unrecognized entry: lines".to_string()),
+        SymbolMap::parse("lines")
+    );
+}
+
+#[test]
+fn symbol_map_labels() {
+    let text = "label 0x0200 main\nlabel 0x02a2 draw_player\n";
+    let symbols = SymbolMap::parse(text).unwrap();
+
+    assert_eq!(Some(0x02A2), symbols.get_label_address("draw_player"));
+    assert_eq!(None, symbols.get_label_address("draw_enemy"));
+
+    assert_eq!("main+0x2", symbols.format_address(0x0202));
+    assert_eq!("draw_player+0x10", symbols.format_address(0x02B2));
+    assert_eq!("0x01fe", symbols.format_address(0x01FE));
+    assert_eq!("0x0200", SymbolMap::default().format_address(0x0200));
+}
+
+#[test]
+fn symbol_map_find_address() {
+    let text = "line 0x0200 game.8o:12\nline 0x0202 game.8o:14\nline 0x0300 lib.8o:3\n";
+    let symbols = SymbolMap::parse(text).unwrap();
+
+    assert_eq!(
+        Some((0x0200, 12)),
+        symbols.find_address("/home/user/game.8o", 12)
+    );
+    assert_eq!(Some((0x0202, 14)), symbols.find_address("game.8o", 13));
+    assert_eq!(None, symbols.find_address("game.8o", 15));
+    assert_eq!(None, symbols.find_address("other.8o", 3));
+}
@@ -0,0 +1,106 @@
+//! A model of how long instructions take to run in the CHIP-8 interpreter of the COSMAC VIP.
+//!
+//! The VIP's CDP1802 runs at 1.76064 MHz, with each machine cycle taking 8 clock cycles. The
+//! display interrupt fires 60 times a second, and while the CDP1861 is drawing the screen the
+//! interrupt routine keeps the CPU busy, so only about half of each frame is left over for
+//! running CHIP-8 instructions. The interrupt routine is also what decrements the timers.
+//!
+//! The cycle counts are taken from published disassemblies of the VIP interpreter. Instructions
+//! whose real cost depends on their data (DXYN and FX33 most of all) use a typical cost instead,
+//! which is close enough for ROMs that rely on roughly how many instructions run per frame.
+
+use std::time::Duration;
+
+use crate::instruction::Instruction;
+
+pub const MACHINE_CYCLES_PER_SECOND: u64 = 1_760_640 / 8;
+pub const MACHINE_CYCLES_PER_FRAME: u64 = MACHINE_CYCLES_PER_SECOND / 60;
+
+/// Machine cycles taken up by the display interrupt at the start of each frame.
+pub const INTERRUPT_MACHINE_CYCLES: u64 = 1832;
+
+/// Returns the number of machine cycles the instruction takes to run, including fetching and
+/// decoding it. `skipped` tells whether a conditional skip instruction skipped the next
+/// instruction, which takes a little longer.
+pub fn get_instruction_cycles(instruction: &Instruction, skipped: bool) -> u64 {
+    use Instruction::*;
+
+    // Every instruction goes through the same fetch and dispatch code before running
+    const FETCH_CYCLES: u64 = 40;
+    let skip_cycles = match skipped {
+        true => 4,
+        false => 0,
+    };
+
+    let execute_cycles = match instruction {
+        ClearDisplay() => 3038,
+        Return() => 10,
+        Jump(_) => 12,
+        Call(_) => 26,
+        JumpIfEqValue(_, _) => 10 + skip_cycles,
+        JumpIfNotEqValue(_, _) => 10 + skip_cycles,
+        JumpIfRegistersEq(_, _) => 14 + skip_cycles,
+        SetRegister(_, _) => 6,
+        IncrementRegister(_, _) => 10,
+        CopyRegister(_, _) => 12,
+        BitwiseOr(_, _)
+        | BitwiseAnd(_, _)
+        | BitwiseXor(_, _)
+        | IncrementByRegister(_, _)
+        | DecrementByRegister(_, _)
+        | RightShift(_, _)
+        | DecrementByRegisterRev(_, _)
+        | LeftShift(_, _) => 44,
+        JumpIfRegistersNotEq(_, _) => 14 + skip_cycles,
+        SetIndexRegister(_) => 12,
+        JumpWithOffset(_) => 22,
+        SetRandomAnd(_, _) => 36,
+        DrawSprite(_, _, height) => 26 + 46 * *height as u64,
+        SkipIfNotPressed(_) => 14 + skip_cycles,
+        GetDelayTimer(_) => 10,
+        SetDelayTimer(_) => 10,
+        SetSoundTimer(_) => 10,
+        IncrementIndexByRegister(_) => 16,
+        GetFontCharacter(_) => 16,
+        GetLargeFontCharacter(_) => 16,
+        StoreBinCodedDec(_) => 152,
+        DumpRegisters(last_register) => 14 + 14 * (last_register.to_nibble() as u64 + 1),
+        LoadRegisters(last_register) => 14 + 14 * (last_register.to_nibble() as u64 + 1),
+    };
+
+    FETCH_CYCLES + execute_cycles
+}
+
+/// Returns how long the given number of machine cycles takes in real time.
+pub fn get_cycles_duration(cycles: u64) -> Duration {
+    Duration::from_nanos(cycles * 1_000_000_000 / MACHINE_CYCLES_PER_SECOND)
+}
+
+#[test]
+fn timing_get_instruction_cycles() {
+    use crate::instruction::Register;
+
+    assert_eq!(
+        52,
+        get_instruction_cycles(&Instruction::Jump(0x0200), false)
+    );
+    assert_eq!(
+        54,
+        get_instruction_cycles(&Instruction::JumpIfEqValue(Register::V0, 0x01), true)
+    );
+    assert_eq!(
+        250,
+        get_instruction_cycles(
+            &Instruction::DrawSprite(Register::V0, Register::V1, 4),
+            false
+        )
+    );
+}
+
+#[test]
+fn timing_get_cycles_duration() {
+    assert_eq!(
+        Duration::from_secs(1),
+        get_cycles_duration(MACHINE_CYCLES_PER_SECOND)
+    );
+}
@@ -2,7 +2,7 @@ extern crate minifb;
 
 use std::io::Write;
 
-use minifb::{Key, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
 
 use crate::screen::{Pixel, Screen};
 
@@ -27,45 +27,171 @@ impl Default for InputState {
     }
 }
 
+pub const NUM_KEYS: u8 = 16;
+
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct Inputs {
-    zero: InputState,
-    seven: InputState,
-    eight: InputState,
-    nine: InputState,
+    keys: [InputState; NUM_KEYS as usize],
 }
 
 impl Inputs {
-    fn set_input(&mut self, input: &InputKey, value: InputState) {
-        use InputKey::*;
-
-        match input {
-            Zero => self.zero = value,
-            Seven => self.seven = value,
-            Eight => self.eight = value,
-            Nine => self.nine = value,
+    pub fn set_input(&mut self, key_id: u8, value: InputState) -> Result<(), String> {
+        Inputs::validate_key_id(key_id)?;
+
+        self.keys[key_id as usize] = value;
+        Ok(())
+    }
+
+    pub fn get_input(&self, key_id: u8) -> Result<InputState, String> {
+        Inputs::validate_key_id(key_id)?;
+
+        Ok(self.keys[key_id as usize])
+    }
+
+    /// Packs the inputs into a bitmask where bit N is set if key N is pressed.
+    pub fn to_bits(&self) -> u16 {
+        let mut bits = 0;
+        for (key_id, state) in self.keys.iter().enumerate() {
+            if *state == InputState::Pressed {
+                bits |= 1 << key_id;
+            }
+        }
+
+        bits
+    }
+
+    /// Unpacks inputs from a bitmask created by `to_bits`.
+    pub fn from_bits(bits: u16) -> Inputs {
+        let mut inputs = Inputs::default();
+        for (key_id, state) in inputs.keys.iter_mut().enumerate() {
+            *state = InputState::from_bool(bits & (1 << key_id) != 0);
+        }
+
+        inputs
+    }
+
+    fn validate_key_id(key_id: u8) -> Result<(), String> {
+        if key_id >= NUM_KEYS {
+            return Err(format!("Unrecognized key id: 0x{:02x}", key_id));
+        }
+
+        Ok(())
+    }
+}
+
+/// A mapping from keyboard keys to the keys of the CHIP-8 hex keypad.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Keymap {
+    bindings: Vec<(Key, u8)>,
+}
+
+impl Default for Keymap {
+    /// Maps each hex digit key on the keyboard to the keypad key with the same value.
+    fn default() -> Self {
+        use Key::*;
+
+        let hex_keys = [
+            Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, A, B, C, D, E, F,
+        ];
+
+        Keymap {
+            bindings: hex_keys
+                .iter()
+                .enumerate()
+                .map(|(key_id, key)| (*key, key_id as u8))
+                .collect(),
+        }
+    }
+}
+
+impl Keymap {
+    pub fn bind(&mut self, key: Key, key_id: u8) -> Result<(), String> {
+        Inputs::validate_key_id(key_id)?;
+
+        self.bindings.retain(|(k, _)| *k != key);
+        self.bindings.push((key, key_id));
+        Ok(())
+    }
+
+    /// Binds a keypad key to the keyboard key used for a named role (ex. "up", "a",
+    /// "player2Left"), as used in the key bindings of the CHIP-8 database.
+    pub fn bind_named(&mut self, name: &str, key_id: u8) -> Result<(), String> {
+        let key = match name {
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            "a" => Key::Z,
+            "b" => Key::X,
+            "player2Up" => Key::I,
+            "player2Down" => Key::K,
+            "player2Left" => Key::J,
+            "player2Right" => Key::L,
+            "player2A" => Key::N,
+            "player2B" => Key::M,
+            _ => return Err(format!("Unrecognized key binding name: {}", name)),
+        };
+
+        self.bind(key, key_id)
+    }
+
+    fn get_inputs(&self, window: &Window) -> Inputs {
+        let mut inputs = Inputs::default();
+        for (key, key_id) in self.bindings.iter() {
+            if window.is_key_down(*key) {
+                inputs.keys[*key_id as usize] = InputState::Pressed;
+            }
         }
+
+        inputs
     }
 }
 
+/// The colors used to display pixels, in 0x00RRGGBB format.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum InputKey {
-    Zero,
-    Eight,
+pub struct Palette {
+    pub on: u32,
+    pub off: u32,
 }
 
-impl Inputs {
-    pub fn get_input(&self, key_id: u8) -> Result<InputState, String> {
-        match key_id {
-            0 => Ok(self.zero),
-            7 => Ok(self.seven),
-            8 => Ok(self.eight),
-            9 => Ok(self.nine),
-            _ => Err(format!("Unrecognized key id: 0x{:02x}", key_id)),
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            on: 0x00000000,
+            off: 0x00FFFFFF,
         }
     }
 }
 
+impl Palette {
+    /// Parses a color in "#RRGGBB" format.
+    ///
+    /// ```rust
+    /// # use chip8_interpreter::views::Palette;
+    /// assert_eq!(Ok(0x00FF8000), Palette::parse_color("#ff8000"));
+    /// ```
+    pub fn parse_color(color: &str) -> Result<u32, String> {
+        let hex = color.trim_start_matches('#');
+        if hex.len() != 6 {
+            return Err(format!("Invalid color: {}", color));
+        }
+
+        u32::from_str_radix(hex, 16).map_err(|_| format!("Invalid color: {}", color))
+    }
+}
+
+/// Actions on the interpreter itself that can be triggered from a view.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HotkeyAction {
+    /// Starts the program over from the beginning.
+    Reset,
+    /// Loads the ROM from disk again and starts it over.
+    Reload,
+}
+
+const RESET_KEY: Key = Key::F1;
+const RELOAD_KEY: Key = Key::F5;
+
 #[derive(Eq, PartialEq)]
 pub enum ViewState {
     Open,
@@ -89,9 +215,9 @@ impl<W: Write> CliView<W> {
     }
 
     fn display_screen(&mut self, screen: &Screen) {
-        for row in screen.pixels.iter() {
+        for row in screen.rows() {
             write!(self.output, "|");
-            for p in row.iter() {
+            for p in row {
                 match p {
                     Pixel::On => write!(self.output, "#"),
                     Pixel::Off => write!(self.output, " "),
@@ -121,12 +247,7 @@ impl<W: Write> View for CliView<W> {
         // TODO: look into
         // https://www.reddit.com/r/rust/comments/c8076q/check_if_a_key_is_pressed/
         // https://github.com/redox-os/termion/blob/master/examples/keys.rs
-        Ok(Inputs {
-            zero: InputState::NotPressed,
-            seven: InputState::NotPressed,
-            eight: InputState::NotPressed,
-            nine: InputState::NotPressed,
-        })
+        Ok(Inputs::default())
     }
 }
 
@@ -136,6 +257,12 @@ pub struct MiniFbView {
     height: usize,
     window_options: WindowOptions,
     window: Option<Window>,
+    keymap: Keymap,
+    palette: Palette,
+    buffer: Vec<u32>,
+    /// Whether the whole buffer needs to be redrawn, rather than only the rows of the screen that
+    /// have changed.
+    needs_full_redraw: bool,
 }
 
 impl MiniFbView {
@@ -151,40 +278,77 @@ impl MiniFbView {
             height,
             window_options,
             window: None,
+            keymap: Keymap::default(),
+            palette: Palette::default(),
+            buffer: vec![0; width * height],
+            needs_full_redraw: true,
         }
     }
 
-    fn update_display(&mut self, screen: &Screen) {
-        // 0x00RRGGBB
-        let white = 0x00FFFFFF;
-        let black = 0x00000000;
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
 
-        let mut buffer: Vec<u32> = vec![0; self.width * self.height];
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.needs_full_redraw = true;
+    }
 
-        for (i, b) in buffer.iter_mut().enumerate() {
-            let row = (i / (64 * 2)) as usize / 2;
-            let column = (i % (64 * 2)) as usize / 2;
+    /// Returns the action for the hotkey that was just pressed, if any.
+    pub fn get_hotkey_action(&self) -> Option<HotkeyAction> {
+        let window = self.window.as_ref()?;
 
-            //println!("{} => {}, {}", i, row, column);
+        if window.is_key_pressed(RESET_KEY, KeyRepeat::No) {
+            Some(HotkeyAction::Reset)
+        } else if window.is_key_pressed(RELOAD_KEY, KeyRepeat::No) {
+            Some(HotkeyAction::Reload)
+        } else {
+            None
+        }
+    }
 
-            *b = match screen.pixels[row][column] {
-                Pixel::On => black,
-                Pixel::Off => white,
-            };
+    fn update_display(&mut self, screen: &Screen) {
+        let rows: Vec<u8> = if self.needs_full_redraw {
+            (0..screen.height()).collect()
+        } else {
+            screen.dirty_rows().collect()
+        };
+        for y in rows {
+            self.draw_row(screen, y);
         }
+        self.needs_full_redraw = false;
 
         self.window
             .as_mut()
             .unwrap()
-            .update_with_buffer(&buffer, self.width, self.height)
+            .update_with_buffer(&self.buffer, self.width, self.height)
             .unwrap();
     }
+
+    /// Draws the given row of the screen into the buffer, scaled up to fit the window.
+    fn draw_row(&mut self, screen: &Screen, y: u8) {
+        let scale_x = self.width / screen.width() as usize;
+        let scale_y = self.height / screen.height() as usize;
+
+        let start = y as usize * scale_y * self.width;
+        let end = start + scale_y * self.width;
+        for line in self.buffer[start..end].chunks_exact_mut(self.width) {
+            for (x, pixel) in screen.row(y).enumerate() {
+                let color = match pixel {
+                    Pixel::On => self.palette.on,
+                    Pixel::Off => self.palette.off,
+                };
+                line[x * scale_x..(x + 1) * scale_x].fill(color);
+            }
+        }
+    }
 }
 
 impl View for MiniFbView {
     fn open(&mut self, screen: &Screen) {
         self.window =
             Some(Window::new(&self.name, self.width, self.height, self.window_options).unwrap());
+        self.needs_full_redraw = true;
     }
 
     fn close(&mut self) {
@@ -205,11 +369,6 @@ impl View for MiniFbView {
     }
 
     fn get_inputs(&mut self) -> Result<Inputs, String> {
-        Ok(Inputs {
-            zero: InputState::from_bool(self.window.as_ref().unwrap().is_key_down(Key::Key0)),
-            seven: InputState::from_bool(self.window.as_ref().unwrap().is_key_down(Key::Key7)),
-            eight: InputState::from_bool(self.window.as_ref().unwrap().is_key_down(Key::Key8)),
-            nine: InputState::from_bool(self.window.as_ref().unwrap().is_key_down(Key::Key9)),
-        })
+        Ok(self.keymap.get_inputs(self.window.as_ref().unwrap()))
     }
 }
@@ -0,0 +1,508 @@
+//! Reading of zip files, which ROMs are often distributed in.
+//!
+//! Only what is needed to get files out of typical archives is supported: files have to be stored
+//! or compressed with deflate, and encrypted and ZIP64 archives can't be read.
+
+use crate::patch::crc32;
+
+const LOCAL_FILE_SIGNATURE: &[u8] = b"PK\x03\x04";
+const CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0201_4B50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4B50;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const CENTRAL_DIRECTORY_HEADER_SIZE: usize = 46;
+const LOCAL_FILE_HEADER_SIZE: usize = 30;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+const FLAG_ENCRYPTED: u16 = 0x0001;
+
+const TRUNCATED: &str = "Zip file ended unexpectedly";
+/// Largest entry that will be extracted. ROMs and cartridges are far smaller than this, so larger
+/// entries are rejected rather than risking running out of memory.
+const MAX_UNCOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ZipEntry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+pub fn is_zip(bytes: &[u8]) -> bool {
+    bytes.starts_with(LOCAL_FILE_SIGNATURE)
+}
+
+/// Returns the files in the given zip file, leaving out directories.
+pub fn extract(bytes: &[u8]) -> Result<Vec<ZipEntry>, String> {
+    // The end of central directory record is followed by a comment of up to 64KB, so it has to be
+    // searched for from the end
+    let end_offset = (0..=bytes.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE))
+        .rev()
+        .find(|offset| read_u32(bytes, *offset) == Some(END_OF_CENTRAL_DIRECTORY_SIGNATURE))
+        .ok_or("Zip file is missing its central directory")?;
+    let num_entries = read_u16(bytes, end_offset + 10).ok_or(TRUNCATED)?;
+    let mut offset = read_u32(bytes, end_offset + 16).ok_or(TRUNCATED)? as usize;
+
+    let mut entries = vec![];
+    for _ in 0..num_entries {
+        if read_u32(bytes, offset) != Some(CENTRAL_DIRECTORY_SIGNATURE) {
+            return Err("Zip file has an invalid central directory".to_string());
+        }
+        let header = get_range(bytes, offset, CENTRAL_DIRECTORY_HEADER_SIZE)?;
+        let flags = read_u16(header, 8).ok_or(TRUNCATED)?;
+        let method = read_u16(header, 10).ok_or(TRUNCATED)?;
+        let checksum = read_u32(header, 16).ok_or(TRUNCATED)?;
+        let compressed_size = read_u32(header, 20).ok_or(TRUNCATED)? as usize;
+        let uncompressed_size = read_u32(header, 24).ok_or(TRUNCATED)? as usize;
+        let name_length = read_u16(header, 28).ok_or(TRUNCATED)? as usize;
+        let extra_length = read_u16(header, 30).ok_or(TRUNCATED)? as usize;
+        let comment_length = read_u16(header, 32).ok_or(TRUNCATED)? as usize;
+        let local_offset = read_u32(header, 42).ok_or(TRUNCATED)? as usize;
+
+        let name = get_range(bytes, offset + CENTRAL_DIRECTORY_HEADER_SIZE, name_length)?;
+        let name = String::from_utf8_lossy(name).to_string();
+        offset += CENTRAL_DIRECTORY_HEADER_SIZE + name_length + extra_length + comment_length;
+
+        if name.ends_with('/') {
+            continue;
+        }
+        if flags & FLAG_ENCRYPTED != 0 {
+            return Err(format!("Zip file entry {} is encrypted", name));
+        }
+        if uncompressed_size > MAX_UNCOMPRESSED_SIZE {
+            return Err(format!(
+                "Zip file entry {} is too large ({} bytes, max {} bytes)",
+                name, uncompressed_size, MAX_UNCOMPRESSED_SIZE
+            ));
+        }
+
+        // The local header's extra field can differ from the central directory's
+        let local_name_length = read_u16(bytes, local_offset + 26).ok_or(TRUNCATED)? as usize;
+        let local_extra_length = read_u16(bytes, local_offset + 28).ok_or(TRUNCATED)? as usize;
+        let data_offset =
+            local_offset + LOCAL_FILE_HEADER_SIZE + local_name_length + local_extra_length;
+        let compressed = get_range(bytes, data_offset, compressed_size)?;
+
+        let data = match method {
+            METHOD_STORED => compressed.to_vec(),
+            METHOD_DEFLATE => inflate(compressed, uncompressed_size)
+                .map_err(|e| format!("Failed to decompress {}: {}", name, e))?,
+            _ => {
+                return Err(format!(
+                    "Zip file entry {} uses an unsupported compression method ({})",
+                    name, method
+                ))
+            }
+        };
+        if data.len() != uncompressed_size || crc32(&data) != checksum {
+            return Err(format!("Zip file entry {} is corrupted", name));
+        }
+
+        entries.push(ZipEntry { name, data });
+    }
+
+    Ok(entries)
+}
+
+fn get_range(bytes: &[u8], start: usize, length: usize) -> Result<&[u8], String> {
+    bytes
+        .get(start..start + length)
+        .ok_or_else(|| TRUNCATED.to_string())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Base lengths of the length codes 257-285, and how many extra bits each one has.
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distances of the distance codes 0-29, and how many extra bits each one has.
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order that the code length code lengths of a dynamic block are given in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+const MAX_CODE_LENGTH: usize = 15;
+
+/// Decompresses raw deflate data, as described in RFC 1951, failing if it decompresses to more
+/// than the given size.
+fn inflate(data: &[u8], max_size: usize) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader::new(data);
+    let mut output = vec![];
+
+    loop {
+        let is_final = reader.read_bits(1)? == 1;
+        match reader.read_bits(2)? {
+            0 => {
+                reader.align_to_byte();
+                let length = reader.read_bits(16)?;
+                let length_complement = reader.read_bits(16)?;
+                if length != !length_complement & 0xFFFF {
+                    return Err("Stored block has an invalid length".to_string());
+                }
+                check_output_size(&output, length as usize, max_size)?;
+                for _ in 0..length {
+                    output.push(reader.read_bits(8)? as u8);
+                }
+            }
+            1 => {
+                let (literals, distances) = create_fixed_codes();
+                inflate_block(&mut reader, &mut output, max_size, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut output, max_size, &literals, &distances)?;
+            }
+            _ => return Err("Invalid block type".to_string()),
+        }
+
+        if is_final {
+            return Ok(output);
+        }
+    }
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    max_size: usize,
+    literals: &HuffmanCode,
+    distances: &HuffmanCode,
+) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => {
+                check_output_size(output, 1, max_size)?;
+                output.push(symbol as u8);
+            }
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                if index >= LENGTH_BASES.len() {
+                    return Err(format!("Invalid length code: {}", symbol));
+                }
+                let length = LENGTH_BASES[index] as usize
+                    + reader.read_bits(LENGTH_EXTRA_BITS[index])? as usize;
+
+                let index = distances.decode(reader)? as usize;
+                if index >= DISTANCE_BASES.len() {
+                    return Err(format!("Invalid distance code: {}", index));
+                }
+                let distance = DISTANCE_BASES[index] as usize
+                    + reader.read_bits(DISTANCE_EXTRA_BITS[index])? as usize;
+                if distance > output.len() {
+                    return Err("Distance goes back past the start of the data".to_string());
+                }
+
+                // Copied one byte at a time, since the copy can overlap the bytes it writes
+                check_output_size(output, length, max_size)?;
+                let start = output.len() - distance;
+                for i in 0..length {
+                    output.push(output[start + i]);
+                }
+            }
+        }
+    }
+}
+
+/// Checks that the given number of bytes can be added to the output without going over its
+/// maximum size.
+fn check_output_size(output: &[u8], length: usize, max_size: usize) -> Result<(), String> {
+    if length > max_size - output.len() {
+        return Err(format!(
+            "Decompressed data is larger than its expected {} bytes",
+            max_size
+        ));
+    }
+
+    Ok(())
+}
+
+fn create_fixed_codes() -> (HuffmanCode, HuffmanCode) {
+    let mut lengths = [0; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+
+    (HuffmanCode::new(&lengths), HuffmanCode::new(&[5; 30]))
+}
+
+fn read_dynamic_codes(reader: &mut BitReader) -> Result<(HuffmanCode, HuffmanCode), String> {
+    let num_literals = reader.read_bits(5)? as usize + 257;
+    let num_distances = reader.read_bits(5)? as usize + 1;
+    let num_code_lengths = reader.read_bits(4)? as usize + 4;
+
+    let mut code_length_lengths = [0; 19];
+    for index in CODE_LENGTH_ORDER.iter().take(num_code_lengths) {
+        code_length_lengths[*index] = reader.read_bits(3)? as u8;
+    }
+    let code_lengths = HuffmanCode::new(&code_length_lengths);
+
+    // The literal and distance code lengths are given together, and repeats can cross between them
+    let mut lengths = vec![];
+    while lengths.len() < num_literals + num_distances {
+        let symbol = code_lengths.decode(reader)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or("Repeated code length with no previous length")?;
+                (previous, 3 + reader.read_bits(2)?)
+            }
+            17 => (0, 3 + reader.read_bits(3)?),
+            _ => (0, 11 + reader.read_bits(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(length);
+        }
+    }
+    if lengths.len() > num_literals + num_distances {
+        return Err("Code lengths repeat past the end of the codes".to_string());
+    }
+
+    Ok((
+        HuffmanCode::new(&lengths[..num_literals]),
+        HuffmanCode::new(&lengths[num_literals..]),
+    ))
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length along with the symbols
+/// in order of their codes.
+struct HuffmanCode {
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    symbols: Vec<u16>,
+}
+
+impl HuffmanCode {
+    fn new(lengths: &[u8]) -> HuffmanCode {
+        let mut counts = [0; MAX_CODE_LENGTH + 1];
+        for length in lengths.iter() {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut symbols = vec![];
+        for length in 1..=MAX_CODE_LENGTH {
+            for (symbol, symbol_length) in lengths.iter().enumerate() {
+                if *symbol_length as usize == length {
+                    symbols.push(symbol as u16);
+                }
+            }
+        }
+
+        HuffmanCode { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        // Codes are read one bit at a time, checking at each length whether the code so far is
+        // one of the codes of that length
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for length in 1..=MAX_CODE_LENGTH {
+            code |= reader.read_bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err("Invalid Huffman code".to_string())
+    }
+}
+
+/// Reads bits starting from the least significant bit of each byte.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    bit: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            bytes,
+            position: 0,
+            bit: 0,
+        }
+    }
+
+    fn read_bits(&mut self, count: u8) -> Result<u32, String> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self
+                .bytes
+                .get(self.position)
+                .ok_or("Compressed data ended unexpectedly")?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.position += 1;
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.position += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+fn create_test_zip(files: &[(&str, u16, &[u8], &[u8])]) -> Vec<u8> {
+    let mut zip = vec![];
+    let mut central_directory = vec![];
+    for (name, method, data, compressed) in files.iter() {
+        let mut header = vec![];
+        header.extend_from_slice(&[20, 0, 0, 0]);
+        header.extend_from_slice(&method.to_le_bytes());
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        header.extend_from_slice(&crc32(data).to_le_bytes());
+        header.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&[0, 0]);
+
+        central_directory.extend_from_slice(&CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        central_directory.extend_from_slice(&[20, 0]);
+        central_directory.extend_from_slice(&header);
+        central_directory.extend_from_slice(&[0; 10]);
+        central_directory.extend_from_slice(&(zip.len() as u32).to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+
+        zip.extend_from_slice(LOCAL_FILE_SIGNATURE);
+        zip.extend_from_slice(&header);
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(compressed);
+    }
+
+    let central_directory_offset = zip.len() as u32;
+    zip.extend_from_slice(&central_directory);
+    zip.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+    zip.extend_from_slice(&[0, 0, 0, 0]);
+    zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(central_directory.len() as u32).to_le_bytes());
+    zip.extend_from_slice(&central_directory_offset.to_le_bytes());
+    zip.extend_from_slice(&[0, 0]);
+    zip
+}
+
+#[test]
+fn zip_inflate() {
+    // Fixed Huffman codes
+    assert_eq!(
+        Ok(b"hello".to_vec()),
+        inflate(&[0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x07, 0x00], 5)
+    );
+    assert_eq!(
+        Err("Decompressed data is larger than its expected 4 bytes".to_string()),
+        inflate(&[0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x07, 0x00], 4)
+    );
+
+    // Dynamic Huffman codes
+    let compressed = [
+        0x25, 0x87, 0x81, 0x09, 0x00, 0x00, 0x0C, 0x82, 0x6E, 0x35, 0xFF, 0xFF, 0x61, 0xC5, 0x28,
+        0x93, 0x48, 0x02, 0x58, 0x49, 0xD8, 0x59, 0x9F, 0x05, 0xB5, 0x7B,
+    ];
+    assert_eq!(
+        Ok(b"abbbaaacbbbcababbaabaabbaabbababaacccbaa".to_vec()),
+        inflate(&compressed, 40)
+    );
+    assert_eq!(
+        Err("Decompressed data is larger than its expected 39 bytes".to_string()),
+        inflate(&compressed, 39)
+    );
+
+    // Stored block
+    assert_eq!(
+        Ok(vec![0x12, 0x00]),
+        inflate(&[0x01, 0x02, 0x00, 0xFD, 0xFF, 0x12, 0x00], 2)
+    );
+    assert_eq!(
+        Err("Decompressed data is larger than its expected 1 bytes".to_string()),
+        inflate(&[0x01, 0x02, 0x00, 0xFD, 0xFF, 0x12, 0x00], 1)
+    );
+
+    assert_eq!(
+        Err("Compressed data ended unexpectedly".to_string()),
+        inflate(&[0xCB, 0x48, 0xCD], 5)
+    );
+}
+
+#[test]
+fn zip_extract() {
+    let rom = [0x00, 0xE0, 0xA2, 0x2A, 0x60, 0x0C, 0x61, 0x08, 0xD0, 0x1F].repeat(6);
+    let compressed = [
+        0x63, 0x78, 0xB0, 0x48, 0x2B, 0x81, 0x27, 0x91, 0xE3, 0x82, 0x3C, 0x03, 0xC9, 0x2C, 0x00,
+    ];
+    let zip = create_test_zip(&[
+        ("README.txt", METHOD_STORED, b"Have fun", b"Have fun"),
+        ("game.ch8", METHOD_DEFLATE, &rom, &compressed),
+    ]);
+    assert!(is_zip(&zip));
+
+    assert_eq!(
+        Ok(vec![
+            ZipEntry {
+                name: "README.txt".to_string(),
+                data: b"Have fun".to_vec()
+            },
+            ZipEntry {
+                name: "game.ch8".to_string(),
+                data: rom.clone()
+            },
+        ]),
+        extract(&zip)
+    );
+
+    let corrupted = create_test_zip(&[("game.ch8", METHOD_STORED, &rom, &rom[1..])]);
+    assert_eq!(
+        Err("Zip file entry game.ch8 is corrupted".to_string()),
+        extract(&corrupted)
+    );
+
+    // The header says the entry is smaller than it decompresses to
+    let oversized = create_test_zip(&[("game.ch8", METHOD_DEFLATE, &rom[..10], &compressed)]);
+    assert_eq!(
+        Err(
+            "Failed to decompress game.ch8: Decompressed data is larger than its expected 10 bytes"
+                .to_string()
+        ),
+        extract(&oversized)
+    );
+}